@@ -1,10 +1,14 @@
 use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
 
+use ed25519_dalek::{Signature, Signer};
 use rand_core::OsRng;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
-pub(crate) struct DeviceId {
+pub struct DeviceId {
     auth_key: ed25519_dalek::VerifyingKey,
 }
 
@@ -33,19 +37,126 @@ impl DeviceId {
     pub(crate) fn new(auth_key: ed25519_dalek::VerifyingKey) -> Self {
         Self { auth_key }
     }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.auth_key.as_bytes()
+    }
+
+    /// Verify that this device signed the message.
+    pub(crate) fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        self.auth_key.verify_strict(message, signature).is_ok()
+    }
+
+    /// The X25519 public key that corresponds to this device's authentication key, which keys are
+    /// wrapped for.
+    pub(crate) fn key_agreement_key(&self) -> [u8; 32] {
+        self.auth_key.to_montgomery().to_bytes()
+    }
 }
 
 #[derive(Debug, Clone)]
-pub(crate) struct Identity {
+pub struct Identity {
     // Authentication key.
     auth_key: ed25519_dalek::SigningKey,
     // Signing key.
     // Encryption key.
 }
 
+/// Errors that can occur when importing an identity.
+#[derive(Debug)]
+pub enum IdentityError {
+    /// Failed to read or write the key file.
+    Io(io::Error),
+    /// The key file does not contain a valid identity.
+    InvalidKeyFile,
+}
+
+impl Display for IdentityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityError::Io(err) => write!(f, "IO error: {err}"),
+            IdentityError::InvalidKeyFile => write!(f, "Invalid identity key file"),
+        }
+    }
+}
+
+impl std::error::Error for IdentityError {}
+
+impl From<io::Error> for IdentityError {
+    fn from(err: io::Error) -> Self {
+        IdentityError::Io(err)
+    }
+}
+
+/// Size in bytes of the seed that an identity is derived from.
+pub const IDENTITY_SEED_SIZE: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+
 impl Identity {
-    pub(crate) fn auth_key(&self) -> &ed25519_dalek::SigningKey {
-        &self.auth_key
+    /// Sign the message with this identity's authentication key.
+    pub(crate) fn sign(&self, message: &[u8]) -> Signature {
+        self.auth_key.sign(message)
+    }
+
+    /// X25519 Diffie-Hellman between this identity's authentication key and the given public key.
+    pub(crate) fn diffie_hellman(&self, public_key: &[u8; 32]) -> [u8; 32] {
+        x25519_dalek::x25519(self.auth_key.to_scalar_bytes(), *public_key)
+    }
+
+    /// The device id of this identity.
+    pub fn device_id(&self) -> DeviceId {
+        DeviceId::new(self.auth_key.verifying_key())
+    }
+
+    /// Recreate an identity from its secret seed.
+    pub fn from_seed(seed: &[u8; IDENTITY_SEED_SIZE]) -> Identity {
+        let auth_key = ed25519_dalek::SigningKey::from_bytes(seed);
+        Identity { auth_key }
+    }
+
+    /// Export the secret seed of this identity. Anyone with the seed can impersonate this device, so keep it secret.
+    pub fn to_seed(&self) -> [u8; IDENTITY_SEED_SIZE] {
+        self.auth_key.to_bytes()
+    }
+
+    /// Save this identity to a key file. The file contains the base 58 encoded seed.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), IdentityError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(path)?;
+        let encoded = bs58::encode(self.to_seed()).into_string();
+        file.write_all(encoded.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Load an identity from a key file created by `Identity::save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Identity, IdentityError> {
+        let encoded = fs::read_to_string(path)?;
+        let seed = bs58::decode(encoded.trim())
+            .into_vec()
+            .map_err(|_| IdentityError::InvalidKeyFile)?;
+        let seed: [u8; IDENTITY_SEED_SIZE] =
+            seed.try_into().map_err(|_| IdentityError::InvalidKeyFile)?;
+        Ok(Identity::from_seed(&seed))
+    }
+
+    /// Load the identity from the key file, or generate a new identity and save it if the key file does not exist.
+    pub fn load_or_generate<P: AsRef<Path>>(path: P) -> Result<Identity, IdentityError> {
+        match Identity::load(&path) {
+            Ok(identity) => Ok(identity),
+            Err(IdentityError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                let identity = generate_identity();
+                identity.save(&path)?;
+                Ok(identity)
+            }
+            Err(err) => Err(err),
+        }
     }
 }
 
@@ -55,3 +166,30 @@ pub fn generate_identity() -> Identity {
 
     Identity { auth_key }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::generate_nonce;
+
+    #[test]
+    fn test_identity_round_trip() {
+        let identity = generate_identity();
+        let imported = Identity::from_seed(&identity.to_seed());
+        assert_eq!(identity.device_id(), imported.device_id());
+
+        let path = std::env::temp_dir().join(format!(
+            "odyssey-identity-{}",
+            hex::encode(&generate_nonce()[..8])
+        ));
+        let saved = Identity::load_or_generate(&path).unwrap();
+        let loaded = Identity::load(&path).unwrap();
+        assert_eq!(saved.device_id(), loaded.device_id());
+
+        fs::write(&path, "not a key").unwrap();
+        let err = Identity::load_or_generate(&path).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid identity key file");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
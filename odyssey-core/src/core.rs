@@ -3,34 +3,41 @@ use odyssey_crdt::time::CausalState;
 // use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
 use odyssey_crdt::CRDT;
 use serde::{Deserialize, Serialize};
+use std::any::Any;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Display};
 use std::marker::PhantomData;
 use std::net::{Ipv4Addr, SocketAddrV4};
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, Mutex};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio::sync::{watch, RwLock};
 use tokio::task::JoinHandle;
 use tokio_util::codec::{self, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tracing::{debug, error, info, warn};
 use typeable::Typeable;
 
 use crate::auth::{generate_identity, DeviceId, Identity};
-use crate::network::protocol::{run_handshake_client, run_handshake_server, HandshakeError};
+use crate::network::protocol::{
+    run_handshake_client, run_handshake_server, HandshakeError, ProtocolError,
+};
 use crate::protocol::manager::v0::PeerManagerCommand;
 use crate::protocol::MiniProtocolArgs;
-use crate::storage::Storage;
-use crate::store::ecg::{self, ECGBody, ECGHeader};
-use crate::store::{self, StateUpdate, StoreCommand, UntypedStoreCommand};
+use crate::storage::{Storage, StorageError};
+use crate::store::ecg::{self, ECGBody, ECGHeader, HeaderMetadata, ValidationError};
+use crate::store::{
+    self,
+    acl::{Acl, AclChange},
+    encryption::StoreKey,
+    MetadataHeader, StateSender, StateUpdate, StoreCommand, SubscriptionMode, UntypedStoreCommand,
+};
 use crate::time::ConcretizeTime;
 use crate::util::{self, TypedStream};
 
 pub struct Odyssey<OT: OdysseyType> {
-    /// Thread running the Odyssey server.
-    thread: thread::JoinHandle<()>,
     // command_channel: UnboundedSender<OdysseyCommand>,
     tokio_runtime: Runtime,
     /// Active stores.
@@ -42,6 +49,16 @@ pub struct Odyssey<OT: OdysseyType> {
     // instead?
     phantom: PhantomData<OT>,
     identity_keys: Identity,
+    /// IPv4 port to listen for connections on.
+    port: u16,
+    /// Number of ECG nodes each store applies between persisted checkpoints.
+    checkpoint_interval: u64,
+    /// Cancels the server and all peer connections when disconnecting. `None` while offline.
+    network: Mutex<Option<CancellationToken>>,
+    /// Addresses of peers we've connected to. These are reconnected to when coming back online.
+    peer_addresses: Mutex<BTreeSet<SocketAddrV4>>,
+    /// Tracks the server and peer connection tasks so that shutdown can wait for them to close.
+    connections: TaskTracker,
 }
 pub type StoreStatuses<StoreId, Hash, HeaderId, Header> =
     BTreeMap<StoreId, StoreStatus<Hash, HeaderId, Header>>; // Rename this MiniProtocolArgs?
@@ -62,6 +79,10 @@ pub enum StoreStatus<Hash, HeaderId, Header> {
         // send_command_chan: UnboundedSender<StoreCommand<store::ecg::v0::Header<dyn Hash, dyn CRDT>, dyn CRDT>>,
         // send_command_chan: UnboundedSender<UntypedStoreCommand>,
         send_command_chan: UnboundedSender<UntypedStoreCommand<Hash, HeaderId, Header>>,
+        /// The store's typed `UnboundedSender<StoreCommand<..>>`, used to hand out new `StoreHandle`s.
+        typed_command_chan: Box<dyn Any + Send + Sync>,
+        /// The store's current ACL, or `None` while the store is downloading.
+        acl: watch::Receiver<Option<Acl>>,
     },
 }
 
@@ -70,6 +91,45 @@ pub enum StoreStatus<Hash, HeaderId, Header> {
 pub(crate) struct SharedState<StoreId> {
     pub(crate) peer_state:
         Arc<RwLock<BTreeMap<DeviceId, UnboundedSender<PeerManagerCommand<StoreId>>>>>,
+    /// Connection status of every peer we've connected to.
+    pub(crate) peer_statuses: watch::Sender<PeerStatuses>,
+}
+
+impl<StoreId> SharedState<StoreId> {
+    /// Record that a miniprotocol with the peer failed.
+    pub(crate) fn report_peer_error(&self, peer: DeviceId, error: &ProtocolError) {
+        self.peer_statuses.send_modify(|statuses| {
+            statuses.entry(peer).or_default().last_error = Some(error.to_string());
+        });
+    }
+
+    /// Record that the peer sent us an invalid ECG node.
+    pub(crate) fn report_invalid_ecg_node(&self, peer: DeviceId, error: ValidationError) {
+        self.peer_statuses.send_modify(|statuses| {
+            let status = statuses.entry(peer).or_default();
+            status.invalid_ecg_nodes += 1;
+            status.last_error = Some(error.to_string());
+        });
+    }
+
+    fn set_peer_connected(&self, peer: DeviceId, connected: bool) {
+        self.peer_statuses.send_modify(|statuses| {
+            statuses.entry(peer).or_default().connected = connected;
+        });
+    }
+}
+
+pub type PeerStatuses = BTreeMap<DeviceId, PeerStatus>;
+
+/// Status of a peer we've connected to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerStatus {
+    /// Whether we're currently connected to the peer.
+    pub connected: bool,
+    /// Why the most recent connection or miniprotocol with the peer failed, if any has.
+    pub last_error: Option<String>,
+    /// Number of ECG nodes the peer sent that failed validation.
+    pub invalid_ecg_nodes: u64,
 }
 
 impl<Hash, HeaderId, Header> StoreStatus<Hash, HeaderId, Header> {
@@ -84,6 +144,29 @@ impl<Hash, HeaderId, Header> StoreStatus<Hash, HeaderId, Header> {
         !self.is_initializing()
     }
 
+    /// Create a new handle to this store, if it's running and holds a `T`.
+    fn store_handle<O: OdysseyType, T>(&self, identity: &Identity) -> Option<StoreHandle<O, T>>
+    where
+        T: CRDT<Time = O::Time, Op: ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>
+            + 'static,
+    {
+        match self {
+            StoreStatus::Initializing => None,
+            StoreStatus::Running {
+                typed_command_chan, ..
+            } => {
+                let send_command_chan = typed_command_chan
+                    .downcast_ref::<UnboundedSender<StoreCommand<O::ECGHeader, O::ECGBody<T>, T>>>(
+                    )?;
+                Some(StoreHandle {
+                    send_command_chan: send_command_chan.clone(),
+                    identity: identity.clone(),
+                    phantom: PhantomData,
+                })
+            }
+        }
+    }
+
     pub(crate) fn command_channel(
         &self,
     ) -> Option<&UnboundedSender<UntypedStoreCommand<Hash, HeaderId, Header>>> {
@@ -94,6 +177,18 @@ impl<Hash, HeaderId, Header> StoreStatus<Hash, HeaderId, Header> {
             } => Some(send_command_chan),
         }
     }
+
+    /// Whether the store may be advertised to the peer. Stores that are still downloading don't
+    /// know their ACL yet, so they're advertised to find peers to download from. Those peers check
+    /// their ACL before sharing the store.
+    pub(crate) fn is_shared_with(&self, peer: &DeviceId) -> bool {
+        match self {
+            StoreStatus::Initializing => false,
+            StoreStatus::Running { acl, .. } => {
+                acl.borrow().as_ref().is_none_or(|acl| acl.can_sync(peer))
+            }
+        }
+    }
 }
 
 impl<OT: OdysseyType> Odyssey<OT> {
@@ -117,16 +212,16 @@ impl<OT: OdysseyType> Odyssey<OT> {
 
     // Start odyssey.
     pub fn start(config: OdysseyConfig) -> Self {
-        // TODO: Load identity or take it as an argument.
-        let identity_keys = generate_identity();
+        // Use the provided identity or generate a fresh one.
+        let identity_keys = config.identity.clone().unwrap_or_else(generate_identity);
 
         // // Create channels to communicate with Odyssey thread.
         // let (send_odyssey_commands, mut recv_odyssey_commands) = futures_channel::mpsc::unbounded();
-        let (active_stores, active_stores_receiver) = watch::channel(BTreeMap::new());
-        let device_id = DeviceId::new(identity_keys.auth_key().verifying_key());
+        let (active_stores, _) = watch::channel(BTreeMap::new());
 
-        let shared_state_ = SharedState {
+        let shared_state = SharedState {
             peer_state: Arc::new(RwLock::new(BTreeMap::new())),
+            peer_statuses: watch::Sender::new(BTreeMap::new()),
         };
 
         // Start async runtime.
@@ -137,14 +232,39 @@ impl<OT: OdysseyType> Odyssey<OT> {
                 todo!()
             }
         };
-        let runtime_handle = runtime.handle().clone();
-        let shared_state = shared_state_.clone();
 
-        // Spawn server thread.
-        let odyssey_thread = thread::spawn(move || {
-            runtime_handle.block_on(async move {
+        let odyssey = Odyssey {
+            // command_channel: send_odyssey_commands,
+            tokio_runtime: runtime,
+            active_stores,
+            phantom: PhantomData,
+            shared_state,
+            identity_keys,
+            port: config.port,
+            checkpoint_interval: config.checkpoint_interval,
+            network: Mutex::new(None),
+            peer_addresses: Mutex::new(BTreeSet::new()),
+            connections: TaskTracker::new(),
+        };
+
+        // Start listening for connections.
+        odyssey.connect();
+
+        odyssey
+    }
+
+    /// Spawn the server that accepts connections from peers until `cancel` is cancelled.
+    fn spawn_server(&self, cancel: CancellationToken) {
+        let active_stores_receiver = self.active_stores.subscribe();
+        let identity = self.identity_keys.clone();
+        let port = self.port;
+        let shared_state = self.shared_state.clone();
+        let connections = self.connections.clone();
+
+        self.connections.spawn_on(
+            async move {
                 // Start listening for connections.
-                let Some(listener) = Odyssey::<OT>::bind_server_ipv4(config.port).await else {
+                let Some(listener) = Odyssey::<OT>::bind_server_ipv4(port).await else {
                     error!("Failed to start server.");
                     return;
                 };
@@ -161,7 +281,14 @@ impl<OT: OdysseyType> Odyssey<OT> {
                 info!("Starting server");
                 loop {
                     // Accept connection.
-                    let (tcpstream, peer) = match listener.accept().await {
+                    let accepted = tokio::select! {
+                        _ = cancel.cancelled() => {
+                            info!("Stopped server");
+                            return;
+                        }
+                        accepted = listener.accept() => accepted,
+                    };
+                    let (tcpstream, peer) = match accepted {
                         Ok(r) => r,
                         Err(err) => {
                             error!("Failed to accept connection: {}", err);
@@ -173,18 +300,22 @@ impl<OT: OdysseyType> Odyssey<OT> {
                     let active_stores = active_stores_receiver.clone();
                     // let device_id = DeviceId::new(identity_keys.auth_key().verifying_key());
                     let shared_state = shared_state.clone();
+                    let identity = identity.clone();
+                    let cancel = cancel.clone();
 
-                    let future_handle = tokio::spawn(async move {
+                    connections.spawn(async move {
                         // let (read_stream, write_stream) = tcpstream.split();
                         let stream = codec::Framed::new(tcpstream, LengthDelimitedCodec::new());
 
-                        // TODO XXX
-                        // Handshake.
-                        // Diffie Hellman? TLS?
-                        // Authenticate peer's public key?
+                        // Handshake to authenticate the peer and encrypt the connection.
                         let mut stream = TypedStream::new(stream);
-                        let handshake_result = run_handshake_server(&mut stream, &device_id).await;
-                        let stream = stream.finalize().into_inner();
+                        let Some(handshake_result) = cancel
+                            .run_until_cancelled(run_handshake_server(&mut stream, &identity))
+                            .await
+                        else {
+                            return;
+                        };
+                        let stream = stream.finalize();
 
                         let handshake_result = match handshake_result {
                             Ok(r) => r,
@@ -192,63 +323,56 @@ impl<OT: OdysseyType> Odyssey<OT> {
                                 info!("Disconnecting. Attempting to connect to ourself.");
                                 return;
                             }
+                            Err(err) => {
+                                warn!("Disconnecting. Handshake with {} failed: {:?}", peer, err);
+                                return;
+                            }
                         };
 
-                        info!(
-                            "Handshake complete with peer: {}",
-                            handshake_result.peer_id()
-                        );
+                        let peer_id = handshake_result.peer_id();
+                        let version = handshake_result.version();
+                        info!("Handshake complete with peer: {}", peer_id);
+                        let stream = handshake_result.into_encrypted_stream(stream);
+
                         // Store peer in state.
-                        if let Some(recv) =
-                            initiate_peer(handshake_result.peer_id(), &shared_state).await
-                        {
+                        if let Some((send, recv)) = initiate_peer(peer_id, &shared_state).await {
                             // Start miniprotocols.
-                            let args = MiniProtocolArgs::new(
-                                handshake_result.peer_id(),
-                                active_stores,
-                                recv,
-                            );
-                            handshake_result
-                                .version()
-                                .run_miniprotocols_server::<OT>(stream, args)
-                                .await;
+                            let args =
+                                MiniProtocolArgs::new(peer_id, active_stores.clone(), recv, cancel);
+                            let result = version.run_miniprotocols_server::<OT>(stream, args).await;
+                            if let Err(err) = result {
+                                warn!("Connection with peer {} failed: {}", peer_id, err);
+                                shared_state.report_peer_error(peer_id, &err);
+                            }
+                            teardown_peer(peer_id, &send, &shared_state, &active_stores).await;
                         } else {
-                            info!(
-                                "Disconnecting. Already connected to peer: {}",
-                                handshake_result.peer_id()
-                            );
+                            info!("Disconnecting. Already connected to peer: {}", peer_id);
                         }
                     });
                 }
-            });
-        });
-
-        // TODO: Store identity key
-
-        Odyssey {
-            thread: odyssey_thread,
-            // command_channel: send_odyssey_commands,
-            tokio_runtime: runtime,
-            active_stores,
-            phantom: PhantomData,
-            shared_state: shared_state_,
-            identity_keys,
-        }
+            },
+            self.tokio_runtime.handle(),
+        );
     }
 
-    pub fn create_store<T, S: Storage>(&self, initial_state: T, _storage: S) -> StoreHandle<OT, T>
+    pub fn create_store<T, S: Storage + Send + 'static>(
+        &self,
+        initial_state: T,
+        storage: S,
+    ) -> StoreHandle<OT, T>
     where
         T: CRDT<Time = OT::Time>
             + Clone
             + Debug
             + Send
+            + Sync
             + 'static
             + Typeable
             + Serialize
             + for<'d> Deserialize<'d>,
         // T::Op<CausalTime<OT::Time>>: Serialize,
         // T::Op: ConcretizeTime<T::Time>, // <OT::ECGHeader as ECGHeader>::HeaderId>,
-        T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+        T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId> + Send + Sync,
         OT::ECGBody<T>: Send
             + Serialize
             + for<'d> Deserialize<'d>
@@ -263,26 +387,36 @@ impl<OT: OdysseyType> Odyssey<OT> {
         //     Send + ECGBody<T, Header = OT::ECGHeader> + Serialize + for<'d> Deserialize<'d> + Debug,
         <OT::ECGHeader as ECGHeader>::HeaderId: Send + Serialize + for<'d> Deserialize<'d>,
     {
-        // Create store by generating nonce, etc.
-        let store = store::State::<OT::StoreId, OT::ECGHeader, T, OT::Hash>::new_syncing(
-            initial_state.clone(),
-        );
-        let store_id = store.store_id();
+        let mut storage: Box<dyn Storage + Send> = Box::new(storage);
+        let (store_id, mut store) = loop {
+            // Create store by generating nonce, etc.
+            let store = store::State::<OT::StoreId, OT::ECGHeader, T, OT::Hash>::new_syncing(
+                initial_state.clone(),
+                storage,
+                &self.identity_keys,
+            );
+            let store_id = store.store_id();
 
-        // Check if this store id already exists and try again if there's a conflict.
-        // Otherwise, mark this store as initializing.
-        let mut already_exists = false;
-        self.active_stores.send_if_modified(|active_stores| {
-            let res = active_stores.try_insert(store_id, StoreStatus::Initializing);
-            if res.is_err() {
-                already_exists = true;
+            // Check if this store id already exists and try again if there's a conflict.
+            // Otherwise, mark this store as initializing.
+            let mut already_exists = false;
+            self.active_stores.send_if_modified(|active_stores| {
+                let res = active_stores.try_insert(store_id, StoreStatus::Initializing);
+                if res.is_err() {
+                    already_exists = true;
+                }
+                false
+            });
+            if !already_exists {
+                break (store_id, store);
             }
-            false
-        });
-        if already_exists {
+
             // This will generate a new nonce if there's a conflict.
-            return self.create_store(initial_state, _storage);
-        }
+            storage = store.into_storage();
+        };
+
+        // Persist the new store.
+        store.persist_store();
 
         // Launch the store.
         let store_handle = self.launch_store(store_id, store);
@@ -290,14 +424,17 @@ impl<OT: OdysseyType> Odyssey<OT> {
         store_handle
     }
 
-    pub fn connect_to_store<T>(
+    /// Open the store with the given id, loading it from `storage` or downloading it from peers.
+    /// If the store is already active, a new handle to the running store is returned instead, or
+    /// `None` if the running store holds a different type.
+    pub fn connect_to_store<T, S: Storage + Send + 'static>(
         &self,
         store_id: OT::StoreId,
-        // storage: S,
-    ) -> StoreHandle<OT, T>
+        storage: S,
+    ) -> Option<StoreHandle<OT, T>>
     where
         OT::ECGHeader: Send + Sync + Clone + 'static,
-        T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+        T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId> + Send + Sync,
         OT::ECGBody<T>: Send
             + Serialize
             + for<'d> Deserialize<'d>
@@ -311,31 +448,66 @@ impl<OT: OdysseyType> Odyssey<OT> {
         // OT::ECGBody<T>:
         //     Send + ECGBody<T, Header = OT::ECGHeader> + Serialize + for<'d> Deserialize<'d> + Debug,
         <<OT as OdysseyType>::ECGHeader as ECGHeader>::HeaderId: Send,
-        T: CRDT<Time = OT::Time> + Clone + Debug + Send + 'static + for<'d> Deserialize<'d>,
+        T: CRDT<Time = OT::Time>
+            + Clone
+            + Debug
+            + Send
+            + Sync
+            + 'static
+            + Serialize
+            + for<'d> Deserialize<'d>,
         // T::Op<CausalTime<OT::Time>>: Serialize,
     {
         // Check if store is already active.
         // If it isn't, mark it as initializing and continue.
-        let mut is_active = false;
-        self.active_stores.send_if_modified(|active_stores| {
-            let res = active_stores.try_insert(store_id, StoreStatus::Initializing);
-            if res.is_err() {
-                is_active = true;
+        loop {
+            let mut is_active = false;
+            self.active_stores.send_if_modified(|active_stores| {
+                let res = active_stores.try_insert(store_id, StoreStatus::Initializing);
+                if res.is_err() {
+                    is_active = true;
+                }
+                false
+            });
+            if !is_active {
+                break;
             }
-            false
-        });
-        if is_active {
-            // TODO: Get handle of existing store.
-            return todo!();
+
+            // Wait for the store to finish initializing and return a new handle to it.
+            // The wait runs on our runtime so that callers on another tokio runtime only block on
+            // the response, like the `StoreHandle` methods do.
+            let mut active_stores = self.active_stores.subscribe();
+            let (response_chan, recv) = tokio::sync::oneshot::channel();
+            self.tokio_runtime.spawn(async move {
+                let _ = active_stores
+                    .wait_for(|s| {
+                        s.get(&store_id)
+                            .is_none_or(|status| status.is_initialized())
+                    })
+                    .await
+                    .expect("Unreachable: Odyssey owns the sender.");
+                let _ = response_chan.send(());
+            });
+            futures::executor::block_on(recv).expect("Unreachable: The task always responds.");
+
+            if let Some(status) = self.active_stores.borrow().get(&store_id) {
+                if status.is_initialized() {
+                    let handle = status.store_handle(&self.identity_keys);
+                    if handle.is_none() {
+                        warn!("Store {store_id} is already active with a different type.");
+                    }
+                    return handle;
+                }
+            }
+            // The store was closed (and maybe reopened) in the mean time, so try again.
         }
 
-        // TODO:
-        // - Load store from disk if we have it locally.
+        // Load store from disk if we have it locally.
         // Spawn async handler.
-        let state = store::State::new_downloading(store_id);
+        let state = store::State::load::<OT>(store_id, Box::new(storage), &self.identity_keys);
         let store_handler = self.launch_store(store_id, state);
         debug!("Joined store: {}", store_id);
-        store_handler
+        Some(store_handler)
 
         // - Add it to our active store set with the appropriate status.
         //
@@ -344,86 +516,276 @@ impl<OT: OdysseyType> Odyssey<OT> {
         // TODO: Set status as initializing in create_store too
     }
 
-    // Connect to network.
-    pub fn connect() {
-        todo!("Turn on network connection")
+    /// Reopen every store of type `T` that is persisted in the given storage. Each store gets its
+    /// own clone of the storage. This is typically called right after `Odyssey::start`.
+    pub fn reopen_stores<T, S: Storage + Clone + Send + 'static>(
+        &self,
+        storage: S,
+    ) -> Result<ReopenedStores<OT, T>, StorageError>
+    where
+        OT::ECGHeader: Send + Sync + Clone + 'static,
+        T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId> + Send + Sync,
+        OT::ECGBody<T>: Send
+            + Serialize
+            + for<'d> Deserialize<'d>
+            + Debug
+            + ECGBody<
+                T::Op,
+                <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+                Header = OT::ECGHeader,
+            >,
+        <<OT as OdysseyType>::ECGHeader as ECGHeader>::HeaderId: Send,
+        T: CRDT<Time = OT::Time>
+            + Clone
+            + Debug
+            + Send
+            + Sync
+            + 'static
+            + Typeable
+            + Serialize
+            + for<'d> Deserialize<'d>,
+    {
+        let mut handles = vec![];
+        for raw_store_id in storage.list_stores()? {
+            let Some(metadata) = storage.read_metadata(&raw_store_id)? else {
+                continue;
+            };
+            let metadata: MetadataHeader<OT::Hash> = match serde_cbor::from_slice(&metadata) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    error!("Failed to parse persisted store metadata: {err}");
+                    continue;
+                }
+            };
+
+            // Skip stores that hold a different type.
+            if metadata.store_type != T::type_ident() {
+                continue;
+            }
+
+            let store_id: OT::StoreId = metadata.store_id();
+            if store_id.as_ref() != raw_store_id.as_slice() {
+                warn!("Persisted store metadata does not match its store id: {store_id}");
+                continue;
+            }
+
+            if let Some(handle) = self.connect_to_store(store_id, storage.clone()) {
+                handles.push((store_id, handle));
+            }
+        }
+
+        Ok(handles)
+    }
+
+    /// Connect to the network. Starts the server and reconnects to the peers we previously
+    /// connected to. Does nothing if we're already connected.
+    pub fn connect(&self) {
+        let cancel = {
+            let mut network = self.network.lock().expect("Network lock is poisoned");
+            if network.is_some() {
+                return;
+            }
+            let cancel = CancellationToken::new();
+            *network = Some(cancel.clone());
+            cancel
+        };
+
+        self.spawn_server(cancel.clone());
+
+        let peer_addresses = self
+            .peer_addresses
+            .lock()
+            .expect("Peer addresses lock is poisoned")
+            .clone();
+        for address in peer_addresses {
+            self.spawn_peer_connection(address, cancel.clone());
+        }
+    }
+
+    /// Disconnect from the network (work offline). Stops the server and tears down all peer
+    /// connections. Stores keep running, so local operations can still be applied and are synced
+    /// once we `connect` again.
+    pub fn disconnect(&self) {
+        let cancel = self
+            .network
+            .lock()
+            .expect("Network lock is poisoned")
+            .take();
+        if let Some(cancel) = cancel {
+            info!("Disconnecting from the network");
+            cancel.cancel();
+        }
+    }
+
+    /// Shut down Odyssey. Closes every active store (flushing their storage), gracefully closes
+    /// all peer connections, stops the server, and finally stops the runtime.
+    /// This blocks, so it must not be called from within an async context.
+    pub fn shutdown(self) {
+        // Close all stores.
+        let closing: Vec<_> = self
+            .active_stores
+            .borrow()
+            .values()
+            .filter_map(|status| {
+                let (response_chan, recv) = tokio::sync::oneshot::channel();
+                let chan = status.command_channel()?;
+                chan.send(UntypedStoreCommand::Close { response_chan })
+                    .ok()?;
+                Some(recv)
+            })
+            .collect();
+
+        // Close connections and stop the server.
+        self.disconnect();
+        self.connections.close();
+
+        self.tokio_runtime.block_on(async {
+            for recv in closing {
+                let _ = recv.await;
+            }
+            self.connections.wait().await;
+        });
+        info!("Shut down Odyssey");
+
+        self.tokio_runtime.shutdown_background();
+    }
+
+    /// Whether we're connected to the network.
+    pub fn is_connected(&self) -> bool {
+        self.network
+            .lock()
+            .expect("Network lock is poisoned")
+            .is_some()
+    }
+
+    /// The status of every peer we've connected to.
+    pub fn peer_statuses(&self) -> PeerStatuses {
+        self.shared_state.peer_statuses.borrow().clone()
+    }
+
+    /// Subscribe to changes in the status of peers, like connecting, disconnecting, or a failed
+    /// miniprotocol.
+    pub fn subscribe_to_peer_statuses(&self) -> watch::Receiver<PeerStatuses> {
+        self.shared_state.peer_statuses.subscribe()
     }
 
-    // Disconnect from network.
-    pub fn disconnect() {
-        todo!("Turn off network connections (work offline)")
+    /// The identity of this device.
+    pub fn identity(&self) -> &Identity {
+        &self.identity_keys
     }
 
-    fn device_id(&self) -> DeviceId {
-        DeviceId::new(self.identity_keys.auth_key().verifying_key())
+    /// The device id of this device.
+    pub fn device_id(&self) -> DeviceId {
+        self.identity_keys.device_id()
     }
 
     // Connect to a peer over ipv4.
+    /// If we're offline, the connection is made once we `connect`.
     pub fn connect_to_peer_ipv4(&self, address: SocketAddrV4) {
+        self.peer_addresses
+            .lock()
+            .expect("Peer addresses lock is poisoned")
+            .insert(address);
+
+        let network = self
+            .network
+            .lock()
+            .expect("Network lock is poisoned")
+            .clone();
+        if let Some(cancel) = network {
+            self.spawn_peer_connection(address, cancel);
+        } else {
+            info!("Offline. Will connect to {} once connected.", address);
+        }
+
+        // Return channel with peer connection status.
+    }
+
+    /// Spawn a connection to a peer that runs until it ends or `cancel` is cancelled.
+    fn spawn_peer_connection(&self, address: SocketAddrV4, cancel: CancellationToken) {
         let active_stores = self.active_stores.subscribe();
-        let device_id = self.device_id();
+        let identity = self.identity_keys.clone();
         let shared_state = self.shared_state.clone();
 
         // Spawn async.
-        let future_handle = self.tokio_runtime.spawn(async move {
-            // Attempt to connect to peer, returning message on failure.
-            let mut stream = match TcpStream::connect(address).await {
-                Ok(tcpstream) => {
-                    let stream = codec::Framed::new(tcpstream, LengthDelimitedCodec::new());
-                    TypedStream::new(stream)
-                }
-                Err(err) => {
-                    todo!("TODO: Log error");
+        self.connections.spawn_on(
+            async move {
+                // Attempt to connect to peer, returning message on failure.
+                let Some(tcpstream) = cancel
+                    .run_until_cancelled(TcpStream::connect(address))
+                    .await
+                else {
                     return;
-                }
-            };
-
-            // Run client handshake.
-            let handshake_result = run_handshake_client(&mut stream, &device_id).await;
-            let stream = stream.finalize().into_inner();
-            debug!("Connected to server!");
+                };
+                let mut stream = match tcpstream {
+                    Ok(tcpstream) => {
+                        let stream = codec::Framed::new(tcpstream, LengthDelimitedCodec::new());
+                        TypedStream::new(stream)
+                    }
+                    Err(err) => {
+                        warn!("Failed to connect to peer ({}): {}", address, err);
+                        return;
+                    }
+                };
 
-            let handshake_result = match handshake_result {
-                Ok(r) => r,
-                Err(HandshakeError::ConnectingToSelf) => {
-                    info!("Disconnecting. Attempting to connect to ourself.");
+                // Run client handshake.
+                let Some(handshake_result) = cancel
+                    .run_until_cancelled(run_handshake_client(&mut stream, &identity))
+                    .await
+                else {
                     return;
-                }
-            };
+                };
+                let stream = stream.finalize();
+                debug!("Connected to server!");
 
-            info!(
-                "Handshake complete with peer: {}",
-                handshake_result.peer_id()
-            );
-            // Store peer in state.
-            if let Some(recv) = initiate_peer(handshake_result.peer_id(), &shared_state).await {
-                // Start miniprotocols.
-                debug!("Start miniprotocols");
-                let args = MiniProtocolArgs::new(handshake_result.peer_id(), active_stores, recv);
-                handshake_result
-                    .version()
-                    .run_miniprotocols_client::<OT>(stream, args)
-                    .await;
-            } else {
-                info!(
-                    "Disconnecting. Already connected to peer: {}",
-                    handshake_result.peer_id()
-                );
-            }
-        });
+                let handshake_result = match handshake_result {
+                    Ok(r) => r,
+                    Err(HandshakeError::ConnectingToSelf) => {
+                        info!("Disconnecting. Attempting to connect to ourself.");
+                        return;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Disconnecting. Handshake with {} failed: {:?}",
+                            address, err
+                        );
+                        return;
+                    }
+                };
 
-        // Return channel with peer connection status.
+                let peer_id = handshake_result.peer_id();
+                let version = handshake_result.version();
+                info!("Handshake complete with peer: {}", peer_id);
+                let stream = handshake_result.into_encrypted_stream(stream);
+
+                // Store peer in state.
+                if let Some((send, recv)) = initiate_peer(peer_id, &shared_state).await {
+                    // Start miniprotocols.
+                    debug!("Start miniprotocols");
+                    let args = MiniProtocolArgs::new(peer_id, active_stores.clone(), recv, cancel);
+                    let result = version.run_miniprotocols_client::<OT>(stream, args).await;
+                    if let Err(err) = result {
+                        warn!("Connection with peer {} failed: {}", peer_id, err);
+                        shared_state.report_peer_error(peer_id, &err);
+                    }
+                    teardown_peer(peer_id, &send, &shared_state, &active_stores).await;
+                } else {
+                    info!("Disconnecting. Already connected to peer: {}", peer_id);
+                }
+            },
+            self.tokio_runtime.handle(),
+        );
     }
 
     // TODO: Separate state (that keeps state, syncs with other peers, etc) and optional user API (that sends state updates)?
     fn launch_store<T>(
         &self,
         store_id: OT::StoreId,
-        store: store::State<OT::StoreId, OT::ECGHeader, T, OT::Hash>,
+        mut store: store::State<OT::StoreId, OT::ECGHeader, T, OT::Hash>,
     ) -> StoreHandle<OT, T>
     where
         OT::ECGHeader: Send + Sync + Clone + 'static + for<'d> Deserialize<'d> + Serialize,
-        T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+        T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId> + Send + Sync,
         OT::ECGBody<T>: Send
             + Serialize
             + for<'d> Deserialize<'d>
@@ -438,9 +800,17 @@ impl<OT: OdysseyType> Odyssey<OT> {
         <<OT as OdysseyType>::ECGHeader as ECGHeader>::HeaderId:
             Send + for<'d> Deserialize<'d> + Serialize,
         // T::Op<CausalTime<OT::Time>>: Serialize,
-        T: CRDT<Time = OT::Time> + Debug + Clone + Send + 'static + for<'d> Deserialize<'d>,
+        T: CRDT<Time = OT::Time>
+            + Debug
+            + Clone
+            + Send
+            + Sync
+            + 'static
+            + Serialize
+            + for<'d> Deserialize<'d>,
     {
         // Initialize storage for this store.
+        store.set_checkpoint_interval(self.checkpoint_interval);
 
         // Create channels to handle requests and send updates.
         let (send_commands, recv_commands) = tokio::sync::mpsc::unbounded_channel::<
@@ -460,15 +830,27 @@ impl<OT: OdysseyType> Odyssey<OT> {
 
         let shared_state = self.shared_state.clone();
         let send_commands_untyped_ = send_commands_untyped.clone();
+        let active_stores = self.active_stores.clone();
+        let (send_acl, recv_acl) = watch::channel(None);
         let future_handle = self.tokio_runtime.spawn(async move {
-            store::run_handler::<OT, T>(
+            let close_chan = store::run_handler::<OT, T>(
                 store,
                 recv_commands,
                 send_commands_untyped_,
                 recv_commands_untyped,
                 shared_state,
+                send_acl,
             )
             .await;
+
+            // Deregister the store now that it's closed.
+            active_stores.send_if_modified(|active_stores| {
+                active_stores.remove(&store_id);
+                true
+            });
+            if let Some(close_chan) = close_chan {
+                let _ = close_chan.send(());
+            }
         });
 
         // Register this store.
@@ -478,6 +860,8 @@ impl<OT: OdysseyType> Odyssey<OT> {
                 StoreStatus::Running {
                     store_handle: future_handle,
                     send_command_chan: send_commands_untyped,
+                    typed_command_chan: Box::new(send_commands.clone()),
+                    acl: recv_acl,
                 },
             );
             true
@@ -486,35 +870,93 @@ impl<OT: OdysseyType> Odyssey<OT> {
         StoreHandle {
             // future_handle,
             send_command_chan: send_commands,
+            identity: self.identity_keys.clone(),
             phantom: PhantomData,
         }
     }
 }
 
-/// Initiates a peer by creating a channel to send commands and by inserting it into the shared state. On success, returns the receiver. If the peer already exists, fails with `None`.
+/// Initiates a peer by creating a channel to send commands and by inserting it into the shared state. On success, returns the channel. If the peer already exists, fails with `None`.
 async fn initiate_peer<StoreId>(
     peer_id: DeviceId,
     shared_state: &SharedState<StoreId>,
-) -> Option<UnboundedReceiver<PeerManagerCommand<StoreId>>> {
+) -> Option<(
+    UnboundedSender<PeerManagerCommand<StoreId>>,
+    UnboundedReceiver<PeerManagerCommand<StoreId>>,
+)> {
     let (send, recv) = tokio::sync::mpsc::unbounded_channel();
     let inserted = {
         let mut w = shared_state.peer_state.write().await;
-        w.try_insert(peer_id, send).is_ok()
+        w.try_insert(peer_id, send.clone()).is_ok()
     };
     if inserted {
-        Some(recv)
+        shared_state.set_peer_connected(peer_id, true);
+        Some((send, recv))
     } else {
         // JP: Record if we're already connected to the peer?
         None
     }
 }
 
-#[derive(Clone, Copy)]
+/// Tears down a peer once its connection ends by removing it from the shared state and telling the
+/// stores that it's gone. `send` is the channel created by `initiate_peer` for this connection.
+async fn teardown_peer<StoreId, Hash, HeaderId, Header>(
+    peer_id: DeviceId,
+    send: &UnboundedSender<PeerManagerCommand<StoreId>>,
+    shared_state: &SharedState<StoreId>,
+    active_stores: &watch::Receiver<StoreStatuses<StoreId, Hash, HeaderId, Header>>,
+) {
+    let removed = {
+        let mut w = shared_state.peer_state.write().await;
+        // Only remove the entry if it belongs to this connection.
+        if w.get(&peer_id).is_some_and(|s| s.same_channel(send)) {
+            w.remove(&peer_id);
+            true
+        } else {
+            false
+        }
+    };
+    if !removed {
+        return;
+    }
+    shared_state.set_peer_connected(peer_id, false);
+
+    for status in active_stores.borrow().values() {
+        if let Some(chan) = status.command_channel() {
+            let _ = chan.send(UntypedStoreCommand::UnregisterPeer { peer: peer_id });
+        }
+    }
+    info!("Disconnected from peer: {}", peer_id);
+}
+
+#[derive(Clone)]
 pub struct OdysseyConfig {
     // IPv4 port to run Odyssey on.
     pub port: u16,
+    /// Identity of this device. A new identity is generated if none is provided. Persist it with
+    /// `Identity::save` so that peers recognize this device across restarts.
+    pub identity: Option<Identity>,
+    /// Number of ECG nodes a store applies between persisting checkpoints of its state, so that
+    /// reopening it doesn't replay its whole history. Zero disables checkpoints.
+    pub checkpoint_interval: u64,
 }
 
+/// The id of the ECG header an operation was applied in, and the id assigned to the operation.
+pub type AppliedOperation<OT> = (
+    <<OT as OdysseyType>::ECGHeader as ECGHeader>::HeaderId,
+    <OT as OdysseyType>::Time,
+);
+
+/// The ids of the ECG headers a batch was applied in, and the ids assigned to each operation.
+pub type AppliedBatch<OT> = (
+    Vec<<<OT as OdysseyType>::ECGHeader as ECGHeader>::HeaderId>,
+    Vec<<OT as OdysseyType>::Time>,
+);
+
+/// Stores reopened from storage, with their ids.
+pub type ReopenedStores<OT, T> = Vec<(<OT as OdysseyType>::StoreId, StoreHandle<OT, T>)>;
+
+/// Handle to a running store. Handles are cheap to clone, and every clone talks to the same store.
 pub struct StoreHandle<
     O: OdysseyType,
     T: CRDT<Time = O::Time, Op: ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>,
@@ -525,9 +967,25 @@ pub struct StoreHandle<
 {
     // future_handle: JoinHandle<()>, // JP: Maybe this should be owned by `Odyssey`?
     send_command_chan: UnboundedSender<StoreCommand<O::ECGHeader, O::ECGBody<T>, T>>,
+    /// Identity that signs the operations applied through this handle.
+    identity: Identity,
     phantom: PhantomData<O>,
 }
 
+impl<
+        O: OdysseyType,
+        T: CRDT<Time = O::Time, Op: ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>,
+    > Clone for StoreHandle<O, T>
+{
+    fn clone(&self) -> Self {
+        StoreHandle {
+            send_command_chan: self.send_command_chan.clone(),
+            identity: self.identity.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
 /// Trait to define newtype wrapers that instantiate type families required by Odyssey.
 pub trait OdysseyType: 'static {
     type StoreId: Debug
@@ -569,6 +1027,12 @@ pub trait OdysseyType: 'static {
     fn to_causal_state<T: CRDT<Time = Self::Time>>(
         st: &store::ecg::State<Self::ECGHeader, T>,
     ) -> &Self::CausalState<T>;
+
+    /// The time of the operation at `operation_position` in the body of ECG node `header_id`.
+    fn operation_time(
+        header_id: <Self::ECGHeader as ECGHeader>::HeaderId,
+        operation_position: u8,
+    ) -> Self::Time;
 }
 
 impl<
@@ -582,7 +1046,7 @@ impl<
         &mut self,
         parents: BTreeSet<<O::ECGHeader as ECGHeader>::HeaderId>,
         op: <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
-    ) -> <O::ECGHeader as ECGHeader>::HeaderId
+    ) -> Option<<O::ECGHeader as ECGHeader>::HeaderId>
     where
         T::Op: ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>,
         O::ECGBody<T>: ECGBody<
@@ -591,16 +1055,27 @@ impl<
             Header = O::ECGHeader,
         >,
     {
-        self.apply_batch(parents, vec![op])
+        self.apply_batch(parents, vec![op])?.pop()
     }
 
-    // TODO: Don't take parents as an argument. Pull it from the state. XXX
+    /// Apply operations on top of the given parents. See `apply_batch_to_tips` to use the store's
+    /// current tips as parents instead.
+    ///
+    /// An ECG node holds at most `MAX_BODY_OPERATIONS` operations, so larger batches are split
+    /// into a chain of nodes where each node's parent is the previous node. Returns the header ids
+    /// of the chain in order, or `None` if the store is still downloading, we don't have its key,
+    /// or it was closed. `CausalTime::Current` positions are relative to the start of the batch,
+    /// so operations can refer to any operation in the batch that way.
+    ///
+    /// Batches that span several nodes are not atomic. Each node is applied and persisted on its
+    /// own, so if the store closes or rejects a node partway through, the earlier nodes stay
+    /// applied even though `None` is returned. They still show up in the store's `log`.
     pub fn apply_batch(
         &mut self,
         parents: BTreeSet<<<O as OdysseyType>::ECGHeader as ECGHeader>::HeaderId>,
         op: Vec<<T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized>, // T::Op<CausalTime<T::Time>>>,
                                                                                                // op: Vec<T::Op>,
-    ) -> <O::ECGHeader as ECGHeader>::HeaderId
+    ) -> Option<Vec<<O::ECGHeader as ECGHeader>::HeaderId>>
     where
         T::Op: ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>,
         <O as OdysseyType>::ECGBody<T>: ECGBody<
@@ -609,48 +1084,1195 @@ impl<
             Header = O::ECGHeader,
         >,
     {
-        // TODO: Divide into 256 operation chunks.
-        // if op.is_empty() {
-        //     return vec![];
-        // }
+        self.apply_chunks(parents, op, vec![])
+    }
 
-        // Create ECG header and body.
-        let body = <<O as OdysseyType>::ECGBody<T> as ECGBody<
+    /// Apply the remaining chunks of a batch as a chain of ECG nodes on top of `parents`.
+    /// `header_ids` are the nodes that hold the chunks of the batch that were already applied.
+    /// Returns the header ids of every chunk of the batch.
+    fn apply_chunks(
+        &mut self,
+        parents: BTreeSet<<O::ECGHeader as ECGHeader>::HeaderId>,
+        op: Vec<<T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized>,
+        mut header_ids: Vec<<O::ECGHeader as ECGHeader>::HeaderId>,
+    ) -> Option<Vec<<O::ECGHeader as ECGHeader>::HeaderId>>
+    where
+        O::ECGBody<T>: ECGBody<
             T::Op,
             <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
-        >>::new_body(op);
-        let header = body.new_header(parents);
-        let header_id = header.get_header_id();
-        // let times = body.get_operation_times(&header);
+            Header = O::ECGHeader,
+        >,
+    {
+        let mut parents = parents;
+        let mut ops = op.into_iter().peekable();
+        while ops.peek().is_some() {
+            // Operations may refer to operations in earlier chunks of the batch.
+            let chunk = ops
+                .by_ref()
+                .take(ecg::MAX_BODY_OPERATIONS)
+                .map(|op| T::Op::rebase_batch_time(op, &header_ids))
+                .collect();
 
-        self.send_command_chan
-            .send(StoreCommand::Apply {
-                operation_header: header,
-                operation_body: body,
-            })
-            .expect("TODO");
+            // Create ECG header and body.
+            let body = <<O as OdysseyType>::ECGBody<T> as ECGBody<
+                T::Op,
+                <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            >>::new_body(chunk);
+            // The header commits to the encrypted body, so the store's handler creates it.
+            let (response_chan, recv) = tokio::sync::oneshot::channel();
+            self.send_command_chan
+                .send(StoreCommand::Apply {
+                    parents,
+                    operation_body: body,
+                    author: self.identity.clone(),
+                    response_chan,
+                })
+                .ok()?;
+            let header_id = futures::executor::block_on(recv).ok()?;
+
+            // Chain the next chunk onto this one.
+            parents = BTreeSet::from([header_id]);
+            header_ids.push(header_id);
+        }
 
         // times
-        header_id
+        Some(header_ids)
     }
 
-    pub fn subscribe_to_state(&mut self) -> UnboundedReceiver<StateUpdate<O::ECGHeader, T>> {
-        let (send_state, recv_state) = tokio::sync::mpsc::unbounded_channel();
-        self.send_command_chan
-            .send(StoreCommand::SubscribeState { send_state })
-            .expect("TODO");
-
-        recv_state
+    /// Apply an operation on top of the store's current tips. Returns the id of the new ECG header
+    /// and the id assigned to the operation, or `None` if the store is still downloading or was
+    /// closed.
+    pub fn apply_to_tips(
+        &mut self,
+        op: <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+    ) -> Option<AppliedOperation<O>>
+    where
+        O::ECGBody<T>: ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = O::ECGHeader,
+        >,
+    {
+        let (header_ids, mut operation_ids) = self.apply_batch_to_tips(vec![op])?;
+        Some((header_ids[0], operation_ids.remove(0)))
     }
-}
 
-// pub enum OdysseyCommand {
-//     CreateStore {
-//         // Since Rust doesn't have existentials...
-//         initial_state: (), // Box<Dynamic>, // T
-//         storage: Box<dyn Storage + Send>,
-//     },
-// }
+    /// Apply operations on top of the store's current tips. The tips are read by the store's
+    /// handler, so operations applied concurrently through other handles are never missed.
+    /// Like `apply_batch`, large batches are split into a chain of ECG nodes, and are not atomic.
+    /// Returns the ids of the new ECG headers and the ids assigned to each operation, or `None` if
+    /// the store is still downloading or was closed.
+    pub fn apply_batch_to_tips(
+        &mut self,
+        op: Vec<<T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized>,
+    ) -> Option<AppliedBatch<O>>
+    where
+        O::ECGBody<T>: ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = O::ECGHeader,
+        >,
+    {
+        let operations_count = op.len();
+        let mut op = op;
+        let rest = op.split_off(op.len().min(ecg::MAX_BODY_OPERATIONS));
+
+        // Apply the first chunk on top of the tips.
+        let body = <O::ECGBody<T> as ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+        >>::new_body(op);
+        let (response_chan, recv) = tokio::sync::oneshot::channel();
+        self.send_command_chan
+            .send(StoreCommand::ApplyToTips {
+                operation_body: body,
+                author: self.identity.clone(),
+                acl_changes: vec![],
+                rotate_key: false,
+                response_chan,
+            })
+            .ok()?;
+        let header_id = futures::executor::block_on(recv).ok()?;
+
+        // Chain the remaining chunks onto it.
+        let header_ids = self.apply_chunks(BTreeSet::from([header_id]), rest, vec![header_id])?;
+
+        // Each chunk except the last is full.
+        let operation_ids = (0..operations_count)
+            .map(|i| {
+                let header_id = header_ids[i / ecg::MAX_BODY_OPERATIONS];
+                let position = (i % ecg::MAX_BODY_OPERATIONS) as u8;
+                O::operation_time(header_id, position)
+            })
+            .collect();
+        Some((header_ids, operation_ids))
+    }
+
+    /// Change the store's ACL with a new ECG node on top of the store's current tips. Only admins
+    /// may change the ACL. Returns the id of the new ECG header, or `None` if this device isn't an
+    /// admin, or if the store is still downloading or was closed.
+    pub fn update_acl(
+        &mut self,
+        changes: Vec<AclChange>,
+    ) -> Option<<O::ECGHeader as ECGHeader>::HeaderId>
+    where
+        O::ECGBody<T>: ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = O::ECGHeader,
+        >,
+    {
+        let body = <O::ECGBody<T> as ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+        >>::new_body(vec![]);
+        let (response_chan, recv) = tokio::sync::oneshot::channel();
+        self.send_command_chan
+            .send(StoreCommand::ApplyToTips {
+                operation_body: body,
+                author: self.identity.clone(),
+                acl_changes: changes,
+                rotate_key: false,
+                response_chan,
+            })
+            .ok()?;
+        futures::executor::block_on(recv).ok()
+    }
+
+    /// Rotate the store's key with a new ECG node on top of the store's current tips. Later nodes
+    /// are encrypted with a new key that is only shared with devices that can read the store, so
+    /// devices that were removed can't read them. Current members can still read older nodes.
+    /// Only admins may rotate the key. Returns the id of the new ECG header, or `None` if this
+    /// device isn't an admin, or if the store is still downloading or was closed.
+    pub fn rotate_key(&mut self) -> Option<<O::ECGHeader as ECGHeader>::HeaderId>
+    where
+        O::ECGBody<T>: ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = O::ECGHeader,
+        >,
+    {
+        self.rotate_key_with(vec![])
+    }
+
+    /// Remove a device from the store and rotate the store's key in the same ECG node, so that the
+    /// device can't read anything written after it was removed. Only admins may remove members.
+    /// Returns the id of the new ECG header, or `None` if this device isn't an admin, or if the
+    /// store is still downloading or was closed.
+    pub fn remove_member(
+        &mut self,
+        device: DeviceId,
+    ) -> Option<<O::ECGHeader as ECGHeader>::HeaderId>
+    where
+        O::ECGBody<T>: ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = O::ECGHeader,
+        >,
+    {
+        self.rotate_key_with(vec![AclChange::Revoke { device }])
+    }
+
+    fn rotate_key_with(
+        &mut self,
+        changes: Vec<AclChange>,
+    ) -> Option<<O::ECGHeader as ECGHeader>::HeaderId>
+    where
+        O::ECGBody<T>: ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = O::ECGHeader,
+        >,
+    {
+        let body = <O::ECGBody<T> as ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<O::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+        >>::new_body(vec![]);
+        let (response_chan, recv) = tokio::sync::oneshot::channel();
+        self.send_command_chan
+            .send(StoreCommand::ApplyToTips {
+                operation_body: body,
+                author: self.identity.clone(),
+                acl_changes: changes,
+                rotate_key: true,
+                response_chan,
+            })
+            .ok()?;
+        futures::executor::block_on(recv).ok()
+    }
+
+    /// The store's latest key, which lets anyone read the store, including nodes encrypted with
+    /// older keys. Share it out of band with devices that should read the store without being
+    /// given the key through the ACL. Returns `None` if we don't have the key, the store is still
+    /// downloading, or it was closed.
+    pub fn store_key(&self) -> Option<StoreKey> {
+        let (response_chan, recv) = tokio::sync::oneshot::channel();
+        self.send_command_chan
+            .send(StoreCommand::Key { response_chan })
+            .ok()?;
+        futures::executor::block_on(recv).ok()
+    }
+
+    /// Start reading the store with a key that was shared out of band. Returns whether the key was
+    /// accepted, which it isn't if it isn't one of the store's keys. While the store is
+    /// downloading, the key is accepted and checked once enough of the store is downloaded.
+    pub fn set_store_key(&mut self, key: StoreKey) -> bool {
+        let (response_chan, recv) = tokio::sync::oneshot::channel();
+        if self
+            .send_command_chan
+            .send(StoreCommand::SetKey { key, response_chan })
+            .is_err()
+        {
+            return false;
+        }
+        futures::executor::block_on(recv).unwrap_or(false)
+    }
+
+    /// The store's current ACL. Returns `None` if the store is still downloading or was closed.
+    pub fn acl(&self) -> Option<Acl> {
+        let (response_chan, recv) = tokio::sync::oneshot::channel();
+        self.send_command_chan
+            .send(StoreCommand::Acl { response_chan })
+            .ok()?;
+        futures::executor::block_on(recv).ok()
+    }
+
+    /// The state obtained by applying exactly the given heads and their ancestors, which is what
+    /// the store looked like at that point in its history. Returns `None` if any of the heads are
+    /// unknown, or if the store is still downloading or was closed.
+    pub fn state_at(&self, heads: BTreeSet<<O::ECGHeader as ECGHeader>::HeaderId>) -> Option<T> {
+        let (response_chan, recv) = tokio::sync::oneshot::channel();
+        self.send_command_chan
+            .send(StoreCommand::StateAt {
+                heads,
+                response_chan,
+            })
+            .ok()?;
+        futures::executor::block_on(recv).ok()?
+    }
+
+    /// All of the store's ECG headers in topological order, so that parents come before their
+    /// children. Returns `None` if the store is still downloading or was closed.
+    pub fn log(&self) -> Option<impl Iterator<Item = O::ECGHeader>>
+    where
+        O::ECGHeader: Clone + Send + 'static,
+    {
+        let log: Vec<_> = self.query_ecg(|ecg_state| ecg_state.log().cloned().collect())?;
+        Some(log.into_iter())
+    }
+
+    /// All ancestors of the given header. Returns `None` if the header is unknown, or if the store
+    /// is still downloading or was closed.
+    pub fn ancestors(
+        &self,
+        header_id: <O::ECGHeader as ECGHeader>::HeaderId,
+    ) -> Option<BTreeSet<<O::ECGHeader as ECGHeader>::HeaderId>>
+    where
+        <O::ECGHeader as ECGHeader>::HeaderId: Send + 'static,
+    {
+        self.query_ecg(move |ecg_state| ecg_state.ancestors(&header_id))?
+    }
+
+    /// All descendants of the given header. Returns `None` if the header is unknown, or if the
+    /// store is still downloading or was closed.
+    pub fn descendants(
+        &self,
+        header_id: <O::ECGHeader as ECGHeader>::HeaderId,
+    ) -> Option<BTreeSet<<O::ECGHeader as ECGHeader>::HeaderId>>
+    where
+        <O::ECGHeader as ECGHeader>::HeaderId: Send + 'static,
+    {
+        self.query_ecg(move |ecg_state| ecg_state.descendants(&header_id))?
+    }
+
+    /// Headers that `to_heads` depend on but `from_heads` don't, in topological order. For
+    /// example, pass the tips from a user's last visit as `from_heads` and the current tips as
+    /// `to_heads` to get the changes since then. Returns `None` if the store is still downloading
+    /// or was closed.
+    pub fn diff(
+        &self,
+        from_heads: BTreeSet<<O::ECGHeader as ECGHeader>::HeaderId>,
+        to_heads: BTreeSet<<O::ECGHeader as ECGHeader>::HeaderId>,
+    ) -> Option<Vec<<O::ECGHeader as ECGHeader>::HeaderId>>
+    where
+        <O::ECGHeader as ECGHeader>::HeaderId: Send + 'static,
+    {
+        self.query_ecg(move |ecg_state| ecg_state.diff(&from_heads, &to_heads))
+    }
+
+    /// Metadata about the given header. Returns `None` if the header is unknown, or if the store
+    /// is still downloading or was closed.
+    pub fn header_metadata(
+        &self,
+        header_id: <O::ECGHeader as ECGHeader>::HeaderId,
+    ) -> Option<HeaderMetadata<O::ECGHeader>>
+    where
+        O::ECGHeader: Clone + Send + 'static,
+        <O::ECGHeader as ECGHeader>::HeaderId: Send + 'static,
+    {
+        self.query_ecg(move |ecg_state| {
+            let node = ecg_state.state.get_node(&header_id)?;
+            let children = ecg_state
+                .get_children_with_depth(&header_id)?
+                .into_iter()
+                .map(|(_, child_id)| child_id)
+                .collect();
+            Some(HeaderMetadata {
+                header: node.header().clone(),
+                author: node.header().get_author(),
+                depth: ecg_state.get_header_depth(&header_id)?,
+                children,
+                // The body was checked against the header's count when it was applied.
+                operation_count: node.header().operations_count(),
+            })
+        })?
+    }
+
+    /// Run `query` against the store's ECG and wait for its result. Returns `None` if the store is
+    /// still downloading or was closed.
+    fn query_ecg<A: Send + 'static>(
+        &self,
+        query: impl FnOnce(&ecg::State<O::ECGHeader, T>) -> A + Send + 'static,
+    ) -> Option<A> {
+        let (response_chan, recv) = tokio::sync::oneshot::channel();
+        let query = Box::new(move |ecg_state: &ecg::State<O::ECGHeader, T>| {
+            let _ = response_chan.send(query(ecg_state));
+        });
+        self.send_command_chan
+            .send(StoreCommand::QueryECG { query })
+            .ok()?;
+        futures::executor::block_on(recv).ok()
+    }
+
+    /// Close the store. This flushes its storage, ends its syncs with peers, and removes it from
+    /// the active stores. Blocks until the store is closed. Other handles to the store stop
+    /// working.
+    pub fn close(self) {
+        let (response_chan, recv) = tokio::sync::oneshot::channel();
+        if self
+            .send_command_chan
+            .send(StoreCommand::Close { response_chan })
+            .is_err()
+        {
+            // The store is already closed.
+            return;
+        }
+        let _ = futures::executor::block_on(recv);
+    }
+
+    /// Subscribe to the store's state. A snapshot of the whole state is sent every time it
+    /// changes. The subscription ends when the receiver is dropped.
+    pub fn subscribe_to_state(&mut self) -> UnboundedReceiver<StateUpdate<O::ECGHeader, T>> {
+        let (send_state, recv_state) = mpsc::unbounded_channel();
+        self.subscribe(
+            StateSender::Unbounded(send_state),
+            SubscriptionMode::Snapshots,
+        );
+        recv_state
+    }
+
+    /// Subscribe to the operations applied to the store. A snapshot is sent once the store is
+    /// downloaded, followed by `StateUpdate::Operations` with only the newly applied operations
+    /// every time the store changes. The subscription ends when the receiver is dropped.
+    pub fn subscribe_to_operations(&mut self) -> UnboundedReceiver<StateUpdate<O::ECGHeader, T>> {
+        let (send_state, recv_state) = mpsc::unbounded_channel();
+        self.subscribe(
+            StateSender::Unbounded(send_state),
+            SubscriptionMode::Operations,
+        );
+        recv_state
+    }
+
+    /// Like `subscribe_to_state`, but at most `capacity` snapshots are buffered. Snapshots are
+    /// skipped while the buffer is full, so slow consumers only miss intermediate states.
+    /// Panics if `capacity` is zero.
+    pub fn subscribe_to_state_bounded(
+        &mut self,
+        capacity: usize,
+    ) -> mpsc::Receiver<StateUpdate<O::ECGHeader, T>> {
+        let (send_state, recv_state) = mpsc::channel(capacity);
+        self.subscribe(
+            StateSender::Bounded(send_state),
+            SubscriptionMode::Snapshots,
+        );
+        recv_state
+    }
+
+    /// Like `subscribe_to_operations`, but at most `capacity` updates are buffered. Since
+    /// skipping operations would leave the consumer with the wrong state, the subscription is
+    /// closed if the buffer is full. Consumers can then resubscribe to get a fresh snapshot.
+    /// Panics if `capacity` is zero.
+    pub fn subscribe_to_operations_bounded(
+        &mut self,
+        capacity: usize,
+    ) -> mpsc::Receiver<StateUpdate<O::ECGHeader, T>> {
+        let (send_state, recv_state) = mpsc::channel(capacity);
+        self.subscribe(
+            StateSender::Bounded(send_state),
+            SubscriptionMode::Operations,
+        );
+        recv_state
+    }
+
+    /// Subscribe to the latest snapshot of the store's state. Only the most recent snapshot is
+    /// kept, so this never buffers.
+    pub fn subscribe_to_latest_state(&mut self) -> watch::Receiver<StateUpdate<O::ECGHeader, T>> {
+        let (send_state, recv_state) = watch::channel(StateUpdate::Downloading { percent: 0 });
+        self.subscribe(StateSender::Latest(send_state), SubscriptionMode::Snapshots);
+        recv_state
+    }
+
+    fn subscribe(&mut self, send_state: StateSender<O::ECGHeader, T>, mode: SubscriptionMode) {
+        // If the store is closed, the receiver is closed as well.
+        let _ = self
+            .send_command_chan
+            .send(StoreCommand::SubscribeState { send_state, mode });
+    }
+}
+
+// pub enum OdysseyCommand {
+//     CreateStore {
+//         // Since Rust doesn't have existentials...
+//         initial_state: (), // Box<Dynamic>, // T
+//         storage: Box<dyn Storage + Send>,
+//     },
+// }
 
 // fn handle_odyssey_command() {
 // }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use odyssey_crdt::{
+        map::twopmap::{TwoPMap, TwoPMapOp},
+        register::LWW,
+    };
+
+    use super::*;
+    use crate::storage::{filesystem::FileSystemStorage, memory::MemoryStorage};
+    use crate::store::ecg::v0::{Body, Header, HeaderId, OperationId};
+    use crate::store::encryption::{HeaderKeys, StoreKey};
+    use crate::time::CausalTime;
+    use crate::util::{generate_nonce, Sha256Hash};
+
+    pub(crate) struct TestOdyssey;
+
+    impl OdysseyType for TestOdyssey {
+        type StoreId = Sha256Hash;
+        type Hash = Sha256Hash;
+        type ECGHeader = Header<Sha256Hash>;
+        type ECGBody<T: CRDT<Op: ConcretizeTime<HeaderId<Sha256Hash>>>> =
+            Body<Sha256Hash, <T::Op as ConcretizeTime<HeaderId<Sha256Hash>>>::Serialized>;
+        type Time = OperationId<HeaderId<Sha256Hash>>;
+        type CausalState<T: CRDT<Time = Self::Time>> = ecg::State<Self::ECGHeader, T>;
+
+        fn to_causal_state<T: CRDT<Time = Self::Time>>(
+            st: &ecg::State<Self::ECGHeader, T>,
+        ) -> &Self::CausalState<T> {
+            st
+        }
+
+        fn operation_time(header_id: HeaderId<Sha256Hash>, operation_position: u8) -> Self::Time {
+            OperationId::new(Some(header_id), operation_position)
+        }
+    }
+
+    type Id = HeaderId<Sha256Hash>;
+    type Time = OperationId<Id>;
+
+    /// Registers, keyed by the operation that inserted them.
+    pub(crate) type Registers = TwoPMap<Time, LWW<Time, u64>>;
+    pub(crate) type RegistersOp =
+        TwoPMapOp<CausalTime<Time>, LWW<CausalTime<Time>, u64>, LWW<CausalTime<Time>, u64>>;
+
+    /// Values in the order they were applied. Concurrent appends don't commute, so stores have to
+    /// replay them in a deterministic order.
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Typeable)]
+    pub(crate) struct Log(Vec<u64>);
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(crate) struct Append(u64);
+
+    impl CRDT for Log {
+        type Op = Append;
+        type Time = Time;
+
+        const CONCURRENT_OPERATIONS_COMMUTE: bool = false;
+
+        fn apply<CS: CausalState<Time = Self::Time>>(mut self, _st: &CS, op: Self::Op) -> Self {
+            self.0.push(op.0);
+            self
+        }
+    }
+
+    impl ConcretizeTime<Id> for Append {
+        type Serialized = Append;
+
+        fn concretize_time(src: Self::Serialized, _current_header: Id) -> Self {
+            src
+        }
+
+        fn rebase_batch_time(src: Self::Serialized, _previous_headers: &[Id]) -> Self::Serialized {
+            src
+        }
+    }
+
+    pub(crate) fn start_odyssey() -> Odyssey<TestOdyssey> {
+        Odyssey::start(OdysseyConfig {
+            port: 0,
+            identity: None,
+            checkpoint_interval: 0,
+        })
+    }
+
+    /// Insert a register with `value`. `position` is the position of the operation in its batch.
+    pub(crate) fn insert(position: u32, value: u64) -> RegistersOp {
+        TwoPMapOp::Insert {
+            key: CausalTime::current_time(position),
+            value: LWW::new(CausalTime::current_time(position), value),
+        }
+    }
+
+    /// Set the register inserted by `key` to `value`. `position` is the position of the operation
+    /// in its batch.
+    pub(crate) fn set(key: CausalTime<Time>, position: u32, value: u64) -> RegistersOp {
+        TwoPMapOp::Apply {
+            key,
+            operation: LWW::new(CausalTime::current_time(position), value),
+        }
+    }
+
+    /// The registers' values.
+    pub(crate) fn values(state: &Registers) -> Vec<(Time, u64)> {
+        state.iter().map(|(k, v)| (*k, *v.value())).collect()
+    }
+
+    /// The state at the store's current tips.
+    pub(crate) fn current_state(store: &StoreHandle<TestOdyssey, Registers>) -> Registers {
+        let tips = store
+            .query_ecg(|ecg_state| ecg_state.tips().clone())
+            .unwrap();
+        store.state_at(tips).unwrap()
+    }
+
+    /// Ids of the active stores.
+    pub(crate) fn active_store_ids(odyssey: &Odyssey<TestOdyssey>) -> Vec<Sha256Hash> {
+        odyssey.active_stores.borrow().keys().copied().collect()
+    }
+
+    /// The channel that miniprotocols send the store's handler commands on.
+    pub(crate) fn store_command_channel(
+        odyssey: &Odyssey<TestOdyssey>,
+        store_id: Sha256Hash,
+    ) -> UnboundedSender<UntypedStoreCommand<Sha256Hash, Id, Header<Sha256Hash>>> {
+        odyssey.active_stores.borrow()[&store_id]
+            .command_channel()
+            .unwrap()
+            .clone()
+    }
+
+    pub(crate) fn temp_storage() -> FileSystemStorage {
+        let nonce = generate_nonce();
+        let root = std::env::temp_dir().join(format!("odyssey-test-{}", hex::encode(&nonce[..8])));
+        FileSystemStorage::new(root).unwrap()
+    }
+
+    /// Persist a store with a few operations, restart and reopen it from the same directory.
+    fn reopen_store(checkpoint_interval: u64) {
+        let storage = temp_storage();
+        let start = || {
+            Odyssey::<TestOdyssey>::start(OdysseyConfig {
+                port: 0,
+                identity: None,
+                checkpoint_interval,
+            })
+        };
+
+        let odyssey = start();
+        let mut store = odyssey.create_store(Registers::new(), storage.clone());
+        let [store_id] = active_store_ids(&odyssey)[..] else {
+            panic!("Expected one active store");
+        };
+        let mut parents = BTreeSet::new();
+        for i in 0..5 {
+            parents = BTreeSet::from([store.apply(parents, insert(0, i)).unwrap()]);
+        }
+        let state = values(&current_state(&store));
+        assert_eq!(state.len(), 5);
+        odyssey.shutdown();
+
+        let checkpoint = storage.read_checkpoint(store_id.as_ref()).unwrap();
+        assert_eq!(checkpoint.is_some(), checkpoint_interval > 0);
+
+        // Restart and load the store from the same directory.
+        let odyssey = start();
+        let store = odyssey
+            .connect_to_store::<Registers, _>(store_id, storage.clone())
+            .unwrap();
+        assert_eq!(values(&current_state(&store)), state);
+
+        odyssey.shutdown();
+        std::fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_store() {
+        reopen_store(0);
+    }
+
+    #[test]
+    fn test_reopen_store_from_checkpoint() {
+        reopen_store(2);
+    }
+
+    #[test]
+    fn test_handles_share_store() {
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Registers::new(), MemoryStorage::new());
+        let mut cloned = store.clone();
+        let [store_id] = active_store_ids(&odyssey)[..] else {
+            panic!("Expected one active store");
+        };
+
+        let a = store.apply(BTreeSet::new(), insert(0, 1)).unwrap();
+        let b = cloned.apply(BTreeSet::from([a]), insert(0, 2)).unwrap();
+
+        // Connecting to an active store returns another handle to it.
+        let mut connected = odyssey
+            .connect_to_store::<Registers, _>(store_id, MemoryStorage::new())
+            .unwrap();
+        connected.apply(BTreeSet::from([b]), insert(0, 3)).unwrap();
+
+        let state = values(&current_state(&store));
+        assert_eq!(state.len(), 3);
+        assert_eq!(values(&current_state(&cloned)), state);
+        assert_eq!(values(&current_state(&connected)), state);
+
+        // Unless it holds a different type.
+        assert!(odyssey
+            .connect_to_store::<LWW<Time, u64>, _>(store_id, MemoryStorage::new())
+            .is_none());
+
+        odyssey.shutdown();
+    }
+
+    #[test]
+    fn test_close_store() {
+        let storage = temp_storage();
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Registers::new(), storage.clone());
+        let cloned = store.clone();
+        let [store_id] = active_store_ids(&odyssey)[..] else {
+            panic!("Expected one active store");
+        };
+        store.apply(BTreeSet::new(), insert(0, 1)).unwrap();
+        let state = values(&current_state(&store));
+
+        store.close();
+        assert!(active_store_ids(&odyssey).is_empty());
+        assert!(cloned.send_command_chan.is_closed());
+
+        // The closed store can be opened again.
+        let store = odyssey
+            .connect_to_store::<Registers, _>(store_id, storage.clone())
+            .unwrap();
+        assert_eq!(values(&current_state(&store)), state);
+
+        odyssey.shutdown();
+        std::fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_closes_stores() {
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Registers::new(), MemoryStorage::new());
+        store.apply(BTreeSet::new(), insert(0, 1)).unwrap();
+
+        odyssey.shutdown();
+        assert!(store.send_command_chan.is_closed());
+    }
+
+    #[test]
+    fn test_apply_to_tips_operation_ids() {
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Registers::new(), MemoryStorage::new());
+
+        let (header_id, operation_id) = store.apply_to_tips(insert(0, 1)).unwrap();
+        assert_eq!(operation_id, OperationId::new(Some(header_id), 0));
+
+        let (batch_ids, operation_ids) = store
+            .apply_batch_to_tips(vec![insert(0, 2), insert(1, 3)])
+            .unwrap();
+        let [batch_id] = batch_ids[..] else {
+            panic!("Expected a single chunk");
+        };
+        assert_eq!(
+            operation_ids,
+            vec![
+                OperationId::new(Some(batch_id), 0),
+                OperationId::new(Some(batch_id), 1),
+            ]
+        );
+
+        let mut recv_state = store.subscribe_to_state();
+        let Some(StateUpdate::Snapshot {
+            snapshot,
+            ecg_state,
+        }) = recv_state.blocking_recv()
+        else {
+            panic!("Expected a snapshot of the store's state");
+        };
+
+        // The batch was applied on top of the first operation.
+        assert_eq!(ecg_state.get_parents(&batch_id), Some(vec![header_id]));
+
+        // Registers are keyed by the operation that inserted them.
+        assert_eq!(
+            values(&snapshot),
+            BTreeMap::from([
+                (operation_id, 1),
+                (operation_ids[0], 2),
+                (operation_ids[1], 3),
+            ])
+            .into_iter()
+            .collect::<Vec<_>>()
+        );
+
+        odyssey.shutdown();
+    }
+
+    #[test]
+    fn test_state_at() {
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Registers::new(), MemoryStorage::new());
+
+        // a <- b, a <- c, (b, c) <- d
+        let a = store.apply(BTreeSet::new(), insert(0, 1)).unwrap();
+        let b = store.apply(BTreeSet::from([a]), insert(0, 2)).unwrap();
+        let c = store.apply(BTreeSet::from([a]), insert(0, 3)).unwrap();
+        let d = store.apply(BTreeSet::from([b, c]), insert(0, 4)).unwrap();
+        let op = |header_id| OperationId::new(Some(header_id), 0);
+
+        let state_at = |heads: &[Id]| {
+            let state = store.state_at(heads.iter().copied().collect()).unwrap();
+            values(&state).into_iter().collect::<BTreeSet<_>>()
+        };
+        assert_eq!(state_at(&[a]), BTreeSet::from([(op(a), 1)]));
+        assert_eq!(state_at(&[b]), BTreeSet::from([(op(a), 1), (op(b), 2)]));
+        assert_eq!(
+            state_at(&[b, c]),
+            BTreeSet::from([(op(a), 1), (op(b), 2), (op(c), 3)])
+        );
+        assert_eq!(
+            state_at(&[d]),
+            BTreeSet::from([(op(a), 1), (op(b), 2), (op(c), 3), (op(d), 4)])
+        );
+
+        odyssey.shutdown();
+    }
+
+    #[test]
+    fn test_diff() {
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Registers::new(), MemoryStorage::new());
+
+        // a <- b, a <- c, (b, c) <- d
+        let a = store.apply(BTreeSet::new(), insert(0, 1)).unwrap();
+        let b = store.apply(BTreeSet::from([a]), insert(0, 2)).unwrap();
+        let c = store.apply(BTreeSet::from([a]), insert(0, 3)).unwrap();
+        let d = store.apply(BTreeSet::from([b, c]), insert(0, 4)).unwrap();
+
+        assert_eq!(
+            store
+                .diff(BTreeSet::from([b]), BTreeSet::from([d]))
+                .unwrap(),
+            vec![c, d]
+        );
+        assert_eq!(
+            store
+                .diff(BTreeSet::from([a]), BTreeSet::from([b, c]))
+                .unwrap()
+                .into_iter()
+                .collect::<BTreeSet<_>>(),
+            BTreeSet::from([b, c])
+        );
+        assert!(store
+            .diff(BTreeSet::from([d]), BTreeSet::from([b]))
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(store.ancestors(d).unwrap(), BTreeSet::from([a, b, c]));
+        assert_eq!(store.descendants(b).unwrap(), BTreeSet::from([d]));
+        assert_eq!(store.log().unwrap().count(), 4);
+
+        odyssey.shutdown();
+    }
+
+    #[test]
+    fn test_header_metadata() {
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Registers::new(), MemoryStorage::new());
+
+        let (parent, _) = store
+            .apply_batch_to_tips(vec![insert(0, 1), insert(1, 2)])
+            .unwrap();
+        let (child, _) = store.apply_to_tips(insert(0, 3)).unwrap();
+
+        let metadata = store.header_metadata(parent[0]).unwrap();
+        assert_eq!(metadata.author, odyssey.device_id());
+        assert_eq!(metadata.operation_count, 2);
+        assert_eq!(metadata.children, vec![child]);
+
+        let child_metadata = store.header_metadata(child).unwrap();
+        assert_eq!(child_metadata.operation_count, 1);
+        assert_eq!(child_metadata.depth, metadata.depth + 1);
+        assert!(child_metadata.children.is_empty());
+        assert_eq!(values(&current_state(&store)).len(), 3);
+
+        // The log records who wrote each node.
+        let authors: Vec<_> = store.log().unwrap().map(|h| h.get_author()).collect();
+        assert_eq!(authors, vec![odyssey.device_id(); 2]);
+
+        odyssey.shutdown();
+    }
+
+    type TestStore = store::State<Sha256Hash, Header<Sha256Hash>, Registers, Sha256Hash>;
+
+    /// A store owned by `owner` that's syncing with `peer`, so it accepts ECG nodes from them.
+    pub(crate) fn syncing_store(owner: &Identity, peer: DeviceId) -> TestStore {
+        let mut store =
+            TestStore::new_syncing(Registers::new(), Box::new(MemoryStorage::new()), owner);
+        store.start_syncing_with(peer);
+        store
+    }
+
+    /// A root ECG node written by `author`, with an empty body encrypted with `key`.
+    pub(crate) fn root_node(author: &Identity, key: &StoreKey) -> (Header<Sha256Hash>, Vec<u8>) {
+        let body: <TestOdyssey as OdysseyType>::ECGBody<Registers> =
+            ECGBody::<<Registers as CRDT>::Op, _>::new_body(vec![]);
+        let encrypted_body = key.encrypt(&util::encoding::to_vec(&body));
+        let header = ECGBody::<<Registers as CRDT>::Op, _>::new_header(
+            &body,
+            &encrypted_body,
+            BTreeSet::new(),
+            author,
+            vec![],
+            HeaderKeys {
+                epoch: None,
+                wrapped_keys: vec![],
+                rotated_key: None,
+            },
+        );
+        (header, encrypted_body)
+    }
+
+    /// Replace one of a header's fields with the same field of another header.
+    fn swap_field(
+        header: &Header<Sha256Hash>,
+        other: &Header<Sha256Hash>,
+        field: &str,
+    ) -> Header<Sha256Hash> {
+        let serde_cbor::Value::Map(mut fields) = serde_cbor::value::to_value(header).unwrap()
+        else {
+            panic!("Headers are serialized as maps");
+        };
+        let serde_cbor::Value::Map(other_fields) = serde_cbor::value::to_value(other).unwrap()
+        else {
+            panic!("Headers are serialized as maps");
+        };
+        let key = serde_cbor::Value::Text(field.to_string());
+        fields.insert(key.clone(), other_fields[&key].clone());
+        serde_cbor::value::from_value(serde_cbor::Value::Map(fields)).unwrap()
+    }
+
+    #[test]
+    fn test_forged_nodes_are_rejected() {
+        let owner = generate_identity();
+        let peer = generate_identity().device_id();
+        let mut store = syncing_store(&owner, peer);
+        let key = store.initial_key().unwrap().clone();
+
+        let (header, encrypted_body) = root_node(&owner, &key);
+        let (other_header, _) = root_node(&generate_identity(), &key);
+        let forged_author = swap_field(&header, &other_header, "author");
+        let forged_signature = swap_field(&header, &other_header, "signature");
+
+        for forged in [forged_author, forged_signature] {
+            let header_id = forged.get_header_id();
+            let rejected = store.handle_received_ecg_operations::<TestOdyssey>(
+                peer,
+                vec![(forged, encrypted_body.clone())],
+                &mut vec![],
+            );
+            assert_eq!(
+                rejected,
+                vec![(header_id, ValidationError::InvalidSignature)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_nodes_from_non_writers_are_rejected() {
+        let owner = generate_identity();
+        let peer = generate_identity().device_id();
+        let mut store = syncing_store(&owner, peer);
+        let key = store.initial_key().unwrap().clone();
+
+        // Devices that aren't members of the store can't write to it, even with its key.
+        let (header, encrypted_body) = root_node(&generate_identity(), &key);
+        let header_id = header.get_header_id();
+        let rejected = store.handle_received_ecg_operations::<TestOdyssey>(
+            peer,
+            vec![(header, encrypted_body)],
+            &mut vec![],
+        );
+        assert_eq!(rejected, vec![(header_id, ValidationError::Unauthorized)]);
+
+        let node = root_node(&owner, &key);
+        let rejected =
+            store.handle_received_ecg_operations::<TestOdyssey>(peer, vec![node], &mut vec![]);
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn test_apply_to_unknown_parents() {
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Registers::new(), MemoryStorage::new());
+
+        // A node that was never inserted into the store.
+        let (header, _) = root_node(&generate_identity(), &StoreKey::generate());
+        let unknown = header.get_header_id();
+        assert_eq!(store.apply(BTreeSet::from([unknown]), insert(0, 1)), None);
+
+        // The store keeps running.
+        store.apply_to_tips(insert(0, 2)).unwrap();
+        assert_eq!(values(&current_state(&store)).len(), 1);
+
+        odyssey.shutdown();
+    }
+
+    #[test]
+    fn test_reopen_stores_after_restart() {
+        let storage = temp_storage();
+        let identity = generate_identity();
+        let start = || {
+            Odyssey::<TestOdyssey>::start(OdysseyConfig {
+                port: 0,
+                identity: Some(identity.clone()),
+                checkpoint_interval: 0,
+            })
+        };
+
+        let odyssey = start();
+        let mut store = odyssey.create_store(Registers::new(), storage.clone());
+        store
+            .apply_batch_to_tips(vec![insert(0, 1), insert(1, 2)])
+            .unwrap();
+        store.apply_to_tips(insert(0, 3)).unwrap();
+        let state = values(&current_state(&store));
+        drop(store);
+        odyssey.shutdown();
+
+        let odyssey = start();
+        let stores = odyssey
+            .reopen_stores::<Registers, _>(storage.clone())
+            .unwrap();
+        assert_eq!(stores.len(), 1);
+        assert_eq!(values(&current_state(&stores[0].1)), state);
+        assert_eq!(active_store_ids(&odyssey), vec![stores[0].0]);
+
+        // Stores of other types are skipped.
+        assert!(odyssey
+            .reopen_stores::<LWW<Time, u64>, _>(storage.clone())
+            .unwrap()
+            .is_empty());
+
+        odyssey.shutdown();
+        std::fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn test_large_batch() {
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Registers::new(), MemoryStorage::new());
+
+        // Insert 300 registers, then set each of them, so that operations refer to operations in
+        // earlier ECG nodes of the batch.
+        let count = 300;
+        let inserts = (0..count).map(|i| insert(i, 0));
+        let sets = (0..count).map(|i| set(CausalTime::current_time(i), count + i, i.into()));
+        let (header_ids, operation_ids) = store
+            .apply_batch_to_tips(inserts.chain(sets).collect())
+            .unwrap();
+
+        // The batch is split into a chain of nodes.
+        let operation_counts: Vec<_> = header_ids
+            .iter()
+            .map(|header_id| store.header_metadata(*header_id).unwrap().operation_count)
+            .collect();
+        assert_eq!(operation_counts, vec![255, 255, 90]);
+        for (parent, child) in header_ids.iter().zip(&header_ids[1..]) {
+            let metadata = store.header_metadata(*child).unwrap();
+            assert_eq!(metadata.header.get_parent_ids(), &[*parent]);
+        }
+        assert_eq!(
+            operation_ids[299],
+            OperationId::new(Some(header_ids[1]), 44)
+        );
+
+        // Each register has the value it was set to.
+        let mut expected: Vec<_> = operation_ids[..count as usize]
+            .iter()
+            .copied()
+            .zip(0..)
+            .collect();
+        expected.sort();
+        assert_eq!(values(&current_state(&store)), expected);
+
+        odyssey.shutdown();
+    }
+
+    #[test]
+    fn test_concurrent_operations_are_replayed() {
+        let odyssey = start_odyssey();
+        let mut store = odyssey.create_store(Log(vec![]), MemoryStorage::new());
+
+        // Apply nodes that are concurrent with nodes that were already applied.
+        let (a, _) = store.apply_to_tips(Append(0)).unwrap();
+        let mut concurrent = vec![];
+        for i in 1..=5 {
+            concurrent.push(store.apply(BTreeSet::from([a]), Append(i)).unwrap());
+        }
+        store.apply_to_tips(Append(6)).unwrap();
+        store
+            .apply(BTreeSet::from([concurrent[0]]), Append(7))
+            .unwrap();
+
+        let mut tips = store
+            .query_ecg(|ecg_state| ecg_state.tips().clone())
+            .unwrap();
+        let latest = store.state_at(tips.clone()).unwrap();
+        assert_eq!(latest.0.len(), 8);
+
+        // Including an ancestor of the tips makes the store replay every node instead of returning
+        // its latest state.
+        tips.insert(a);
+        assert_eq!(store.state_at(tips).unwrap(), latest);
+
+        odyssey.shutdown();
+    }
+
+    #[test]
+    fn test_concurrent_operations_across_checkpoints() {
+        // Apply a node that's concurrent with the nodes of a checkpoint, either before or after
+        // the checkpoint is taken.
+        for concurrent_first in [true, false] {
+            let odyssey: Odyssey<TestOdyssey> = Odyssey::start(OdysseyConfig {
+                port: 0,
+                identity: None,
+                checkpoint_interval: 10,
+            });
+            let mut store = odyssey.create_store(Log(vec![]), MemoryStorage::new());
+            let mut values = BTreeMap::new();
+            let mut apply = |parents: BTreeSet<Id>, value| {
+                let header_id = store.apply(parents, Append(value)).unwrap();
+                values.insert(header_id, value);
+                header_id
+            };
+
+            let root = apply(BTreeSet::new(), 0);
+            if concurrent_first {
+                apply(BTreeSet::from([root]), 10);
+            }
+            // The checkpoint is taken after the tenth node.
+            let branches: BTreeSet<_> = (1..=9).map(|i| apply(BTreeSet::from([root]), i)).collect();
+            if !concurrent_first {
+                apply(BTreeSet::from([root]), 10);
+            }
+            apply(branches, 11);
+
+            // Nodes are applied in the same order no matter when they arrived.
+            let log: Vec<_> = store
+                .query_ecg(|ecg_state| ecg_state.log().map(|h| h.get_header_id()).collect())
+                .unwrap();
+            let expected: Vec<_> = log.iter().map(|header_id| values[header_id]).collect();
+            let mut tips = store
+                .query_ecg(|ecg_state| ecg_state.tips().clone())
+                .unwrap();
+            assert_eq!(store.state_at(tips.clone()), Some(Log(expected.clone())));
+            tips.insert(root);
+            assert_eq!(store.state_at(tips), Some(Log(expected)));
+
+            odyssey.shutdown();
+        }
+    }
+
+    /// Wait up to ten seconds for `condition` to hold.
+    pub(crate) fn wait_until(mut condition: impl FnMut() -> bool) {
+        for _ in 0..1000 {
+            if condition() {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("Timed out waiting for condition");
+    }
+
+    /// A port that's currently free on localhost.
+    pub(crate) fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn test_disconnect_and_reconnect() {
+        let port = free_port();
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let server: Odyssey<TestOdyssey> = Odyssey::start(OdysseyConfig {
+            port,
+            identity: None,
+            checkpoint_interval: 0,
+        });
+        let client = start_odyssey();
+        let is_connected = |odyssey: &Odyssey<TestOdyssey>, peer: DeviceId| {
+            odyssey
+                .peer_statuses()
+                .get(&peer)
+                .is_some_and(|status| status.connected)
+        };
+        let is_listening = || std::net::TcpStream::connect(address).is_ok();
+
+        wait_until(is_listening);
+        client.connect_to_peer_ipv4(address);
+        wait_until(|| is_connected(&client, server.device_id()));
+        wait_until(|| is_connected(&server, client.device_id()));
+        let mut store = client.create_store(Registers::new(), MemoryStorage::new());
+
+        // Disconnecting tears down the connection on both sides.
+        client.disconnect();
+        assert!(!client.is_connected());
+        wait_until(|| !is_connected(&client, server.device_id()));
+        wait_until(|| !is_connected(&server, client.device_id()));
+
+        // Stores can still be updated while disconnected.
+        store.apply(BTreeSet::new(), insert(0, 1)).unwrap();
+        let state = values(&current_state(&store));
+        assert_eq!(state.iter().map(|(_, v)| *v).collect::<Vec<_>>(), vec![1]);
+
+        // Reconnecting connects to the same peers again.
+        client.connect();
+        assert!(client.is_connected());
+        wait_until(|| is_connected(&client, server.device_id()));
+        wait_until(|| is_connected(&server, client.device_id()));
+
+        // Disconnecting stops the server.
+        server.disconnect();
+        wait_until(|| !is_connected(&client, server.device_id()));
+        wait_until(|| !is_listening());
+        server.connect();
+        wait_until(is_listening);
+
+        client.shutdown();
+        server.shutdown();
+    }
+}
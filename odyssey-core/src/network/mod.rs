@@ -1,5 +1,6 @@
 pub mod multiplexer;
 pub mod protocol;
+pub mod transport;
 
 use std::fmt::Debug;
 
@@ -1,5 +1,5 @@
 use async_recursion::async_recursion;
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures;
 use futures::task::{Context, Poll};
 use serde::{Deserialize, Serialize};
@@ -10,29 +10,26 @@ use std::pin::Pin;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::oneshot;
 use tokio::{
-    io::{simplex, AsyncReadExt, AsyncWriteExt, SimplexStream, WriteHalf},
-    net::TcpStream,
-    sync::{
-        mpsc::{self, Receiver, Sender},
-        watch,
-    },
+    sync::mpsc::{self, Receiver, Sender},
     task::JoinHandle,
 };
 use tokio_stream::wrappers::ReceiverStream;
-use tokio_util::sync::{PollSendError, PollSender};
+use tokio_util::sync::{CancellationToken, PollSender};
 use tracing::{debug, error, trace, warn};
 
 use crate::core::{OdysseyType, StoreStatuses};
-use crate::store::ecg::ECGHeader;
+use crate::protocol::store_peer::v0::MAX_DELIVER_HEADERS;
+use crate::store::ecg::{self, ECGHeader};
+use crate::store::v0::{BLOCK_REQUEST_LIMIT, BLOCK_SIZE};
 use crate::{
     network::protocol::{MiniProtocol, ProtocolError},
+    network::transport::{EncryptedStream, TransportError},
     protocol::v0::MiniProtocols,
     util::{self, TypedStream},
 };
 
 const OUTGOING_CAPACITY: usize = 32;
 const PROTOCOL_INCOMING_CAPACITY: usize = 4;
-const BUFFER_SIZE: usize = 4096;
 
 #[derive(Clone, Copy, Debug)]
 pub(crate) enum Party {
@@ -80,35 +77,46 @@ impl Multiplexer {
 
     /// Run the multiplexer with these initial mini protocols.
     /// The miniprotocols are assigned identifiers in order, starting at 0.
+    /// Runs until the connection ends or `cancel` is cancelled, in which case every stream is
+    /// closed first. Fails if the connection fails or if one of the initial miniprotocols fails,
+    /// since the connection can't be used without them.
     pub(crate) async fn run_with_miniprotocols<O: OdysseyType>(
         mut self,
-        mut stream: TcpStream,
+        mut stream: EncryptedStream,
         miniprotocols: Vec<
             MiniProtocols<O::StoreId, O::Hash, <O::ECGHeader as ECGHeader>::HeaderId, O::ECGHeader>,
         >,
-    ) {
+        cancel: CancellationToken,
+    ) -> Result<(), ProtocolError> {
         debug!("run_with_miniprotocols: {:?}", self.party);
 
         // Create multiplexer state.
         let mut state: MultiplexerState = MultiplexerState::new();
 
         let (outgoing_channel_send, mut outgoing_channel) = mpsc::channel(OUTGOING_CAPACITY);
+        let (failure_send, mut failure_recv) = mpsc::unbounded_channel();
 
         // Initialize and spawn each miniprotocol.
         for (protocol_id, p) in miniprotocols.into_iter().enumerate() {
-            let protocol_id = protocol_id.try_into().expect("TODO");
+            let protocol_id = protocol_id
+                .try_into()
+                .expect("Unreachable: There are only a few initial miniprotocols.");
             let outgoing_channel_send = outgoing_channel_send.clone();
+            let failure_send = failure_send.clone();
 
             // Create window for the miniprotocol.
             let (sender, receiver) = mpsc::channel(PROTOCOL_INCOMING_CAPACITY);
 
             // Spawn async for the miniprotocol.
-            let handle = tokio::spawn(p.run_async::<O>(
-                self.party.is_client(),
-                protocol_id,
-                outgoing_channel_send,
-                receiver,
-            ));
+            let is_client = self.party.is_client();
+            let handle = tokio::spawn(async move {
+                let result = p
+                    .run_async::<O>(is_client, protocol_id, outgoing_channel_send, receiver)
+                    .await;
+                if let Err(err) = result {
+                    let _ = failure_send.send(err);
+                }
+            });
 
             let mp = MiniprotocolState { handle, sender };
             state.stream_map.insert(protocol_id, mp);
@@ -124,53 +132,83 @@ impl Multiplexer {
         // JP: Should we have separate threads for sending and receiving? Makes managing `state` annoying.
 
         loop {
-            let mut buf = BytesMut::with_capacity(BUFFER_SIZE);
-
             // Wait on data from client or data to send.
             tokio::select! {
+                _ = cancel.cancelled() => {
+                    debug!("Closing multiplexer: {:?}", self.party);
+                    close_streams(&mut stream, &state.stream_map).await;
+                    return Ok(());
+                }
+                Some(err) = failure_recv.recv() => {
+                    warn!("Closing connection since an initial miniprotocol failed: {}", err);
+                    close_streams(&mut stream, &state.stream_map).await;
+                    return Err(err);
+                }
                 msg_e = outgoing_channel.recv() => {
                     match msg_e {
                         None => {
-                            todo!()
+                            unreachable!("We hold a sender for the outgoing channel.")
                         }
                         // Some(Err(_e)) => {
                         //     todo!()
                         // }
-                        Some((stream_id, mut msg)) => {
-                            // We rely on the miniprotocol wrapper to send prepend the stream id, length, etc.
-                            // Write stream id and message length.
-                            // TODO: prepend this to the buffer so we don't make two system calls.
-                            stream.write_u32(stream_id).await.expect("TODO");
-
+                        Some((stream_id, msg)) => {
+                            // Write stream id, message length, and message as a single encrypted frame.
                             trace!("Sending on stream: {}", stream_id);
 
-                            let length = msg.len().try_into().expect("TODO");
-                            stream.write_u32(length).await.expect("TODO");
+                            if msg.len() > MAX_MESSAGE_LENGTH as usize {
+                                error!("Message on stream {} is too large: {}", stream_id, msg.len());
+                                return Err(ProtocolError::MessageTooLarge(msg.len()));
+                            }
+                            let length = msg.len() as u32;
                             trace!("Sending length: {}", length);
 
-                            // Write message.
-                            stream.write_all_buf(&mut msg).await.expect("TODO");
+                            let mut frame = BytesMut::with_capacity(HEADER_LENGTH + msg.len());
+                            frame.put_u32(stream_id);
+                            frame.put_u32(length);
+                            frame.extend_from_slice(&msg);
+
+                            if let Err(err) = stream.write_frame(&frame).await {
+                                error!("Failed to send to peer: {:?}", err);
+                                return Err(err.into());
+                            }
+
+                            // The miniprotocol finished, so forget the stream.
+                            if msg.is_empty() {
+                                debug!("Closed stream: {}", stream_id);
+                                state.stream_map.remove(&stream_id);
+                            }
                         }
                     }
                 }
-                result = stream.read_buf(&mut buf) => {
+                result = stream.read_frames() => {
                     match result {
+                        Err(TransportError::Closed) => {
+                            debug!("Peer closed the connection");
+                            return Ok(());
+                        }
                         Err(err) => {
-                            todo!("Error: {:?}", err);
+                            error!("Failed to receive from peer: {:?}", err);
+                            return Err(err.into());
                         }
-                        Ok(length) => {
-                            assert_eq!(length, buf.len());
-                            state.read_state = state.read_state.handle_receive(&state.stream_map, buf).await;
-                            trace!("Test out: {:?}", state.read_state);
+                        Ok(frames) => {
+                            for buf in frames {
+                                let read_state = std::mem::replace(&mut state.read_state, MultiplexerReadState::new());
+                                state.read_state = match read_state.handle_receive(&mut state.stream_map, buf).await {
+                                    Ok(read_state) => read_state,
+                                    Err(err) => {
+                                        warn!("Closing connection since the peer sent an invalid frame: {}", err);
+                                        close_streams(&mut stream, &state.stream_map).await;
+                                        return Err(err);
+                                    }
+                                };
+                                trace!("Test out: {:?}", state.read_state);
+                            }
                         }
                     }
                 }
-                cmd_m = self.command_recv.recv() => {
-                    let Some(cmd) = cmd_m else {
-                        warn!("Multiplexer command channel dropped");
-                        todo!();
-                    };
-
+                // Stop listening for commands once every manager has exited.
+                Some(cmd) = self.command_recv.recv() => {
                     match cmd {
                         MultiplexerCommand::CreateStream { stream_id, spawn_task, response_chan } => {
                             let outgoing_channel_send = outgoing_channel_send.clone();
@@ -186,7 +224,7 @@ impl Multiplexer {
                             let res = state.stream_map.try_insert(stream_id, mp);
 
                             // Send response.
-                            response_chan.send(res.is_ok()).expect("TODO");
+                            let _ = response_chan.send(res.is_ok());
                         }
                     }
                 }
@@ -210,8 +248,36 @@ impl MultiplexerState {
     }
 }
 
-const MAX_MESSAGE_LENGTH: u32 = 1000 * 1000 * 1024;
-const HEADER_LENGTH: usize = 8;
+/// Upper bound on the length of a miniprotocol message. The largest messages are ECG sync
+/// responses with up to `MAX_DELIVER_HEADERS` nodes, plus some room for their encoding.
+pub(crate) const MAX_MESSAGE_LENGTH: u32 =
+    (MAX_DELIVER_HEADERS as usize * ecg::MAX_NODE_SIZE) as u32 + 4096;
+pub(crate) const HEADER_LENGTH: usize = 8;
+
+// Block responses must fit in a message too.
+const _: () = assert!(BLOCK_REQUEST_LIMIT * BLOCK_SIZE < MAX_MESSAGE_LENGTH as u64);
+
+/// Frame that closes a stream. Serialized messages are never empty, so a message of length zero
+/// tells the peer that the stream is closed.
+fn close_frame(stream_id: StreamId) -> [u8; HEADER_LENGTH] {
+    let mut frame = [0; HEADER_LENGTH];
+    frame[0..4].copy_from_slice(&stream_id.to_be_bytes());
+    frame
+}
+
+/// Tell the peer we're closing every stream.
+async fn close_streams(
+    stream: &mut EncryptedStream,
+    stream_map: &BTreeMap<StreamId, MiniprotocolState>,
+) {
+    for stream_id in stream_map.keys() {
+        if let Err(err) = stream.write_frame(&close_frame(*stream_id)).await {
+            debug!("Failed to close stream {}: {:?}", stream_id, err);
+            return;
+        }
+    }
+}
+
 #[derive(Debug)]
 enum MultiplexerReadState {
     ProcessingHeader {
@@ -220,7 +286,8 @@ enum MultiplexerReadState {
     },
     ProcessingBody {
         msg_length: u32,
-        sender: Sender<BytesMut>,
+        /// `None` if the stream was already closed, in which case the message is dropped.
+        sender: Option<Sender<BytesMut>>,
         send_buffer: BytesMut,
         // TODO: miniprotocol channel?, Length, ...
     },
@@ -237,9 +304,9 @@ impl MultiplexerReadState {
     #[async_recursion]
     async fn handle_receive(
         self,
-        stream_map: &BTreeMap<StreamId, MiniprotocolState>,
+        stream_map: &mut BTreeMap<StreamId, MiniprotocolState>,
         mut buf: BytesMut,
-    ) -> MultiplexerReadState {
+    ) -> Result<MultiplexerReadState, ProtocolError> {
         trace!("Test in:  {:?}", self);
         trace!("{:?}", buf);
         match self {
@@ -266,17 +333,28 @@ impl MultiplexerReadState {
                     let stream_id = u32::from_be_bytes(stream_id);
                     trace!("Received stream id: {stream_id:?}");
 
-                    let p = stream_map.get(&stream_id).expect("TODO");
-                    let sender = p.sender.clone();
-
                     // Parse message length.
                     let msg_length = header[4..8].try_into().unwrap();
                     let msg_length = u32::from_be_bytes(msg_length);
                     trace!("Received msg_length: {msg_length:?}");
 
+                    // The peer closed the stream, which stops its miniprotocol.
+                    if msg_length == 0 {
+                        debug!("Peer closed stream: {stream_id}");
+                        stream_map.remove(&stream_id);
+                        let next_state = MultiplexerReadState::new();
+                        return next_state.handle_receive(stream_map, buf).await;
+                    }
+
+                    // Messages for streams that we already closed are dropped.
+                    let sender = stream_map.get(&stream_id).map(|p| p.sender.clone());
+                    if sender.is_none() {
+                        debug!("Dropping message for closed stream: {stream_id}");
+                    }
+
                     // Check upper bound on message length.
                     if msg_length > MAX_MESSAGE_LENGTH {
-                        todo!("TODO: Other party sent too large of a message.");
+                        return Err(ProtocolError::MessageTooLarge(msg_length as usize));
                     }
 
                     // Allocate buffer.
@@ -290,7 +368,7 @@ impl MultiplexerReadState {
 
                     return next_state.handle_receive(stream_map, buf).await;
                 } else {
-                    MultiplexerReadState::ProcessingHeader { position, header }
+                    Ok(MultiplexerReadState::ProcessingHeader { position, header })
                 }
             }
             MultiplexerReadState::ProcessingBody {
@@ -306,7 +384,11 @@ impl MultiplexerReadState {
 
                 // Send message if we've received the entire message.
                 if send_buffer.len() == msg_length as usize {
-                    sender.send(send_buffer).await.expect("TODO");
+                    if let Some(sender) = sender {
+                        if sender.send(send_buffer).await.is_err() {
+                            debug!("Miniprotocol exited before receiving message");
+                        }
+                    }
 
                     let next_state = MultiplexerReadState::new();
 
@@ -315,14 +397,14 @@ impl MultiplexerReadState {
                         debug!("Buffer isn't empty!");
                         return next_state.handle_receive(stream_map, buf).await;
                     } else {
-                        next_state
+                        Ok(next_state)
                     }
                 } else {
-                    MultiplexerReadState::ProcessingBody {
+                    Ok(MultiplexerReadState::ProcessingBody {
                         msg_length,
                         sender,
                         send_buffer,
-                    }
+                    })
                 }
             }
         }
@@ -335,6 +417,13 @@ struct MiniprotocolState {
     sender: mpsc::Sender<BytesMut>,
 }
 
+impl Drop for MiniprotocolState {
+    // Stop the miniprotocol when the multiplexer is torn down.
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
 // JP: TODO: This O probably isn't needed.
 pub(crate) async fn run_miniprotocol_async<P: MiniProtocol, O: OdysseyType>(
     p: P,
@@ -342,20 +431,30 @@ pub(crate) async fn run_miniprotocol_async<P: MiniProtocol, O: OdysseyType>(
     stream_id: StreamId,
     sender: Sender<(StreamId, Bytes)>,
     receiver: Receiver<BytesMut>,
-) {
-    // Serialize/deserialize byte channel
-    let stream = MuxStream::new(stream_id, sender, receiver);
+) -> Result<(), ProtocolError> {
+    let name = std::any::type_name_of_val(&p);
     debug!(
         "Launching miniprotocol: {} ({}, {stream_id:?})",
         if is_client { "Client" } else { "Server" },
-        std::any::type_name_of_val(&p)
+        name
     );
 
-    if is_client {
+    // Serialize/deserialize byte channel
+    let close_sender = sender.clone();
+    let stream = MuxStream::new(stream_id, sender, receiver);
+    let result = if is_client {
         p.run_client(stream).await
     } else {
         p.run_server(stream).await
+    };
+    if let Err(err) = &result {
+        warn!("Miniprotocol {} ({stream_id:?}) failed: {}", name, err);
     }
+
+    // Close the stream now that the miniprotocol is done.
+    let _ = close_sender.send((stream_id, Bytes::new())).await;
+
+    result
 }
 
 pub(crate) type SpawnMultiplexerTask =
@@ -487,3 +586,167 @@ where
 }
 
 impl<T> util::Stream<T> for MuxStream<T> where T: for<'a> Deserialize<'a> + Serialize {}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::core::test::TestOdyssey;
+    use crate::network::transport::TransportKeys;
+    use crate::protocol::heartbeat::v0::Heartbeat;
+
+    /// Both ends of an encrypted connection.
+    async fn connection() -> (EncryptedStream, EncryptedStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let keys = |send_key, receive_key| TransportKeys {
+            send_key,
+            receive_key,
+        };
+        (
+            EncryptedStream::new(client, BytesMut::new(), keys([1; 32], [2; 32])),
+            EncryptedStream::new(server, BytesMut::new(), keys([2; 32], [1; 32])),
+        )
+    }
+
+    /// Run a client multiplexer with the heartbeat miniprotocol on stream 0, and an extra stream
+    /// with the given id. Returns the multiplexer's task and a receiver that fails once the extra
+    /// stream's task is stopped.
+    async fn run_multiplexer(
+        stream: EncryptedStream,
+        stream_id: StreamId,
+    ) -> (JoinHandle<Result<(), ProtocolError>>, oneshot::Receiver<()>) {
+        let (command_send, command_recv) = tokio::sync::mpsc::unbounded_channel();
+        let multiplexer = Multiplexer::new(Party::Client, command_recv);
+        let handle = tokio::spawn(multiplexer.run_with_miniprotocols::<TestOdyssey>(
+            stream,
+            vec![MiniProtocols::Heartbeat(Heartbeat {})],
+            CancellationToken::new(),
+        ));
+
+        let (stopped_send, stopped_recv) = oneshot::channel();
+        let (response_chan, response) = oneshot::channel();
+        command_send
+            .send(MultiplexerCommand::CreateStream {
+                stream_id,
+                spawn_task: Box::new(move |_, _, _, _| {
+                    tokio::spawn(async move {
+                        let _stopped_send = stopped_send;
+                        futures::future::pending::<()>().await
+                    })
+                }),
+                response_chan,
+            })
+            .unwrap();
+        assert!(response.await.unwrap());
+
+        (handle, stopped_recv)
+    }
+
+    /// Read frames until the connection closes and return the ids of the streams that were closed.
+    async fn closed_streams(stream: &mut EncryptedStream) -> BTreeSet<StreamId> {
+        let mut closed = BTreeSet::new();
+        while let Ok(frames) = stream.read_frames().await {
+            for frame in frames {
+                if frame.len() == HEADER_LENGTH && frame[4..] == [0; 4] {
+                    closed.insert(u32::from_be_bytes(frame[..4].try_into().unwrap()));
+                }
+            }
+        }
+        closed
+    }
+
+    #[test]
+    fn test_close_stream() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (sender, mut receiver) = mpsc::channel(PROTOCOL_INCOMING_CAPACITY);
+            let handle = tokio::spawn(futures::future::pending());
+            let mut stream_map = BTreeMap::new();
+            stream_map.insert(3, MiniprotocolState { handle, sender });
+
+            // A message followed by a close frame for the stream.
+            let mut buf = BytesMut::new();
+            buf.put_u32(3);
+            buf.put_u32(2);
+            buf.extend_from_slice(&[7, 8]);
+            buf.extend_from_slice(&close_frame(3));
+            let state = MultiplexerReadState::new()
+                .handle_receive(&mut stream_map, buf)
+                .await
+                .unwrap();
+
+            assert_eq!(&receiver.recv().await.unwrap()[..], &[7, 8]);
+            assert!(stream_map.is_empty());
+            // Closing the stream stops the miniprotocol.
+            assert!(receiver.recv().await.is_none());
+
+            // Messages that arrive after the stream is closed are dropped.
+            let mut buf = BytesMut::new();
+            buf.put_u32(3);
+            buf.put_u32(1);
+            buf.extend_from_slice(&[9]);
+            let state = state.handle_receive(&mut stream_map, buf).await.unwrap();
+            assert!(matches!(
+                state,
+                MultiplexerReadState::ProcessingHeader { position: 0, .. }
+            ));
+        });
+    }
+
+    #[test]
+    fn test_message_too_large() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut stream_map = BTreeMap::new();
+
+            let mut buf = BytesMut::new();
+            buf.put_u32(3);
+            buf.put_u32(MAX_MESSAGE_LENGTH + 1);
+            let result = MultiplexerReadState::new()
+                .handle_receive(&mut stream_map, buf)
+                .await;
+            assert!(matches!(result, Err(ProtocolError::MessageTooLarge(_))));
+        });
+    }
+
+    #[test]
+    fn test_miniprotocol_failure_closes_streams() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (stream, mut peer) = connection().await;
+            let (handle, stopped) = run_multiplexer(stream, 5).await;
+
+            // Send a malformed heartbeat, which fails the heartbeat miniprotocol.
+            let mut frame = BytesMut::new();
+            frame.put_u32(0);
+            frame.put_u32(1);
+            frame.put_u8(0xff);
+            peer.write_frame(&frame).await.unwrap();
+
+            assert!(matches!(
+                handle.await.unwrap(),
+                Err(ProtocolError::DeserializationError(_))
+            ));
+            assert!(stopped.await.is_err());
+            assert!(closed_streams(&mut peer).await.contains(&5));
+        });
+    }
+
+    #[test]
+    fn test_peer_closing_stops_streams() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (stream, peer) = connection().await;
+            let (handle, stopped) = run_multiplexer(stream, 5).await;
+
+            drop(peer);
+            assert!(handle.await.unwrap().is_ok());
+            assert!(stopped.await.is_err());
+        });
+    }
+}
@@ -1,10 +1,16 @@
 use bytes::Bytes;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::Signature;
 use futures::{SinkExt, StreamExt};
+use hkdf::Hkdf;
+use rand_core::OsRng;
 use serde::{Deserialize, Serialize};
 use serde_cbor::to_vec;
+use sha2::{Digest, Sha256};
 use std::any::type_name;
 use std::collections::BTreeSet;
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display};
 use std::future::Future;
 use std::marker::Send;
 use tokio::net::TcpStream;
@@ -13,16 +19,18 @@ use tokio_util::{
     codec::{self, LengthDelimitedCodec},
     sync::PollSendError,
 };
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, trace};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+use zeroize::Zeroize;
 
+use crate::network::transport::{EncryptedStream, TransportError, TransportKeys};
 use crate::protocol::v0::{
     MsgStoreMetadataHeader, StoreMetadataHeaderRequest, StoreMetadataHeaderResponse,
 };
-use crate::protocol::Version;
-use crate::store::v0::MetadataHeader;
+use crate::protocol::{Version, SUPPORTED_VERSIONS};
 use crate::util::Stream;
 use crate::{
-    auth::DeviceId,
+    auth::{DeviceId, Identity},
     core::{OdysseyType, StoreStatuses},
     network::multiplexer,
 };
@@ -35,23 +43,171 @@ pub(crate) trait MiniProtocol: Send {
 
     // fn run_client<S: Stream<Self::Message>, O: OdysseyType>(self, stream: S, active_stores: watch::Receiver<StoreStatuses<O::StoreId>>,) -> impl Future<Output = ()> + Send;
     // fn run_server<S: Stream<Self::Message>, O: OdysseyType>(self, stream: S, active_stores: watch::Receiver<StoreStatuses<O::StoreId>>,) -> impl Future<Output = ()> + Send;
-    fn run_client<S: Stream<Self::Message>>(self, stream: S) -> impl Future<Output = ()> + Send;
-    fn run_server<S: Stream<Self::Message>>(self, stream: S) -> impl Future<Output = ()> + Send;
+    /// Run the client side of the miniprotocol. An error stops the miniprotocol and closes its
+    /// stream.
+    fn run_client<S: Stream<Self::Message>>(
+        self,
+        stream: S,
+    ) -> impl Future<Output = Result<(), ProtocolError>> + Send;
+    /// Run the server side of the miniprotocol. An error stops the miniprotocol and closes its
+    /// stream.
+    fn run_server<S: Stream<Self::Message>>(
+        self,
+        stream: S,
+    ) -> impl Future<Output = Result<(), ProtocolError>> + Send;
 }
 
 // pub enum ProtocolVersion {
 //     V0,
 // }
 
-type MsgHandshake = DeviceId;
+/// Label used to domain separate the handshake's hashes and keys.
+const HANDSHAKE_LABEL: &[u8] = b"odyssey-handshake-v0";
+
+// Handshake (similar to Noise XX, but authenticated with each device's ed25519 `auth_key`):
+// 1. Client -> Server: Client's ephemeral X25519 key and the protocol versions it supports.
+// 2. Server -> Client: Server's ephemeral X25519 key, the highest protocol version both support,
+//    plus the server's `DeviceId` and its signature over the handshake transcript, encrypted with
+//    a key derived from the ephemeral Diffie-Hellman secret.
+//    If there is no common version, the server instead replies with the versions it supports and
+//    closes the connection.
+// 3. Client -> Server: The client's `DeviceId` and its signature over the handshake transcript,
+//    encrypted with a key derived from the ephemeral Diffie-Hellman secret.
+// Both parties then derive a key for each direction of the connection from the Diffie-Hellman
+// secret and the transcript, which encrypts every multiplexer frame.
+// The advertised versions are part of the transcript, so they can't be downgraded by an attacker.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) enum MsgHandshake {
+    Init(HandshakeInit),
+    Response(HandshakeResponse),
+    Reject(HandshakeReject),
+    Finish(HandshakeFinish),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct HandshakeInit {
+    ephemeral_key: [u8; 32],
+    /// Protocol versions (as bytes) supported by the client.
+    versions: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct HandshakeResponse {
+    ephemeral_key: [u8; 32],
+    /// Protocol version chosen by the server.
+    version: u8,
+    /// Encrypted `HandshakeIdentity` of the server.
+    encrypted_identity: Vec<u8>,
+}
+
+/// Sent by the server when it doesn't support any of the client's versions.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct HandshakeReject {
+    /// Protocol versions (as bytes) supported by the server.
+    versions: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct HandshakeFinish {
+    /// Encrypted `HandshakeIdentity` of the client.
+    encrypted_identity: Vec<u8>,
+}
+
+/// Proof that a party owns the private key for its `DeviceId`.
+#[derive(Debug, Deserialize, Serialize)]
+struct HandshakeIdentity {
+    device_id: DeviceId,
+    /// Signature of the handshake transcript.
+    signature: Signature,
+}
+
+impl From<HandshakeInit> for MsgHandshake {
+    fn from(msg: HandshakeInit) -> MsgHandshake {
+        MsgHandshake::Init(msg)
+    }
+}
+impl From<HandshakeResponse> for MsgHandshake {
+    fn from(msg: HandshakeResponse) -> MsgHandshake {
+        MsgHandshake::Response(msg)
+    }
+}
+impl From<HandshakeReject> for MsgHandshake {
+    fn from(msg: HandshakeReject) -> MsgHandshake {
+        MsgHandshake::Reject(msg)
+    }
+}
+impl From<HandshakeFinish> for MsgHandshake {
+    fn from(msg: HandshakeFinish) -> MsgHandshake {
+        MsgHandshake::Finish(msg)
+    }
+}
+impl TryFrom<MsgHandshake> for HandshakeInit {
+    type Error = ();
+    fn try_from(msg: MsgHandshake) -> Result<HandshakeInit, ()> {
+        match msg {
+            MsgHandshake::Init(r) => Ok(r),
+            _ => Err(()),
+        }
+    }
+}
+impl TryFrom<MsgHandshake> for HandshakeResponse {
+    type Error = ();
+    fn try_from(msg: MsgHandshake) -> Result<HandshakeResponse, ()> {
+        match msg {
+            MsgHandshake::Response(r) => Ok(r),
+            _ => Err(()),
+        }
+    }
+}
+impl TryFrom<MsgHandshake> for HandshakeReject {
+    type Error = ();
+    fn try_from(msg: MsgHandshake) -> Result<HandshakeReject, ()> {
+        match msg {
+            MsgHandshake::Reject(r) => Ok(r),
+            _ => Err(()),
+        }
+    }
+}
+impl TryFrom<MsgHandshake> for HandshakeFinish {
+    type Error = ();
+    fn try_from(msg: MsgHandshake) -> Result<HandshakeFinish, ()> {
+        match msg {
+            MsgHandshake::Finish(r) => Ok(r),
+            _ => Err(()),
+        }
+    }
+}
 
 pub(crate) struct HandshakeInfo {
     version: Version,
     peer_id: DeviceId,
+    transport_keys: TransportKeys,
 }
 
-pub(crate) enum HandshakeError {
+#[derive(Debug)]
+pub enum HandshakeError {
     ConnectingToSelf,
+    /// Failed to send or receive handshake messages.
+    ProtocolError(ProtocolError),
+    /// The Diffie-Hellman exchange was not contributory (the peer sent a low order point).
+    InvalidEphemeralKey,
+    /// The peer's identity could not be decrypted or parsed.
+    DecryptionFailed,
+    /// The peer failed to prove that it owns the private key of the `DeviceId` it claims.
+    InvalidSignature,
+    /// We and the peer don't support any protocol version in common.
+    NoCommonVersion {
+        /// Protocol versions (as bytes) the peer supports.
+        peer_versions: Vec<u8>,
+    },
+    /// The server chose a protocol version that we didn't advertise.
+    UnsupportedVersion(u8),
+}
+
+impl From<ProtocolError> for HandshakeError {
+    fn from(err: ProtocolError) -> Self {
+        HandshakeError::ProtocolError(err)
+    }
 }
 
 impl HandshakeInfo {
@@ -62,79 +218,287 @@ impl HandshakeInfo {
     pub(crate) fn peer_id(&self) -> DeviceId {
         self.peer_id
     }
+
+    /// Encrypt the connection with the keys established during the handshake.
+    pub(crate) fn into_encrypted_stream(
+        self,
+        stream: codec::Framed<TcpStream, LengthDelimitedCodec>,
+    ) -> EncryptedStream {
+        // Keep any bytes the framer already read past the handshake.
+        let parts = stream.into_parts();
+        EncryptedStream::new(parts.io, parts.read_buf, self.transport_keys)
+    }
+}
+
+/// Hash the parts of the handshake transcript.
+fn transcript_hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(HANDSHAKE_LABEL);
+    for part in parts {
+        h.update((part.len() as u32).to_be_bytes());
+        h.update(part);
+    }
+    h.finalize().into()
+}
+
+/// Derive a key from the Diffie-Hellman secret.
+fn derive_key(shared_secret: &SharedSecret, salt: &[u8; 32], info: &[u8]) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), shared_secret.as_bytes());
+    let mut key = [0; 32];
+    hkdf.expand(info, &mut key)
+        .expect("Unreachable: 32 bytes is a valid HKDF-SHA256 output length.");
+    key
+}
+
+/// Encrypt our identity. Each key is only used for a single message, so the nonce is fixed.
+fn seal_identity(key: &[u8; 32], identity: &HandshakeIdentity) -> Result<Vec<u8>, HandshakeError> {
+    let plaintext = serde_cbor::to_vec(identity)
+        .map_err(|err| HandshakeError::ProtocolError(ProtocolError::SerializationError(err)))?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .encrypt(&Nonce::default(), plaintext.as_slice())
+        .map_err(|_| HandshakeError::DecryptionFailed)
+}
+
+/// Decrypt the peer's identity.
+fn open_identity(key: &[u8; 32], ciphertext: &[u8]) -> Result<HandshakeIdentity, HandshakeError> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let plaintext = cipher
+        .decrypt(&Nonce::default(), ciphertext)
+        .map_err(|_| HandshakeError::DecryptionFailed)?;
+    serde_cbor::from_slice(&plaintext).map_err(|_| HandshakeError::DecryptionFailed)
+}
+
+/// Keys and transcript shared by both parties once the ephemeral keys are exchanged.
+struct HandshakeKeys {
+    shared_secret: SharedSecret,
+    transcript: [u8; 32],
+    server_identity_key: [u8; 32],
+    client_identity_key: [u8; 32],
+}
+
+impl HandshakeKeys {
+    fn new(
+        ephemeral_secret: EphemeralSecret,
+        their_ephemeral_key: [u8; 32],
+        client_ephemeral_key: &[u8; 32],
+        server_ephemeral_key: &[u8; 32],
+        client_versions: &[u8],
+        version: Version,
+    ) -> Result<HandshakeKeys, HandshakeError> {
+        let shared_secret = ephemeral_secret.diffie_hellman(&PublicKey::from(their_ephemeral_key));
+        if !shared_secret.was_contributory() {
+            return Err(HandshakeError::InvalidEphemeralKey);
+        }
+
+        let transcript = transcript_hash(&[
+            client_ephemeral_key,
+            server_ephemeral_key,
+            client_versions,
+            &[version.as_byte()],
+        ]);
+        let server_identity_key = derive_key(&shared_secret, &transcript, b"server identity");
+        let client_identity_key = derive_key(&shared_secret, &transcript, b"client identity");
+        Ok(HandshakeKeys {
+            shared_secret,
+            transcript,
+            server_identity_key,
+            client_identity_key,
+        })
+    }
+
+    /// Message the server signs to prove its identity.
+    fn server_proof(&self, server_id: &DeviceId) -> [u8; 32] {
+        transcript_hash(&[b"server", &self.transcript, server_id.as_bytes()])
+    }
+
+    /// Message the client signs to prove its identity.
+    fn client_proof(&self, server_id: &DeviceId, client_id: &DeviceId) -> [u8; 32] {
+        transcript_hash(&[
+            b"client",
+            &self.transcript,
+            server_id.as_bytes(),
+            client_id.as_bytes(),
+        ])
+    }
+
+    /// Derive the (client to server, server to client) transport keys.
+    fn transport_keys(&self, server_id: &DeviceId, client_id: &DeviceId) -> ([u8; 32], [u8; 32]) {
+        let salt = transcript_hash(&[
+            b"transport",
+            &self.transcript,
+            server_id.as_bytes(),
+            client_id.as_bytes(),
+        ]);
+        let client_to_server = derive_key(&self.shared_secret, &salt, b"client to server");
+        let server_to_client = derive_key(&self.shared_secret, &salt, b"server to client");
+        (client_to_server, server_to_client)
+    }
+}
+
+impl Drop for HandshakeKeys {
+    fn drop(&mut self) {
+        self.server_identity_key.zeroize();
+        self.client_identity_key.zeroize();
+    }
+}
+
+/// The protocol versions we advertise during the handshake.
+fn supported_versions() -> Vec<u8> {
+    SUPPORTED_VERSIONS.iter().map(|v| v.as_byte()).collect()
 }
 
-// TODO: Actually setup TLS connection and get their DeviceId.
 pub(crate) async fn run_handshake_server<S: Stream<MsgHandshake>>(
     stream: &mut S,
-    device_id: &DeviceId,
+    identity: &Identity,
 ) -> Result<HandshakeInfo, HandshakeError> {
-    // TODO: Implement this and make it abstract.
+    let device_id = identity.device_id();
 
-    // Get their DeviceId.
-    let peer_id = receive(stream).await.expect("TODO");
+    // Get their ephemeral key and pick the protocol version.
+    let init: HandshakeInit = receive(stream).await?;
+    let Some(version) = Version::negotiate(&init.versions) else {
+        send(
+            stream,
+            HandshakeReject {
+                versions: supported_versions(),
+            },
+        )
+        .await?;
+        return Err(HandshakeError::NoCommonVersion {
+            peer_versions: init.versions,
+        });
+    };
 
-    // Send our DeviceId.
-    send(stream, *device_id).await.expect("TODO");
+    // Generate our ephemeral key and derive the handshake keys.
+    let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_key = PublicKey::from(&ephemeral_secret).to_bytes();
+    let keys = HandshakeKeys::new(
+        ephemeral_secret,
+        init.ephemeral_key,
+        &init.ephemeral_key,
+        &ephemeral_key,
+        &init.versions,
+        version,
+    )?;
+
+    // Send our ephemeral key and prove our identity.
+    let our_identity = HandshakeIdentity {
+        device_id,
+        signature: identity.sign(&keys.server_proof(&device_id)),
+    };
+    let encrypted_identity = seal_identity(&keys.server_identity_key, &our_identity)?;
+    send(
+        stream,
+        HandshakeResponse {
+            ephemeral_key,
+            version: version.as_byte(),
+            encrypted_identity,
+        },
+    )
+    .await?;
+
+    // Get and authenticate their identity.
+    let finish: HandshakeFinish = receive(stream).await?;
+    let their_identity = open_identity(&keys.client_identity_key, &finish.encrypted_identity)?;
+    let peer_id = their_identity.device_id;
+    if !peer_id.verify(
+        &keys.client_proof(&device_id, &peer_id),
+        &their_identity.signature,
+    ) {
+        return Err(HandshakeError::InvalidSignature);
+    }
 
     // Check that the peer isn't us.
-    if device_id == &peer_id {
+    if device_id == peer_id {
         return Err(HandshakeError::ConnectingToSelf);
     }
 
+    let (client_to_server, server_to_client) = keys.transport_keys(&device_id, &peer_id);
     Ok(HandshakeInfo {
         peer_id,
-        version: Version::V0,
+        version,
+        transport_keys: TransportKeys {
+            send_key: server_to_client,
+            receive_key: client_to_server,
+        },
     })
 }
 
 pub(crate) async fn run_handshake_client<S: Stream<MsgHandshake>>(
     stream: &mut S,
-    device_id: &DeviceId,
+    identity: &Identity,
 ) -> Result<HandshakeInfo, HandshakeError> {
-    // TODO: Implement this and make it abstract.
+    let device_id = identity.device_id();
+
+    // Send our ephemeral key and the versions we support.
+    let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_key = PublicKey::from(&ephemeral_secret).to_bytes();
+    let versions = supported_versions();
+    send(
+        stream,
+        HandshakeInit {
+            ephemeral_key,
+            versions: versions.clone(),
+        },
+    )
+    .await?;
+
+    // Get their ephemeral key and chosen version.
+    let response = match receive(stream).await? {
+        MsgHandshake::Response(response) => response,
+        MsgHandshake::Reject(reject) => {
+            return Err(HandshakeError::NoCommonVersion {
+                peer_versions: reject.versions,
+            });
+        }
+        msg => {
+            error!("Received unexpected handshake message: {:?}", msg);
+            return Err(ProtocolError::ProtocolDeviation.into());
+        }
+    };
+    let version = Version::from_byte(response.version)
+        .filter(|v| versions.contains(&v.as_byte()))
+        .ok_or(HandshakeError::UnsupportedVersion(response.version))?;
+
+    // Derive the handshake keys.
+    let keys = HandshakeKeys::new(
+        ephemeral_secret,
+        response.ephemeral_key,
+        &ephemeral_key,
+        &response.ephemeral_key,
+        &versions,
+        version,
+    )?;
 
-    // Send our DeviceId.
-    send(stream, *device_id).await.expect("TODO");
+    // Authenticate their identity.
+    let their_identity = open_identity(&keys.server_identity_key, &response.encrypted_identity)?;
+    let peer_id = their_identity.device_id;
+    if !peer_id.verify(&keys.server_proof(&peer_id), &their_identity.signature) {
+        return Err(HandshakeError::InvalidSignature);
+    }
 
-    // Get their DeviceId.
-    let peer_id = receive(stream).await.expect("TODO");
+    // Prove our identity.
+    let our_identity = HandshakeIdentity {
+        device_id,
+        signature: identity.sign(&keys.client_proof(&peer_id, &device_id)),
+    };
+    let encrypted_identity = seal_identity(&keys.client_identity_key, &our_identity)?;
+    send(stream, HandshakeFinish { encrypted_identity }).await?;
 
     // Check that the peer isn't us.
-    if device_id == &peer_id {
+    if device_id == peer_id {
         return Err(HandshakeError::ConnectingToSelf);
     }
 
+    let (client_to_server, server_to_client) = keys.transport_keys(&peer_id, &device_id);
     Ok(HandshakeInfo {
         peer_id,
-        version: Version::V0,
-    })
-}
-
-// TODO: Generalize the argument.
-// pub(crate) async fn run_store_metadata_server<'a, StoreId:Deserialize<'a>>(stream: &mut codec::Framed<TcpStream, LengthDelimitedCodec>) -> () {
-pub(crate) async fn run_store_metadata_server<StoreId, S: Stream<MsgStoreMetadataHeader<StoreId>>>(
-    stream: &mut S,
-) -> Result<(), ProtocolError>
-where
-    StoreId: for<'a> Deserialize<'a> + Send + Debug,
-{
-    let req: StoreMetadataHeaderRequest<StoreId> = receive(stream).await?;
-    info!("Received request: {:?}", req);
-
-    // TODO: Proper response.
-    let response: StoreMetadataHeaderResponse<StoreId> = StoreMetadataHeaderResponse {
-        header: MetadataHeader {
-            nonce: [0; 32],
-            protocol_version: Version::V0,
-            store_type: todo!(), // [1;32],
-            initial_state_size: 0,
-            merkle_root: todo!(), // [2;32],
+        version,
+        transport_keys: TransportKeys {
+            send_key: client_to_server,
+            receive_key: server_to_client,
         },
-        // body: None,
-    };
-
-    send(stream, response).await
+    })
 }
 
 // pub async fn run_store_metadata_client<TypeId, StoreId, S:Stream<MsgStoreMetadataHeader<TypeId, StoreId>>>(stream: &mut codec::Framed<TcpStream, LengthDelimitedCodec>, request: &StoreMetadataHeaderRequest<StoreId>) -> Result<StoreMetadataHeaderResponse<TypeId, StoreId>, ProtocolError>
@@ -159,6 +523,51 @@ pub enum ProtocolError {
     StreamReceiveError(std::io::Error),
     ProtocolDeviation, // Temporary?
     ChannelSendError(PollSendError<(multiplexer::StreamId, Bytes)>),
+    /// The encrypted connection to the peer failed.
+    TransportError(TransportError),
+    /// The peer sent a message larger than allowed.
+    MessageTooLarge(usize),
+    /// The store being synced was closed.
+    StoreClosed,
+    /// The peer rejected our request, for example because we aren't allowed to read the store.
+    Rejected,
+    /// The connection's multiplexer stopped.
+    MultiplexerClosed,
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::SerializationError(err) => {
+                write!(f, "Failed to serialize message: {err}")
+            }
+            ProtocolError::DeserializationError(err) => {
+                write!(f, "Failed to deserialize message: {err}")
+            }
+            ProtocolError::ReceivedNoData => write!(f, "Stream closed by peer"),
+            ProtocolError::StreamSendError(err) => write!(f, "Failed to send message: {err}"),
+            ProtocolError::StreamReceiveError(err) => write!(f, "Failed to receive message: {err}"),
+            ProtocolError::ProtocolDeviation => write!(f, "Peer deviated from the protocol"),
+            ProtocolError::ChannelSendError(_) => {
+                write!(f, "Failed to send message to multiplexer")
+            }
+            ProtocolError::TransportError(err) => write!(f, "Connection failed: {err}"),
+            ProtocolError::MessageTooLarge(length) => {
+                write!(f, "Message too large: {length} bytes")
+            }
+            ProtocolError::StoreClosed => write!(f, "Store closed"),
+            ProtocolError::Rejected => write!(f, "Peer rejected the request"),
+            ProtocolError::MultiplexerClosed => write!(f, "Multiplexer closed"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<TransportError> for ProtocolError {
+    fn from(err: TransportError) -> Self {
+        ProtocolError::TransportError(err)
+    }
 }
 
 /// Send a message over the given stream.
@@ -169,8 +578,7 @@ where
 {
     match stream.send(message.into()).await {
         Err(err) => {
-            // TODO: Push the error up the stack instead of recording it here?
-            error!("Failed to send {}: {:?}", type_name::<T>(), err);
+            debug!("Failed to send {}: {:?}", type_name::<T>(), err);
             Err(err)
         }
         Ok(()) => Ok(()),
@@ -186,17 +594,17 @@ where
 {
     match stream.next().await {
         None => {
-            error!("Failed to receive data from peer"); // Closed connection?
+            debug!("Failed to receive data from peer"); // Closed connection?
             Err(ProtocolError::ReceivedNoData)
         }
         Some(Err(err)) => {
-            error!("Error while receiving data from peer: {:?}", err);
+            debug!("Error while receiving data from peer: {:?}", err);
             Err(err)
         }
         Some(Ok(msg)) => {
             match msg.try_into() {
                 Err(err) => {
-                    error!("Received unexpected data from peer"); // : {:?}", err);
+                    debug!("Received unexpected data from peer"); // : {:?}", err);
                     Err(ProtocolError::ProtocolDeviation)
                 }
                 Ok(msg) => {
@@ -207,3 +615,116 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::generate_identity;
+    use crate::protocol::LATEST_VERSION;
+    use crate::util::TypedStream;
+    use tokio::net::TcpListener;
+
+    async fn connect() -> (
+        codec::Framed<TcpStream, LengthDelimitedCodec>,
+        codec::Framed<TcpStream, LengthDelimitedCodec>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(address), listener.accept());
+        (
+            codec::Framed::new(server.unwrap().0, LengthDelimitedCodec::new()),
+            codec::Framed::new(client.unwrap(), LengthDelimitedCodec::new()),
+        )
+    }
+
+    #[test]
+    fn test_handshake() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let server_identity = generate_identity();
+            let client_identity = generate_identity();
+            let (server, client) = connect().await;
+            let mut server = TypedStream::new(server);
+            let mut client = TypedStream::new(client);
+
+            let (server_result, client_result) = tokio::join!(
+                run_handshake_server(&mut server, &server_identity),
+                run_handshake_client(&mut client, &client_identity),
+            );
+            let server_result = server_result.unwrap();
+            let client_result = client_result.unwrap();
+            assert_eq!(server_result.peer_id(), client_identity.device_id());
+            assert_eq!(client_result.peer_id(), server_identity.device_id());
+            assert_eq!(server_result.version(), LATEST_VERSION);
+            assert_eq!(client_result.version(), LATEST_VERSION);
+
+            // Frames sent by one side decrypt on the other.
+            let mut server = server_result.into_encrypted_stream(server.finalize());
+            let mut client = client_result.into_encrypted_stream(client.finalize());
+            client.write_frame(b"hello").await.unwrap();
+            server.write_frame(b"world").await.unwrap();
+            assert_eq!(&server.read_frames().await.unwrap()[0][..], b"hello");
+            assert_eq!(&client.read_frames().await.unwrap()[0][..], b"world");
+        });
+    }
+
+    #[test]
+    fn test_handshake_no_common_version() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let identity = generate_identity();
+            let (server, client) = connect().await;
+            let mut server = TypedStream::new(server);
+            let mut client: TypedStream<_, MsgHandshake> = TypedStream::new(client);
+
+            // A client that only speaks a future version.
+            let client = async {
+                let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+                let ephemeral_key = PublicKey::from(&ephemeral_secret).to_bytes();
+                send(
+                    &mut client,
+                    HandshakeInit {
+                        ephemeral_key,
+                        versions: vec![200],
+                    },
+                )
+                .await
+                .unwrap();
+                let reject: HandshakeReject = receive(&mut client).await.unwrap();
+                reject.versions
+            };
+
+            let (server_result, server_versions) =
+                tokio::join!(run_handshake_server(&mut server, &identity), client);
+            assert_eq!(server_versions, supported_versions());
+            assert!(matches!(
+                server_result,
+                Err(HandshakeError::NoCommonVersion { peer_versions }) if peer_versions == vec![200]
+            ));
+        });
+    }
+
+    #[test]
+    fn test_handshake_to_self() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let identity = generate_identity();
+            let (server, client) = connect().await;
+            let mut server = TypedStream::new(server);
+            let mut client = TypedStream::new(client);
+
+            let (server_result, client_result) = tokio::join!(
+                run_handshake_server(&mut server, &identity),
+                run_handshake_client(&mut client, &identity),
+            );
+            assert!(matches!(
+                server_result,
+                Err(HandshakeError::ConnectingToSelf)
+            ));
+            assert!(matches!(
+                client_result,
+                Err(HandshakeError::ConnectingToSelf)
+            ));
+        });
+    }
+}
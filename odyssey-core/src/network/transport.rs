@@ -0,0 +1,217 @@
+use bytes::{Buf, BytesMut};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use zeroize::Zeroize;
+
+use crate::network::multiplexer::{HEADER_LENGTH, MAX_MESSAGE_LENGTH};
+
+/// Size of the length prefix of encrypted frames.
+const LENGTH_PREFIX_SIZE: usize = 4;
+/// Size of the authentication tag appended to every encrypted frame.
+const TAG_SIZE: usize = 16;
+/// Upper bound on the size of an encrypted frame, which holds one multiplexer message.
+const MAX_FRAME_LENGTH: usize = HEADER_LENGTH + MAX_MESSAGE_LENGTH as usize + TAG_SIZE;
+const BUFFER_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// The underlying stream failed.
+    Io(std::io::Error),
+    /// The peer closed the connection.
+    Closed,
+    /// A frame failed to authenticate. The peer is misbehaving or the connection was tampered with.
+    DecryptionFailed,
+    /// A frame was larger than allowed.
+    FrameTooLarge,
+    /// We've sent or received too many frames with the same key.
+    NonceExhausted,
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportError::Io(err) => write!(f, "IO error: {err}"),
+            TransportError::Closed => write!(f, "Connection closed"),
+            TransportError::DecryptionFailed => write!(f, "Failed to decrypt frame"),
+            TransportError::FrameTooLarge => write!(f, "Frame too large"),
+            TransportError::NonceExhausted => write!(f, "Nonces exhausted"),
+        }
+    }
+}
+
+impl From<std::io::Error> for TransportError {
+    fn from(err: std::io::Error) -> Self {
+        TransportError::Io(err)
+    }
+}
+
+/// Symmetric keys for each direction of a connection, derived during the handshake.
+pub(crate) struct TransportKeys {
+    pub(crate) send_key: [u8; 32],
+    pub(crate) receive_key: [u8; 32],
+}
+
+impl Drop for TransportKeys {
+    fn drop(&mut self) {
+        self.send_key.zeroize();
+        self.receive_key.zeroize();
+    }
+}
+
+/// AEAD cipher with a nonce counter. Each nonce is only ever used once per key.
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl CipherState {
+    fn new(key: &[u8; 32]) -> CipherState {
+        CipherState {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<Nonce, TransportError> {
+        let counter = self.counter;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(TransportError::NonceExhausted)?;
+
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        Ok(*Nonce::from_slice(&nonce))
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let nonce = self.next_nonce()?;
+        // Encryption only fails if the plaintext is too large, which we bound elsewhere.
+        self.cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| TransportError::FrameTooLarge)
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let nonce = self.next_nonce()?;
+        self.cipher
+            .decrypt(&nonce, ciphertext)
+            .map_err(|_| TransportError::DecryptionFailed)
+    }
+}
+
+/// A TCP stream where every frame is encrypted and authenticated.
+/// Frames are sent as a big endian u32 length followed by the ciphertext.
+pub(crate) struct EncryptedStream {
+    stream: TcpStream,
+    send_cipher: CipherState,
+    receive_cipher: CipherState,
+    /// Received bytes that haven't been decrypted yet.
+    read_buffer: BytesMut,
+}
+
+impl EncryptedStream {
+    /// Wrap a stream after a successful handshake. `leftover` are bytes that were already read
+    /// from the stream during the handshake.
+    pub(crate) fn new(stream: TcpStream, leftover: BytesMut, keys: TransportKeys) -> Self {
+        EncryptedStream {
+            stream,
+            send_cipher: CipherState::new(&keys.send_key),
+            receive_cipher: CipherState::new(&keys.receive_key),
+            read_buffer: leftover,
+        }
+    }
+
+    /// Encrypt and send a frame.
+    pub(crate) async fn write_frame(&mut self, plaintext: &[u8]) -> Result<(), TransportError> {
+        if plaintext.len() + TAG_SIZE > MAX_FRAME_LENGTH {
+            return Err(TransportError::FrameTooLarge);
+        }
+        let ciphertext = self.send_cipher.encrypt(plaintext)?;
+
+        let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + ciphertext.len());
+        frame.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&ciphertext);
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Receive and decrypt the next available frames.
+    /// This is cancellation safe, so it can be used in `tokio::select!`.
+    pub(crate) async fn read_frames(&mut self) -> Result<Vec<BytesMut>, TransportError> {
+        loop {
+            let frames = self.decrypt_buffered()?;
+            if !frames.is_empty() {
+                return Ok(frames);
+            }
+
+            self.read_buffer.reserve(BUFFER_SIZE);
+            let length = self.stream.read_buf(&mut self.read_buffer).await?;
+            if length == 0 {
+                return Err(TransportError::Closed);
+            }
+        }
+    }
+
+    /// Decrypt all complete frames in the read buffer.
+    fn decrypt_buffered(&mut self) -> Result<Vec<BytesMut>, TransportError> {
+        let mut frames = vec![];
+        while self.read_buffer.len() >= LENGTH_PREFIX_SIZE {
+            let length = u32::from_be_bytes(
+                self.read_buffer[..LENGTH_PREFIX_SIZE]
+                    .try_into()
+                    .expect("Unreachable: We checked the length."),
+            ) as usize;
+            if length > MAX_FRAME_LENGTH {
+                return Err(TransportError::FrameTooLarge);
+            }
+            if self.read_buffer.len() < LENGTH_PREFIX_SIZE + length {
+                break;
+            }
+
+            self.read_buffer.advance(LENGTH_PREFIX_SIZE);
+            let ciphertext = self.read_buffer.split_to(length);
+            let plaintext = self.receive_cipher.decrypt(&ciphertext)?;
+            frames.push(BytesMut::from(&plaintext[..]));
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_frame_too_large() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let tcp_stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let keys = TransportKeys {
+                send_key: [1; 32],
+                receive_key: [2; 32],
+            };
+            let mut stream = EncryptedStream::new(tcp_stream, BytesMut::new(), keys);
+
+            // Frames that can't hold a multiplexer message are rejected before they're buffered.
+            let length = (MAX_FRAME_LENGTH + 1) as u32;
+            stream.read_buffer.extend_from_slice(&length.to_be_bytes());
+            assert!(matches!(
+                stream.decrypt_buffered(),
+                Err(TransportError::FrameTooLarge)
+            ));
+
+            let frame = vec![0; HEADER_LENGTH + MAX_MESSAGE_LENGTH as usize + 1];
+            assert!(matches!(
+                stream.write_frame(&frame).await,
+                Err(TransportError::FrameTooLarge)
+            ));
+        });
+    }
+}
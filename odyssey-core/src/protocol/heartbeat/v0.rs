@@ -7,11 +7,11 @@ use tokio::{
     sync::watch,
     time::{sleep, Duration},
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
     core::{OdysseyType, StoreStatuses},
-    network::protocol::{receive, send, MiniProtocol},
+    network::protocol::{receive, send, MiniProtocol, ProtocolError},
     util::Stream,
 };
 
@@ -107,7 +107,7 @@ impl MiniProtocol for Heartbeat {
     fn run_server<S: Stream<Self::Message>>(
         self,
         mut stream: S,
-    ) -> impl Future<Output = ()> + Send {
+    ) -> impl Future<Output = Result<(), ProtocolError>> + Send {
         async move {
             debug!("Heartbeat server started!");
 
@@ -131,21 +131,21 @@ impl MiniProtocol for Heartbeat {
                     heartbeat,
                 };
                 debug!("Sending heartbeat: {req:?}");
-                send(&mut stream, req).await.expect("TODO");
+                send(&mut stream, req).await?;
 
                 // Get response.
-                let client_response: MsgHeartbeatClientResponse =
-                    receive(&mut stream).await.expect("TODO");
+                let client_response: MsgHeartbeatClientResponse = receive(&mut stream).await?;
                 let latency = server_time.elapsed();
                 debug!("Recieved heartbeat response.\nResponse:{client_response:?}\nLatency: {latency:?}");
                 if client_response.heartbeat != heartbeat {
-                    todo!("Heartbeat does not match");
+                    warn!("Heartbeat does not match");
+                    return Err(ProtocolError::ProtocolDeviation);
                 }
 
                 // Send response.
                 let server_response = MsgHeartbeatServerResponse { heartbeat };
                 debug!("Sending response: {server_response:?}");
-                send(&mut stream, server_response).await.expect("TODO");
+                send(&mut stream, server_response).await?;
             }
         }
     }
@@ -153,13 +153,13 @@ impl MiniProtocol for Heartbeat {
     fn run_client<S: Stream<Self::Message>>(
         self,
         mut stream: S,
-    ) -> impl Future<Output = ()> + Send {
+    ) -> impl Future<Output = Result<(), ProtocolError>> + Send {
         async move {
             debug!("Heartbeat client started!");
 
             loop {
                 // Wait for request.
-                let request: MsgHeartbeatRequest = receive(&mut stream).await.expect("TODO");
+                let request: MsgHeartbeatRequest = receive(&mut stream).await?;
                 debug!("Received heartbeat request.\n{request:?}");
 
                 // Send response.
@@ -169,15 +169,15 @@ impl MiniProtocol for Heartbeat {
                     client_time,
                 };
                 debug!("Sending response.\n{client_response:?}");
-                send(&mut stream, client_response).await.expect("TODO");
+                send(&mut stream, client_response).await?;
 
                 // Wait for response.
-                let server_response: MsgHeartbeatServerResponse =
-                    receive(&mut stream).await.expect("TODO");
+                let server_response: MsgHeartbeatServerResponse = receive(&mut stream).await?;
                 let latency = client_time.elapsed();
                 debug!("Received heartbeat response.\n{server_response:?}\nLatency:{latency:?}");
                 if server_response.heartbeat != request.heartbeat {
-                    todo!("Heartbeat does not match");
+                    warn!("Heartbeat does not match");
+                    return Err(ProtocolError::ProtocolDeviation);
                 }
             }
         }
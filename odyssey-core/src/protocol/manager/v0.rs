@@ -17,7 +17,7 @@ use crate::store::UntypedStoreCommand;
 use crate::{
     network::{
         multiplexer::Party,
-        protocol::{receive, send, MiniProtocol},
+        protocol::{receive, send, MiniProtocol, ProtocolError},
     },
     util::{Channel, Hash, Sha256Hash, Stream},
 };
@@ -69,24 +69,26 @@ impl<
 {
     type Message = MsgManager<StoreId>;
 
-    async fn run_server<S: Stream<Self::Message>>(self, stream: S) {
-        if self.server_has_initiative() {
+    async fn run_server<S: Stream<Self::Message>>(self, stream: S) -> Result<(), ProtocolError> {
+        let result = if self.server_has_initiative() {
             self.run_with_initiative(stream).await
         } else {
             self.run_without_initiative(stream).await
-        }
+        };
         debug!("Manager server exiting");
+        result
     }
 
-    async fn run_client<S: Stream<Self::Message>>(self, stream: S) {
-        if self.server_has_initiative() {
+    async fn run_client<S: Stream<Self::Message>>(self, stream: S) -> Result<(), ProtocolError> {
+        let result = if self.server_has_initiative() {
             self.run_without_initiative(stream).await
         } else {
             // Sleep for 5 seconds to not duplicate effort from the server.
             sleep(Duration::new(5, 0)).await;
             self.run_with_initiative(stream).await
-        }
+        };
         debug!("Manager client exiting");
+        result
     }
 }
 
@@ -94,15 +96,19 @@ impl<StoreId: Send + Sync + Copy + AsRef<[u8]> + Ord + Debug, Hash, HeaderId, He
     Manager<StoreId, Hash, HeaderId, Header>
 {
     /// Manager run in mode that sends requests to peer.
-    async fn run_with_initiative<S: Stream<MsgManager<StoreId>>>(mut self, mut stream: S) {
+    async fn run_with_initiative<S: Stream<MsgManager<StoreId>>>(
+        mut self,
+        mut stream: S,
+    ) -> Result<(), ProtocolError> {
         debug!("Mux manager started with initiative!");
 
         // Advertise stores.
         let shared_stores = run_advertise_stores_server::<_, _, Hash, HeaderId, Header>(
             &mut stream,
+            &self.peer_id,
             &mut self.active_stores,
         )
-        .await;
+        .await?;
         handle_shared_stores(self.peer_id, shared_stores);
 
         // Note: This replaces the manager_channel with `None`. This will fail if this manager ends up being called multiple times.
@@ -114,9 +120,12 @@ impl<StoreId: Send + Sync + Copy + AsRef<[u8]> + Ord + Debug, Hash, HeaderId, He
             debug!("Mux manager looping with initiative!");
             tokio::select! {
                 changed_e = self.active_stores.changed() => {
-                    changed_e.expect("TODO");
+                    if changed_e.is_err() {
+                        // Odyssey was dropped, so there's nothing left to manage.
+                        return Ok(());
+                    }
 
-                    let shared_stores = run_advertise_stores_server::<_, _, Hash, HeaderId, Header>(&mut stream, &mut self.active_stores).await;
+                    let shared_stores = run_advertise_stores_server::<_, _, Hash, HeaderId, Header>(&mut stream, &self.peer_id, &mut self.active_stores).await?;
                     debug!("Client sent store ids: {:?}", shared_stores);
                     handle_shared_stores(self.peer_id, shared_stores);
                 }
@@ -129,7 +138,7 @@ impl<StoreId: Send + Sync + Copy + AsRef<[u8]> + Ord + Debug, Hash, HeaderId, He
                         }
                         Some(PeerManagerCommand::RequestStoreSync { store_id, spawn_task }) => {
                             let stream_id = self.next_stream_id();
-                            let _response = self.run_request_new_stream_server(&mut stream, stream_id, store_id, spawn_task).await;
+                            self.run_request_new_stream_server(&mut stream, stream_id, store_id, spawn_task).await?;
                             debug!("Requested to sync store with peer.");
                         }
                     }
@@ -139,24 +148,28 @@ impl<StoreId: Send + Sync + Copy + AsRef<[u8]> + Ord + Debug, Hash, HeaderId, He
     }
 
     /// Manager run in mode that responds to requests from peer.
-    async fn run_without_initiative<S: Stream<MsgManager<StoreId>>>(mut self, mut stream: S) {
+    async fn run_without_initiative<S: Stream<MsgManager<StoreId>>>(
+        mut self,
+        mut stream: S,
+    ) -> Result<(), ProtocolError> {
         debug!("Mux manager started without initiative!");
 
         loop {
             debug!("Mux manager looping without initiative!");
             // Receive requests from initiator.
-            let response: MsgManagerRequest<StoreId> = receive(&mut stream).await.expect("TODO");
+            let response: MsgManagerRequest<StoreId> = receive(&mut stream).await?;
             match response {
                 MsgManagerRequest::AdvertiseStores { nonce, store_ids } => {
                     debug!("Received MsgManagerRequest::AdvertiseStores: {nonce:?}, {store_ids:?}");
                     let shared_stores =
                         run_advertise_stores_client::<_, _, Hash, HeaderId, Header>(
                             &mut stream,
+                            &self.peer_id,
                             nonce,
                             store_ids,
                             &mut self.active_stores,
                         )
-                        .await;
+                        .await?;
                     // TODO: Store and handle peers too?
                     debug!("Server sent store ids: {:?}", shared_stores);
                     handle_shared_stores(self.peer_id, shared_stores);
@@ -169,7 +182,7 @@ impl<StoreId: Send + Sync + Copy + AsRef<[u8]> + Ord + Debug, Hash, HeaderId, He
                         "Received MsgManagerRequest::CreateStoreStream: {stream_id}, {store_id:?}"
                     );
                     self.run_request_new_stream_client(&mut stream, stream_id, store_id)
-                        .await;
+                        .await?;
                 }
             }
         }
@@ -204,7 +217,7 @@ impl<StoreId: Send + Sync + Copy + AsRef<[u8]> + Ord + Debug, Hash, HeaderId, He
         stream: &mut S,
         stream_id: StreamId,
         store_id: StoreId,
-    ) {
+    ) -> Result<(), ProtocolError> {
         let accept = {
             // Check if stream is valid (it can be allocated by peer and is available).
             let is_valid_id = self.is_valid_stream_id(false, &stream_id);
@@ -221,26 +234,16 @@ impl<StoreId: Send + Sync + Copy + AsRef<[u8]> + Ord + Debug, Hash, HeaderId, He
                 };
                 if let Some(store_chan) = store_chan_m {
                     let (response_chan, rx) = oneshot::channel();
-                    store_chan
-                        .send(UntypedStoreCommand::SyncWithPeer {
-                            peer: self.peer_id,
-                            response_chan,
-                        })
-                        .expect("TODO");
-
-                    let spawn_task = rx.await.expect("TODO");
+                    let _ = store_chan.send(UntypedStoreCommand::SyncWithPeer {
+                        peer: self.peer_id,
+                        response_chan,
+                    });
+
+                    // The store rejects the request if it closed in the meantime.
+                    let spawn_task = rx.await.ok().flatten();
                     if let Some(spawn_task) = spawn_task {
                         // Tell multiplexer to create miniprotocol.
-                        let (response_chan, rx) = oneshot::channel();
-                        let cmd = MultiplexerCommand::CreateStream {
-                            stream_id,
-                            spawn_task,
-                            response_chan,
-                        };
-                        self.multiplexer_channel.send(cmd).expect("TODO");
-
-                        // Wait for stream to be created and return success.
-                        let is_running = rx.await.expect("TODO");
+                        let is_running = self.create_stream(stream_id, spawn_task).await?;
                         Ok(is_running)
                     } else {
                         debug!("Store rejected syncing.");
@@ -256,7 +259,7 @@ impl<StoreId: Send + Sync + Copy + AsRef<[u8]> + Ord + Debug, Hash, HeaderId, He
 
         // Send back response to peer.
         let response = MsgManagerCreateStoreResponse { accept };
-        send(stream, response).await.expect("TODO");
+        send(stream, response).await
     }
 
     async fn run_request_new_stream_server<S: Stream<MsgManager<StoreId>>>(
@@ -265,47 +268,59 @@ impl<StoreId: Send + Sync + Copy + AsRef<[u8]> + Ord + Debug, Hash, HeaderId, He
         stream_id: StreamId,
         store_id: StoreId,
         spawn_task: Box<SpawnMultiplexerTask>,
-    ) {
+    ) -> Result<(), ProtocolError> {
         // Send request message.
         let req = MsgManagerRequest::CreateStoreStream {
             stream_id,
             store_id,
         };
-        send(stream, req).await.expect("TODO");
+        send(stream, req).await?;
 
         // Wait for response.
-        let response: MsgManagerCreateStoreResponse = receive(stream).await.expect("TODO");
+        let response: MsgManagerCreateStoreResponse = receive(stream).await?;
 
         match response.accept {
             Err(MsgManagerError::InvalidStreamId) => {
+                // TODO: Restore status to Known.
                 warn!("Invalid stream id");
-                todo!("Restore status to Known");
             }
             Ok(false) => {
+                // TODO: Restore status to Known.
                 info!("Peer denied sync request for store"); // : {}", store_id);
-                todo!("Restore status to Known");
             }
             Ok(true) => {
                 // Tell multiplexer to create miniprotocol.
-                let (response_chan, rx) = oneshot::channel();
-                let cmd = MultiplexerCommand::CreateStream {
-                    stream_id,
-                    spawn_task,
-                    response_chan,
-                };
-                self.multiplexer_channel.send(cmd).expect("TODO");
-
-                // Wait for stream to be created and return success.
-                let is_running = rx.await.expect("TODO");
+                let is_running = self.create_stream(stream_id, spawn_task).await?;
 
                 if !is_running {
+                    // TODO: Send shutdown for this miniprotocol and restore status to Known.
                     error!("Failed to create miniprotocol stream to sync store.");
-                    panic!(
-                        "TODO: Send shutdown for this miniprotocol and restore status to Known."
-                    );
                 }
             }
         }
+
+        Ok(())
+    }
+
+    /// Tell the multiplexer to create a stream that runs `spawn_task`. Returns whether the stream
+    /// was created.
+    async fn create_stream(
+        &self,
+        stream_id: StreamId,
+        spawn_task: Box<SpawnMultiplexerTask>,
+    ) -> Result<bool, ProtocolError> {
+        let (response_chan, rx) = oneshot::channel();
+        let cmd = MultiplexerCommand::CreateStream {
+            stream_id,
+            spawn_task,
+            response_chan,
+        };
+        self.multiplexer_channel
+            .send(cmd)
+            .map_err(|_| ProtocolError::MultiplexerClosed)?;
+
+        // Wait for stream to be created.
+        rx.await.map_err(|_| ProtocolError::MultiplexerClosed)
     }
 }
 
@@ -357,11 +372,15 @@ async fn run_advertise_stores_server<
     Header,
 >(
     stream: &mut S,
+    peer_id: &DeviceId,
     store_ids: &mut watch::Receiver<StoreStatuses<StoreId, Hash, HeaderId, Header>>,
-) -> Vec<(
-    StoreId,
-    UnboundedSender<UntypedStoreCommand<Hash, HeaderId, Header>>,
-)>
+) -> Result<
+    Vec<(
+        StoreId,
+        UnboundedSender<UntypedStoreCommand<Hash, HeaderId, Header>>,
+    )>,
+    ProtocolError,
+>
 where
     StoreId: Copy + AsRef<[u8]>,
 {
@@ -370,6 +389,7 @@ where
     let store_ids: Vec<_> = store_ids
         .borrow_and_update()
         .iter()
+        .filter(|e| e.1.is_shared_with(peer_id))
         .filter_map(|e| e.1.command_channel().map(|c| (e.0, c)))
         .take(MAX_ADVERTISE_STORES)
         .map(|(&s, c)| (s, c.clone()))
@@ -386,12 +406,12 @@ where
         nonce,
         store_ids: hashed_store_ids,
     };
-    send(stream, req).await.expect("TODO");
+    send(stream, req).await?;
 
     // Wait for response.
-    let response: MsgManagerAdvertiseStoresResponse = receive(stream).await.expect("TODO");
+    let response: MsgManagerAdvertiseStoresResponse = receive(stream).await?;
 
-    store_ids
+    let shared_stores = store_ids
         .into_iter()
         .zip(response.have_stores)
         .filter_map(|((store_id, chan), is_shared)| {
@@ -401,7 +421,8 @@ where
                 None
             }
         })
-        .collect()
+        .collect();
+    Ok(shared_stores)
 }
 
 async fn run_advertise_stores_client<
@@ -412,16 +433,21 @@ async fn run_advertise_stores_client<
     Header,
 >(
     stream: &mut S,
+    peer_id: &DeviceId,
     nonce: [u8; 4],
     their_store_ids: Vec<Sha256Hash>,
     our_store_ids: &mut watch::Receiver<StoreStatuses<StoreId, Hash, HeaderId, Header>>,
-) -> Vec<(
-    StoreId,
-    UnboundedSender<UntypedStoreCommand<Hash, HeaderId, Header>>,
-)> {
+) -> Result<
+    Vec<(
+        StoreId,
+        UnboundedSender<UntypedStoreCommand<Hash, HeaderId, Header>>,
+    )>,
+    ProtocolError,
+> {
     let mut our_store_ids: BTreeMap<Sha256Hash, (StoreId, UnboundedSender<_>)> = our_store_ids
         .borrow_and_update()
         .iter()
+        .filter(|e| e.1.is_shared_with(peer_id))
         .filter_map(|e| e.1.command_channel().map(|c| (e.0, c)))
         .map(|(store_id, c)| {
             let h = hash_store_id_with_nonce(nonce, store_id);
@@ -443,9 +469,9 @@ async fn run_advertise_stores_client<
         });
 
     let response = MsgManagerAdvertiseStoresResponse { have_stores };
-    send(stream, response).await.expect("TODO");
+    send(stream, response).await?;
 
-    mutual_store_ids
+    Ok(mutual_store_ids)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -549,3 +575,99 @@ pub(crate) enum PeerManagerCommand<StoreId> {
         spawn_task: Box<SpawnMultiplexerTask>,
     },
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::auth::generate_identity;
+    use crate::store::acl::{Acl, AclChange, Role};
+    use crate::util::UnboundChannel;
+
+    type Statuses = watch::Receiver<StoreStatuses<Sha256Hash, (), (), ()>>;
+
+    /// Running stores with the given ACLs. Stores without an ACL are still downloading.
+    fn running_stores(stores: Vec<(Sha256Hash, Option<Acl>)>) -> Statuses {
+        let statuses = stores
+            .into_iter()
+            .map(|(store_id, acl)| {
+                let status = StoreStatus::Running {
+                    store_handle: tokio::spawn(async {}),
+                    send_command_chan: tokio::sync::mpsc::unbounded_channel().0,
+                    typed_command_chan: Box::new(()),
+                    acl: watch::channel(acl).1,
+                };
+                (store_id, status)
+            })
+            .collect();
+        watch::channel(statuses).1
+    }
+
+    /// An ACL where the given devices can sync the store.
+    fn acl(owner: DeviceId, members: &[DeviceId]) -> Acl {
+        let mut acl = Acl::new(owner);
+        let changes: Vec<_> = members
+            .iter()
+            .map(|&device| AclChange::Grant {
+                device,
+                role: Role::Reader,
+            })
+            .collect();
+        acl.apply(&changes);
+        acl
+    }
+
+    #[test]
+    fn test_advertise_stores_hides_stores_from_non_members() {
+        let owner = generate_identity().device_id();
+        let server = generate_identity().device_id();
+        let client = generate_identity().device_id();
+        let [shared, hidden_by_server, hidden_by_client] = [1, 2, 3].map(|i| Sha256Hash([i; 32]));
+
+        let runtime = Runtime::new().unwrap();
+        let (server_stores, client_stores) = runtime.block_on(async {
+            let mut server_statuses = running_stores(vec![
+                (shared, Some(acl(owner, &[client]))),
+                (hidden_by_server, Some(acl(owner, &[]))),
+                (hidden_by_client, None),
+            ]);
+            let mut client_statuses = running_stores(vec![
+                (shared, Some(acl(owner, &[server]))),
+                (hidden_by_server, None),
+                (hidden_by_client, Some(acl(owner, &[]))),
+            ]);
+            let (mut server_stream, mut client_stream) =
+                UnboundChannel::<MsgManager<Sha256Hash>>::new_pair();
+
+            let run_client = async {
+                let MsgManagerRequest::AdvertiseStores { nonce, store_ids } =
+                    receive(&mut client_stream).await?
+                else {
+                    panic!("Expected the server to advertise its stores");
+                };
+                // Stores the server can't share aren't advertised at all.
+                assert_eq!(store_ids.len(), 2);
+                run_advertise_stores_client(
+                    &mut client_stream,
+                    &server,
+                    nonce,
+                    store_ids,
+                    &mut client_statuses,
+                )
+                .await
+            };
+            let (server_stores, client_stores) = tokio::join!(
+                run_advertise_stores_server(&mut server_stream, &client, &mut server_statuses),
+                run_client,
+            );
+            (server_stores.unwrap(), client_stores.unwrap())
+        });
+
+        let store_ids = |stores: Vec<(Sha256Hash, _)>| -> Vec<_> {
+            stores.into_iter().map(|(store_id, _)| store_id).collect()
+        };
+        assert_eq!(store_ids(server_stores), vec![shared]);
+        assert_eq!(store_ids(client_stores), vec![shared]);
+    }
+}
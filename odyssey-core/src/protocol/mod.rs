@@ -1,12 +1,11 @@
 use serde::{Deserialize, Serialize};
-use tokio::{
-    net::TcpStream,
-    sync::{mpsc::UnboundedReceiver, watch},
-};
+use tokio::sync::{mpsc::UnboundedReceiver, watch};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     auth::DeviceId,
     core::{OdysseyType, StoreStatuses},
+    network::{protocol::ProtocolError, transport::EncryptedStream},
     protocol::manager::v0::PeerManagerCommand,
     store::ecg::ECGHeader,
 };
@@ -20,6 +19,8 @@ pub(crate) struct MiniProtocolArgs<StoreId, Hash, HeaderId, Header> {
     peer_id: DeviceId,
     active_stores: watch::Receiver<StoreStatuses<StoreId, Hash, HeaderId, Header>>,
     manager_channel: UnboundedReceiver<PeerManagerCommand<StoreId>>,
+    /// Cancelled to gracefully close the connection.
+    cancel: CancellationToken,
 }
 
 impl<StoreId, Hash, HeaderId, Header> MiniProtocolArgs<StoreId, Hash, HeaderId, Header> {
@@ -27,17 +28,19 @@ impl<StoreId, Hash, HeaderId, Header> MiniProtocolArgs<StoreId, Hash, HeaderId,
         peer_id: DeviceId,
         active_stores: watch::Receiver<StoreStatuses<StoreId, Hash, HeaderId, Header>>,
         manager_channel: UnboundedReceiver<PeerManagerCommand<StoreId>>,
+        cancel: CancellationToken,
     ) -> Self {
         Self {
             peer_id,
             active_stores,
             manager_channel,
+            cancel,
         }
     }
 }
 
 /// The protocol version.
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub enum Version {
     V0 = 0,
 }
@@ -47,31 +50,47 @@ impl Version {
         *self as u8
     }
 
-    pub async fn run_miniprotocols_server<O: OdysseyType>(
+    /// Parse a version from its byte representation. Returns `None` for versions we don't know about.
+    pub fn from_byte(b: u8) -> Option<Version> {
+        SUPPORTED_VERSIONS
+            .iter()
+            .find(|v| v.as_byte() == b)
+            .copied()
+    }
+
+    /// Pick the highest version that we and the peer both support.
+    pub fn negotiate(peer_versions: &[u8]) -> Option<Version> {
+        peer_versions
+            .iter()
+            .filter_map(|b| Version::from_byte(*b))
+            .max()
+    }
+
+    pub(crate) async fn run_miniprotocols_server<O: OdysseyType>(
         &self,
-        stream: TcpStream,
+        stream: EncryptedStream,
         args: MiniProtocolArgs<
             O::StoreId,
             O::Hash,
             <O::ECGHeader as ECGHeader>::HeaderId,
             O::ECGHeader,
         >,
-    ) {
+    ) -> Result<(), ProtocolError> {
         match self {
             Version::V0 => v0::run_miniprotocols_server::<O>(stream, args).await,
         }
     }
 
-    pub async fn run_miniprotocols_client<O: OdysseyType>(
+    pub(crate) async fn run_miniprotocols_client<O: OdysseyType>(
         &self,
-        stream: TcpStream,
+        stream: EncryptedStream,
         args: MiniProtocolArgs<
             O::StoreId,
             O::Hash,
             <O::ECGHeader as ECGHeader>::HeaderId,
             O::ECGHeader,
         >,
-    ) {
+    ) -> Result<(), ProtocolError> {
         match self {
             Version::V0 => v0::run_miniprotocols_client::<O>(stream, args).await,
         }
@@ -79,3 +98,6 @@ impl Version {
 }
 
 pub(crate) const LATEST_VERSION: Version = Version::V0;
+
+/// All protocol versions we can speak, advertised to peers during the handshake.
+pub(crate) const SUPPORTED_VERSIONS: &[Version] = &[Version::V0];
@@ -56,7 +56,7 @@ use tokio::sync::oneshot;
 use tracing::{debug, warn};
 
 use crate::{
-    network::protocol::{receive, send},
+    network::protocol::{receive, send, ProtocolError},
     protocol::store_peer::v0::{
         HeaderBitmap, MsgStoreECGSyncResponse, MsgStoreSync, MsgStoreSyncRequest, StoreSync,
         MAX_DELIVER_HEADERS, MAX_HAVE_HEADERS,
@@ -82,22 +82,23 @@ impl<
 {
     async fn receive_response_helper<S: Stream<MsgStoreSync<Hash, HeaderId, Header>>>(
         stream: &mut S,
-    ) -> (Vec<HeaderId>, Vec<(Header, RawECGBody)>) {
-        let response = receive(stream).await.expect("TODO");
+    ) -> Result<(Vec<HeaderId>, Vec<(Header, RawECGBody)>), ProtocolError> {
+        let response = receive(stream).await?;
         let (have, operations) = match response {
             MsgStoreECGSyncResponse::Response { have, operations } => (have, operations),
             MsgStoreECGSyncResponse::Wait => {
                 let MsgStoreECGSyncResponse::Response { have, operations } =
-                    receive(stream).await.expect("TODO")
+                    receive(stream).await?
                 else {
-                    todo!("TODO: Prevent this with session types.");
+                    // TODO: Prevent this with session types.
+                    return Err(ProtocolError::ProtocolDeviation);
                 };
                 (have, operations)
             }
         };
         warn!("TODO: Check response sizes.");
 
-        (have, operations)
+        Ok((have, operations))
     }
 
     /// Create a new ECGSyncInitiator and run the first round.
@@ -105,23 +106,23 @@ impl<
     pub(crate) async fn run_new<S: Stream<MsgStoreSync<Hash, HeaderId, Header>>>(
         stream: &mut S,
         ecg_state: &ecg::UntypedState<HeaderId, Header>,
-    ) -> (Self, Vec<(Header, RawECGBody)>) {
+    ) -> Result<(Self, Vec<(Header, RawECGBody)>), ProtocolError> {
         // TODO: Limit on tips (128? 64? 32? MAX_HAVE_HEADERS)
         warn!("TODO: Check request sizes.");
         let req = MsgStoreSyncRequest::ECGInitialSync {
             tips: ecg_state.tips().iter().cloned().collect(),
         };
-        send(stream, req).await.expect("TODO");
+        send(stream, req).await?;
 
         // Receive response.
-        let (have, operations) = Self::receive_response_helper(stream).await;
+        let (have, operations) = Self::receive_response_helper(stream).await?;
 
         let ecg_sync = ECGSyncInitiator {
             have,
             phantom: PhantomData,
         };
 
-        (ecg_sync, operations)
+        Ok((ecg_sync, operations))
     }
 
     /// Run a round of ECG sync, requesting new operations from peer.
@@ -129,7 +130,7 @@ impl<
         &mut self,
         stream: &mut S,
         ecg_state: &ecg::UntypedState<HeaderId, Header>,
-    ) -> Vec<(Header, RawECGBody)> {
+    ) -> Result<Vec<(Header, RawECGBody)>, ProtocolError> {
         // Check which headers they sent us that we know.
         let mut known_bitmap = BitArray::ZERO;
         for (i, header_id) in self.have.iter().enumerate() {
@@ -145,14 +146,14 @@ impl<
             tips: ecg_state.tips().iter().cloned().collect(),
             known: known_bitmap,
         };
-        send(stream, req).await.expect("TODO");
+        send(stream, req).await?;
 
         // Receive response.
-        let (have, operations) = Self::receive_response_helper(stream).await;
+        let (have, operations) = Self::receive_response_helper(stream).await?;
 
         self.have = have;
 
-        operations
+        Ok(operations)
     }
 }
 
@@ -232,7 +233,8 @@ impl<Hash, HeaderId, Header> ECGSyncResponder<Hash, HeaderId, Header> {
         stream: &mut S,
         mut ecg_state: ecg::UntypedState<HeaderId, Header>,
         their_tips: Vec<HeaderId>,
-    ) where
+    ) -> Result<(), ProtocolError>
+    where
         HeaderId: Debug + Ord + Copy,
         Header: Debug + Clone,
     {
@@ -262,7 +264,7 @@ impl<Hash, HeaderId, Header> ECGSyncResponder<Hash, HeaderId, Header> {
                 if is_first_run {
                     is_first_run = false;
                     let msg = MsgStoreECGSyncResponse::Wait;
-                    send(stream, msg).await.expect("TODO");
+                    send(stream, msg).await?;
                 }
 
                 // Subscribe for updates.
@@ -273,10 +275,13 @@ impl<Hash, HeaderId, Header> ECGSyncResponder<Hash, HeaderId, Header> {
                     tips: Some(ecg_state.tips().iter().cloned().collect()),
                     response_chan,
                 };
-                store_peer.send_chan().send(cmd).expect("TODO");
+                store_peer.send_to_store(cmd)?;
 
                 // Wait for ECG updates.
-                ecg_state = recv_chan.await.expect("TODO");
+                ecg_state = recv_chan
+                    .await
+                    .map_err(|_| ProtocolError::StoreClosed)?
+                    .ok_or(ProtocolError::Rejected)?;
                 self.update_our_unknown(&ecg_state);
 
                 // self.run_response_helper(store_peer, stream, &ecg_state, false).await;
@@ -286,8 +291,7 @@ impl<Hash, HeaderId, Header> ECGSyncResponder<Hash, HeaderId, Header> {
                     have: self.sent_haves.clone(),
                     operations,
                 };
-                send(stream, msg).await.expect("TODO");
-                return;
+                return send(stream, msg).await;
             }
         }
     }
@@ -300,7 +304,8 @@ impl<Hash, HeaderId, Header> ECGSyncResponder<Hash, HeaderId, Header> {
         stream: &mut S,
         ecg_state: ecg::UntypedState<HeaderId, Header>,
         their_tips: Vec<HeaderId>,
-    ) where
+    ) -> Result<(), ProtocolError>
+    where
         HeaderId: Debug + Ord + Copy,
         Header: Debug + Clone,
     {
@@ -308,7 +313,7 @@ impl<Hash, HeaderId, Header> ECGSyncResponder<Hash, HeaderId, Header> {
         self.handle_their_tips(&ecg_state, &their_tips);
 
         self.run_response_helper(store_peer, stream, ecg_state, their_tips)
-            .await;
+            .await
     }
 
     pub(crate) async fn run_round<S: Stream<MsgStoreSync<Hash, HeaderId, Header>>>(
@@ -318,7 +323,8 @@ impl<Hash, HeaderId, Header> ECGSyncResponder<Hash, HeaderId, Header> {
         ecg_state: ecg::UntypedState<HeaderId, Header>,
         their_tips: Vec<HeaderId>,
         their_known: HeaderBitmap,
-    ) where
+    ) -> Result<(), ProtocolError>
+    where
         HeaderId: Debug + Copy + Ord,
         Header: Debug + Clone,
     {
@@ -329,7 +335,7 @@ impl<Hash, HeaderId, Header> ECGSyncResponder<Hash, HeaderId, Header> {
         self.handle_their_known(&ecg_state, their_known);
 
         self.run_response_helper(store_peer, stream, ecg_state, their_tips)
-            .await;
+            .await
     }
 
     fn prepare_operations(
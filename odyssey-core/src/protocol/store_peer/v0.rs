@@ -10,12 +10,12 @@ use tracing::{debug, warn};
 
 use crate::{
     auth::DeviceId,
-    network::protocol::{receive, send, MiniProtocol},
+    network::protocol::{receive, send, MiniProtocol, ProtocolError},
     protocol::store_peer::ecg_sync::{ECGSyncInitiator, ECGSyncResponder},
     store::{
         self,
         ecg::{self, RawECGBody},
-        HandlePeerRequest, UntypedStoreCommand,
+        HandlePeerRequest, HandlePeerResponse, UntypedStoreCommand,
     },
     util::Stream,
 };
@@ -230,6 +230,36 @@ impl<Hash, HeaderId, Header> StoreSync<Hash, HeaderId, Header> {
         }
     }
 
+    /// Receive the peer's response to our request, waiting for it if the peer tells us to.
+    /// Returns `None` if the peer rejected the request.
+    async fn receive_response<S, SResp, Resp>(
+        &self,
+        stream: &mut S,
+        unwrap_response: fn(SResp) -> StoreSyncResponse<Resp>,
+    ) -> Result<Option<Resp>, ProtocolError>
+    where
+        S: Stream<MsgStoreSync<Hash, HeaderId, Header>>,
+        MsgStoreSync<Hash, HeaderId, Header>: TryInto<SResp>,
+        SResp: Debug,
+    {
+        let mut is_waiting = false;
+        loop {
+            match unwrap_response(receive(stream).await?) {
+                StoreSyncResponse::Response(response) => return Ok(Some(response)),
+                StoreSyncResponse::Reject => return Ok(None),
+                // Peers only tell us to wait once, before sending their actual response.
+                StoreSyncResponse::Wait if is_waiting => {
+                    debug!("Peer ({}) told us to wait again", self.peer);
+                    return Err(ProtocolError::ProtocolDeviation);
+                }
+                StoreSyncResponse::Wait => {
+                    debug!("Waiting for response from peer ({})", self.peer);
+                    is_waiting = true;
+                }
+            }
+        }
+    }
+
     #[inline(always)]
     async fn run_client_helper<S: Stream<MsgStoreSync<Hash, HeaderId, Header>>, Req, Resp, SResp>(
         &self,
@@ -239,7 +269,8 @@ impl<Hash, HeaderId, Header> StoreSync<Hash, HeaderId, Header> {
             HandlePeerRequest<Req, Resp>,
         ) -> UntypedStoreCommand<Hash, HeaderId, Header>,
         build_response: fn(StoreSyncResponse<Resp>) -> SResp,
-    ) where
+    ) -> Result<(), ProtocolError>
+    where
         Hash: Debug,
         SResp: Into<MsgStoreSync<Hash, HeaderId, Header>> + Debug,
         Req: Debug,
@@ -254,36 +285,36 @@ impl<Hash, HeaderId, Header> StoreSync<Hash, HeaderId, Header> {
             request,
             response_chan,
         });
-        self.send_chan.send(cmd).expect("TODO");
+        self.send_to_store(cmd)?;
 
         // Wait for response.
-        match recv_chan.await.expect("TODO") {
-            Ok(response) => {
+        match recv_chan.await.map_err(|_| ProtocolError::StoreClosed)? {
+            HandlePeerResponse::Response(response) => {
                 // Send response to peer.
                 let response = build_response(StoreSyncResponse::Response(response));
                 debug!("Sending response to peer ({}): {response:?}", self.peer);
-                send(stream, response).await.expect("TODO");
+                send(stream, response).await
+            }
+            HandlePeerResponse::Reject => {
+                debug!("Rejecting request from peer ({})", self.peer);
+                send(stream, build_response(StoreSyncResponse::Reject)).await
             }
-            Err(chan) => {
+            HandlePeerResponse::Wait(chan) => {
                 // If waiting, tell peer.
                 debug!("Telling peer to wait for response ({})", self.peer);
-                send(
-                    stream,
-                    MsgStoreSyncMetadataResponse(StoreSyncResponse::Wait),
-                )
-                .await
-                .expect("TODO");
+                send(stream, build_response(StoreSyncResponse::Wait)).await?;
 
                 // Wait for response and send to peer.
-                let response = if let Some(res) = chan.await.expect("TODO") {
-                    debug!("Sending response to peer ({}): {res:?}", self.peer);
-                    StoreSyncResponse::Response(res)
-                } else {
-                    debug!("Not sending response to peer ({})", self.peer);
-                    StoreSyncResponse::Reject
-                };
-
-                send(stream, build_response(response)).await.expect("TODO");
+                let response =
+                    if let Some(res) = chan.await.map_err(|_| ProtocolError::StoreClosed)? {
+                        debug!("Sending response to peer ({}): {res:?}", self.peer);
+                        StoreSyncResponse::Response(res)
+                    } else {
+                        debug!("Not sending response to peer ({})", self.peer);
+                        StoreSyncResponse::Reject
+                    };
+
+                send(stream, build_response(response)).await
             }
         }
     }
@@ -291,7 +322,7 @@ impl<Hash, HeaderId, Header> StoreSync<Hash, HeaderId, Header> {
     async fn request_ecg_state(
         &self,
         responder: &mut ECGSyncResponder<Hash, HeaderId, Header>,
-    ) -> ecg::UntypedState<HeaderId, Header>
+    ) -> Result<ecg::UntypedState<HeaderId, Header>, ProtocolError>
     where
         HeaderId: Ord + Copy,
     {
@@ -304,25 +335,32 @@ impl<Hash, HeaderId, Header> StoreSync<Hash, HeaderId, Header> {
             tips: None,
             response_chan,
         };
-        self.send_chan().send(cmd).expect("TODO");
+        self.send_to_store(cmd)?;
 
         // Wait for ECG updates.
-        let state = recv_chan.await.expect("TODO");
+        let state = recv_chan
+            .await
+            .map_err(|_| ProtocolError::StoreClosed)?
+            .ok_or(ProtocolError::Rejected)?;
         responder.update_our_unknown(&state);
 
         debug!("Received ECG state");
 
-        state
+        Ok(state)
     }
 
-    pub(crate) fn peer(&self) -> DeviceId {
-        self.peer
+    /// Send a command to the store, failing if the store was closed.
+    pub(crate) fn send_to_store(
+        &self,
+        cmd: UntypedStoreCommand<Hash, HeaderId, Header>,
+    ) -> Result<(), ProtocolError> {
+        self.send_chan
+            .send(cmd)
+            .map_err(|_| ProtocolError::StoreClosed)
     }
 
-    pub(crate) fn send_chan(
-        &self,
-    ) -> &UnboundedSender<UntypedStoreCommand<Hash, HeaderId, Header>> {
-        &self.send_chan
+    pub(crate) fn peer(&self) -> DeviceId {
+        self.peer
     }
 }
 
@@ -346,41 +384,37 @@ impl<
 
     // Has initiative
     fn run_server<S: Stream<Self::Message>>(
-        self,
+        mut self,
         mut stream: S,
-    ) -> impl Future<Output = ()> + Send {
+    ) -> impl Future<Output = Result<(), ProtocolError>> + Send {
         async move {
             let mut ecg_sync: Option<ECGSyncInitiator<Hash, HeaderId, Header>> = None;
 
             // Wait for command from store.
             let mut recv_chan = self
                 .recv_chan
+                .take()
                 .expect("Unreachable. Server must be given a receive channel.");
             while let Some(cmd) = recv_chan.recv().await {
                 match cmd {
                     StoreSyncCommand::MetadataHeaderRequest => {
-                        send(&mut stream, MsgStoreSyncRequest::MetadataHeader)
-                            .await
-                            .expect("TODO");
+                        send(&mut stream, MsgStoreSyncRequest::MetadataHeader).await?;
 
-                        let MsgStoreSyncMetadataResponse(result) =
-                            receive(&mut stream).await.expect("TODO");
+                        let result = self
+                            .receive_response(&mut stream, |MsgStoreSyncMetadataResponse(r)| r)
+                            .await?;
                         match result {
-                            StoreSyncResponse::Response(metadata) => {
+                            Some(metadata) => {
                                 // Send store the metadata and tell store we're ready.
                                 let msg = UntypedStoreCommand::ReceivedMetadata {
                                     peer: self.peer,
-                                    metadata,
+                                    metadata: Box::new(metadata),
                                 };
-                                self.send_chan.send(msg).expect("TODO");
-                            }
-                            StoreSyncResponse::Wait => {
-                                // Wait for response (Must be Cancel or MetadataHeader).
-                                todo!();
+                                self.send_to_store(msg)?;
                             }
-                            StoreSyncResponse::Reject => {
-                                // Send store they canceled and tell store we're ready.
-                                todo!();
+                            None => {
+                                debug!("Peer ({}) rejected our metadata request", self.peer);
+                                return Err(ProtocolError::Rejected);
                             }
                         }
                     }
@@ -392,29 +426,25 @@ impl<
                                 ranges: ranges.clone(),
                             },
                         )
-                        .await
-                        .expect("TODO");
+                        .await?;
 
-                        let MsgStoreSyncMerkleResponse(result) =
-                            receive(&mut stream).await.expect("TODO");
+                        let result = self
+                            .receive_response(&mut stream, |MsgStoreSyncMerkleResponse(r)| r)
+                            .await?;
                         debug!("Received merkle response: {:?}", result);
                         match result {
-                            StoreSyncResponse::Response(nodes) => {
+                            Some(nodes) => {
                                 // Send store the merkle hashes and tell store we're ready.
                                 let msg = UntypedStoreCommand::ReceivedMerkleHashes {
                                     peer: self.peer,
                                     ranges,
                                     nodes,
                                 };
-                                self.send_chan.send(msg).expect("TODO");
+                                self.send_to_store(msg)?;
                             }
-                            StoreSyncResponse::Wait => {
-                                // Wait for response (Must be Cancel or MerkleHashes).
-                                todo!();
-                            }
-                            StoreSyncResponse::Reject => {
-                                // Send store they canceled and tell store we're ready.
-                                todo!();
+                            None => {
+                                debug!("Peer ({}) rejected our merkle request", self.peer);
+                                return Err(ProtocolError::Rejected);
                             }
                         }
                     }
@@ -425,29 +455,23 @@ impl<
                                 ranges: ranges.clone(),
                             },
                         )
-                        .await
-                        .expect("TODO");
-                        let MsgStoreSyncBlockResponse(result) =
-                            receive(&mut stream).await.expect("TODO");
+                        .await?;
+                        let result = self
+                            .receive_response(&mut stream, |MsgStoreSyncBlockResponse(r)| r)
+                            .await?;
                         match result {
-                            StoreSyncResponse::Response(blocks) => {
+                            Some(blocks) => {
                                 // Send store the blocks and tell store we're ready.
                                 let msg = UntypedStoreCommand::ReceivedInitialStateBlocks {
                                     peer: self.peer,
                                     ranges,
                                     blocks,
                                 };
-                                self.send_chan.send(msg).expect("TODO");
-                            }
-                            StoreSyncResponse::Wait =>
-                            // Wait for response (Must be Reject or Repsonse).
-                            {
-                                todo!()
+                                self.send_to_store(msg)?;
                             }
-                            StoreSyncResponse::Reject =>
-                            // Send store they rejected and tell store we're ready.
-                            {
-                                todo!()
+                            None => {
+                                debug!("Peer ({}) rejected our block request", self.peer);
+                                return Err(ProtocolError::Rejected);
                             }
                         }
                     }
@@ -459,13 +483,13 @@ impl<
 
                                 // JP: Eventually switch ecg_state to an Arc<RWLock>?
                                 let (new_ecg_sync, operations) =
-                                    ECGSyncInitiator::run_new(&mut stream, &ecg_state).await;
+                                    ECGSyncInitiator::run_new(&mut stream, &ecg_state).await?;
                                 ecg_sync = Some(new_ecg_sync);
                                 operations
                             }
                             Some(ref mut ecg_sync) => {
                                 // Subsequent rounds of ECG sync.
-                                ecg_sync.run_round(&mut stream, &ecg_state).await
+                                ecg_sync.run_round(&mut stream, &ecg_state).await?
                             }
                         };
 
@@ -476,13 +500,14 @@ impl<
                             peer: self.peer,
                             operations,
                         };
-                        self.send_chan.send(msg).expect("TODO");
+                        self.send_to_store(msg)?;
                         // } else { todo!() }
                     }
                 }
             }
 
             debug!("StoreSyncCommand receiver channel closed");
+            Ok(())
 
             // ??
             // Send our store's status.
@@ -498,14 +523,20 @@ impl<
     fn run_client<S: Stream<Self::Message>>(
         self,
         mut stream: S,
-    ) -> impl Future<Output = ()> + Send {
+    ) -> impl Future<Output = Result<(), ProtocolError>> + Send {
         async move {
             let mut ecg_sync: Option<ECGSyncResponder<Hash, HeaderId, Header>> = None;
 
             // TODO: Check when done.
             loop {
-                // Receive request.
-                let request = receive(&mut stream).await.expect("TODO");
+                // Receive request, stopping if the store is closed.
+                let request = tokio::select! {
+                    _ = self.send_chan.closed() => {
+                        debug!("Store closed, so stopping sync with peer ({})", self.peer);
+                        return Ok(());
+                    }
+                    request = receive(&mut stream) => request?,
+                };
                 match request {
                     MsgStoreSyncRequest::MetadataHeader => {
                         const fn build_command<Hash, HeaderId, Header>(
@@ -519,7 +550,7 @@ impl<
                             MsgStoreSyncMetadataResponse(h)
                         }
 
-                        self.run_client_helper::<_, (), store::v0::MetadataHeader<Hash>, MsgStoreSyncMetadataResponse<Hash>>(&mut stream, (), build_command, build_response::<_, HeaderId, Header>).await;
+                        self.run_client_helper::<_, (), store::v0::MetadataHeader<Hash>, MsgStoreSyncMetadataResponse<Hash>>(&mut stream, (), build_command, build_response::<_, HeaderId, Header>).await?;
                     }
                     MsgStoreSyncRequest::MerkleHashes { ranges } => {
                         const fn build_command<Hash, HeaderId, Header>(
@@ -533,7 +564,7 @@ impl<
                             MsgStoreSyncMerkleResponse(h)
                         }
 
-                        self.run_client_helper::<_, Vec<Range<u64>>, Vec<Hash>, MsgStoreSyncMerkleResponse<Hash>>(&mut stream, ranges, build_command, build_response).await;
+                        self.run_client_helper::<_, Vec<Range<u64>>, Vec<Hash>, MsgStoreSyncMerkleResponse<Hash>>(&mut stream, ranges, build_command, build_response).await?;
                     }
                     MsgStoreSyncRequest::InitialStateBlocks { ranges } => {
                         const fn build_command<Hash, HeaderId, Header>(
@@ -547,37 +578,143 @@ impl<
                             MsgStoreSyncBlockResponse(h)
                         }
 
-                        self.run_client_helper::<_, Vec<Range<u64>>, Vec<Option<Vec<u8>>>, MsgStoreSyncBlockResponse>(&mut stream, ranges, build_command, build_response).await;
+                        self.run_client_helper::<_, Vec<Range<u64>>, Vec<Option<Vec<u8>>>, MsgStoreSyncBlockResponse>(&mut stream, ranges, build_command, build_response).await?;
                     }
                     MsgStoreSyncRequest::ECGInitialSync { tips } => {
                         debug!("Received initial ECG sync request with tips: {tips:?}");
 
                         if ecg_sync.is_some() {
-                            todo!("TODO: Error, ECG sync has already been initialized.");
+                            warn!("Peer ({}) already initialized ECG sync", self.peer);
+                            return Err(ProtocolError::ProtocolDeviation);
                         }
 
                         let mut ecg_sync_ = ECGSyncResponder::new();
 
-                        let ecg_state = self.request_ecg_state(&mut ecg_sync_).await;
+                        let ecg_state = self.request_ecg_state(&mut ecg_sync_).await?;
 
                         ecg_sync_
                             .run_initial(&self, &mut stream, ecg_state, tips)
-                            .await;
+                            .await?;
                         ecg_sync = Some(ecg_sync_);
                     }
                     MsgStoreSyncRequest::ECGSync { tips, known } => {
                         let Some(ref mut ecg_sync) = ecg_sync else {
-                            todo!("TODO: Error, ECG sync hasn't been initialized.");
+                            warn!("Peer ({}) hasn't initialized ECG sync", self.peer);
+                            return Err(ProtocolError::ProtocolDeviation);
                         };
 
-                        let ecg_state = self.request_ecg_state(ecg_sync).await;
+                        let ecg_state = self.request_ecg_state(ecg_sync).await?;
 
                         ecg_sync
                             .run_round(&self, &mut stream, ecg_state, tips, known)
-                            .await;
+                            .await?;
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::auth::generate_identity;
+    use crate::core::test::{
+        active_store_ids, start_odyssey, store_command_channel, Registers, TestOdyssey,
+    };
+    use crate::core::OdysseyType;
+    use crate::storage::memory::MemoryStorage;
+    use crate::util::UnboundChannel;
+
+    type Message = MsgStoreSync<
+        <TestOdyssey as OdysseyType>::Hash,
+        <<TestOdyssey as OdysseyType>::ECGHeader as ecg::ECGHeader>::HeaderId,
+        <TestOdyssey as OdysseyType>::ECGHeader,
+    >;
+
+    #[test]
+    fn test_rejects_peers_that_cant_sync() {
+        let odyssey = start_odyssey();
+        let _store = odyssey.create_store(Registers::new(), MemoryStorage::new());
+        let [store_id] = active_store_ids(&odyssey)[..] else {
+            panic!("Expected one active store");
+        };
+        let store_chan = store_command_channel(&odyssey, store_id);
+
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let stranger = generate_identity().device_id();
+            for (peer, can_sync) in [(odyssey.device_id(), true), (stranger, false)] {
+                let (stream, mut peer_stream) = UnboundChannel::<Message>::new_pair();
+                let sync = StoreSync::new_client(peer, store_chan.clone());
+                tokio::spawn(sync.run_client(stream));
+
+                send(&mut peer_stream, MsgStoreSyncRequest::MetadataHeader)
+                    .await
+                    .unwrap();
+                let MsgStoreSyncMetadataResponse(response) =
+                    receive(&mut peer_stream).await.unwrap();
+                match response {
+                    StoreSyncResponse::Response(_) => assert!(can_sync),
+                    StoreSyncResponse::Reject => assert!(!can_sync),
+                    StoreSyncResponse::Wait => panic!("The store has its metadata"),
+                }
+            }
+        });
+
+        odyssey.shutdown();
+    }
+
+    #[test]
+    fn test_waits_for_responses() {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            // Peers may tell us to wait once, but not twice.
+            for wait_count in [1, 2] {
+                let peer = generate_identity().device_id();
+                let (stream, mut peer_stream) = UnboundChannel::<Message>::new_pair();
+                let (send_command, recv_command) = tokio::sync::mpsc::unbounded_channel();
+                let (send_store, mut recv_store) = tokio::sync::mpsc::unbounded_channel();
+                let sync = StoreSync::new_server(peer, recv_command, send_store);
+                let sync = tokio::spawn(sync.run_server(stream));
+
+                send_command
+                    .send(StoreSyncCommand::MerkleRequest(vec![0..1]))
+                    .unwrap();
+                let MsgStoreSyncRequest::MerkleHashes { .. } =
+                    receive(&mut peer_stream).await.unwrap()
+                else {
+                    panic!("Expected a merkle request");
+                };
+                for _ in 0..wait_count {
+                    send(
+                        &mut peer_stream,
+                        MsgStoreSyncMerkleResponse(StoreSyncResponse::Wait),
+                    )
+                    .await
+                    .unwrap();
+                }
+
+                if wait_count == 1 {
+                    send(
+                        &mut peer_stream,
+                        MsgStoreSyncMerkleResponse(StoreSyncResponse::Response(vec![])),
+                    )
+                    .await
+                    .unwrap();
+                    let Some(UntypedStoreCommand::ReceivedMerkleHashes { nodes, .. }) =
+                        recv_store.recv().await
+                    else {
+                        panic!("Expected the merkle hashes to be sent to the store");
+                    };
+                    assert!(nodes.is_empty());
+                } else {
+                    let result = sync.await.unwrap();
+                    assert!(matches!(result, Err(ProtocolError::ProtocolDeviation)));
+                }
+            }
+        });
+    }
+}
@@ -8,7 +8,6 @@ use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
     runtime::Runtime,
     sync::{
         mpsc::{self, Receiver, Sender, UnboundedSender},
@@ -18,6 +17,7 @@ use tokio::{
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::{PollSendError, PollSender};
 
+use crate::network::transport::EncryptedStream;
 use crate::protocol::heartbeat::v0::Heartbeat;
 use crate::protocol::manager::v0::Manager;
 use crate::protocol::MiniProtocolArgs;
@@ -28,7 +28,7 @@ use crate::{
     core::{OdysseyType, StoreStatuses},
     network::{
         multiplexer::{run_miniprotocol_async, Multiplexer, MultiplexerCommand, Party, StreamId},
-        protocol::MiniProtocol,
+        protocol::{MiniProtocol, ProtocolError},
     },
     store::ecg::ECGHeader,
 };
@@ -52,7 +52,7 @@ impl<
         stream_id: StreamId,
         sender: Sender<(StreamId, Bytes)>,
         receiver: Receiver<BytesMut>,
-    ) {
+    ) -> Result<(), ProtocolError> {
         match self {
             MiniProtocols::Heartbeat(p) => {
                 run_miniprotocol_async::<_, O>(p, is_client, stream_id, sender, receiver).await
@@ -110,31 +110,31 @@ fn initial_miniprotocols<StoreId, Hash, HeaderId, Header>(
 }
 
 pub(crate) async fn run_miniprotocols_server<O: OdysseyType>(
-    stream: TcpStream,
+    stream: EncryptedStream,
     args: MiniProtocolArgs<
         O::StoreId,
         O::Hash,
         <O::ECGHeader as ECGHeader>::HeaderId,
         O::ECGHeader,
     >,
-) {
+) -> Result<(), ProtocolError> {
     run_miniprotocols::<O>(stream, args, Party::Server).await
 }
 
 pub(crate) async fn run_miniprotocols_client<O: OdysseyType>(
-    stream: TcpStream,
+    stream: EncryptedStream,
     args: MiniProtocolArgs<
         O::StoreId,
         O::Hash,
         <O::ECGHeader as ECGHeader>::HeaderId,
         O::ECGHeader,
     >,
-) {
+) -> Result<(), ProtocolError> {
     run_miniprotocols::<O>(stream, args, Party::Client).await
 }
 
 async fn run_miniprotocols<O: OdysseyType>(
-    stream: TcpStream,
+    stream: EncryptedStream,
     args: MiniProtocolArgs<
         O::StoreId,
         O::Hash,
@@ -142,14 +142,19 @@ async fn run_miniprotocols<O: OdysseyType>(
         O::ECGHeader,
     >,
     party: Party,
-) {
+) -> Result<(), ProtocolError> {
     // Start multiplexer.
     let (mux_cmd_send, mux_cmd_recv) = mpsc::unbounded_channel();
     let multiplexer = Multiplexer::new(party, mux_cmd_recv);
 
+    let cancel = args.cancel.clone();
     multiplexer
-        .run_with_miniprotocols::<O>(stream, initial_miniprotocols(party, args, mux_cmd_send))
-        .await;
+        .run_with_miniprotocols::<O>(
+            stream,
+            initial_miniprotocols(party, args, mux_cmd_send),
+            cancel,
+        )
+        .await
 }
 
 // # Protocols run between peers.
@@ -218,4 +223,3 @@ pub type StoreMetadataBodyRequest = (); // TODO: Eventually request certain chun
 pub type StoreMetadataBodyResponse<StoreId> = store::v0::MetadataBody<StoreId>; // TODO: Eventually request certain chunks.
 
 pub type ProtocolResult<T> = Result<T, ProtocolError>;
-pub type ProtocolError = String; // TODO: Eventually more informative error type.
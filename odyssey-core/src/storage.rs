@@ -1,6 +1,104 @@
 // JP: Move inside store?
 
+use std::fmt::{self, Display};
+use std::io;
+
+pub mod filesystem;
 pub mod memory;
 
+/// Errors that can occur when reading from or writing to storage.
+#[derive(Debug)]
+pub enum StorageError {
+    /// An underlying IO operation failed.
+    Io(io::Error),
+    /// Persisted data is malformed and could not be read back.
+    Corrupted(String),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(err) => write!(f, "IO error: {err}"),
+            StorageError::Corrupted(msg) => write!(f, "Corrupted storage: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+/// A persisted ECG node as its serialized header and raw body.
+pub type StoredECGNode = (Vec<u8>, Vec<u8>);
+
 // Trait abstracting in memory or filesystem storage.
-pub trait Storage {}
+/// Storage for the persisted parts of stores. Values are opaque bytes keyed by the store's id, so
+/// (de)serialization is left to the caller and the trait stays object safe (`Box<dyn Storage + Send>`).
+pub trait Storage {
+    /// Ids of all stores that have persisted metadata.
+    fn list_stores(&self) -> Result<Vec<Vec<u8>>, StorageError>;
+
+    /// Write the store's serialized `MetadataHeader`, replacing any previous value.
+    fn write_metadata(&mut self, store_id: &[u8], metadata: &[u8]) -> Result<(), StorageError>;
+
+    /// Read the store's serialized `MetadataHeader`, if it has been written.
+    fn read_metadata(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Write the store's serialized `MerkleTree`, replacing any previous value.
+    fn write_merkle_tree(
+        &mut self,
+        store_id: &[u8],
+        merkle_tree: &[u8],
+    ) -> Result<(), StorageError>;
+
+    /// Read the store's serialized `MerkleTree`, if it has been written.
+    fn read_merkle_tree(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Write the initial state block at the given index.
+    fn write_initial_state_block(
+        &mut self,
+        store_id: &[u8],
+        index: u64,
+        block: &[u8],
+    ) -> Result<(), StorageError>;
+
+    /// Read the initial state block at the given index, if it has been written.
+    fn read_initial_state_block(
+        &self,
+        store_id: &[u8],
+        index: u64,
+    ) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Write the store's latest serialized state checkpoint, replacing any previous one.
+    fn write_checkpoint(&mut self, store_id: &[u8], checkpoint: &[u8]) -> Result<(), StorageError>;
+
+    /// Read the store's latest serialized state checkpoint, if one has been written.
+    fn read_checkpoint(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Write the store's serialized encryption keys, replacing any previous value. Keys are
+    /// written unencrypted, so anyone who can read them can decrypt the store. Implementations
+    /// should keep them readable by this device only.
+    fn write_keys(&mut self, store_id: &[u8], keys: &[u8]) -> Result<(), StorageError>;
+
+    /// Read the store's serialized encryption keys, if they have been written.
+    fn read_keys(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Append a serialized ECG header and its raw body.
+    /// Nodes must be appended after their parents.
+    fn append_ecg_node(
+        &mut self,
+        store_id: &[u8],
+        header: &[u8],
+        body: &[u8],
+    ) -> Result<(), StorageError>;
+
+    /// Read all ECG nodes (serialized header and raw body) in the order they were appended.
+    fn read_ecg_nodes(&self, store_id: &[u8]) -> Result<Vec<StoredECGNode>, StorageError>;
+
+    /// Flush any buffered writes to the underlying medium.
+    fn flush(&mut self) -> Result<(), StorageError>;
+}
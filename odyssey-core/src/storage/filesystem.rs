@@ -0,0 +1,390 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::storage::{Storage, StorageError, StoredECGNode};
+
+const METADATA_FILE: &str = "metadata";
+const MERKLE_TREE_FILE: &str = "merkle_tree";
+const BLOCKS_DIR: &str = "blocks";
+const CHECKPOINT_FILE: &str = "checkpoint";
+const KEYS_FILE: &str = "keys";
+const ECG_LOG_FILE: &str = "ecg";
+
+/// Storage that persists stores to the filesystem under a root directory.
+///
+/// Each store gets its own directory (named by the hex encoding of its id) containing:
+/// - `metadata`: The serialized `MetadataHeader`.
+/// - `merkle_tree`: The serialized `MerkleTree`.
+/// - `blocks/<index>`: The initial state blocks.
+/// - `checkpoint`: The latest serialized state checkpoint.
+/// - `keys`: The store's serialized encryption keys, only readable by the owner of the file.
+/// - `ecg`: An append only log of length prefixed ECG headers and bodies.
+pub struct FileSystemStorage {
+    root: PathBuf,
+    /// Open handles to the ECG logs we've appended to.
+    ecg_logs: BTreeMap<Vec<u8>, File>,
+}
+
+impl FileSystemStorage {
+    /// Create storage rooted at the given directory. The directory is created if it does not exist.
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<FileSystemStorage, StorageError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(FileSystemStorage {
+            root,
+            ecg_logs: BTreeMap::new(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn store_dir(&self, store_id: &[u8]) -> PathBuf {
+        self.root.join(hex::encode(store_id))
+    }
+
+    fn block_path(&self, store_id: &[u8], index: u64) -> PathBuf {
+        self.store_dir(store_id)
+            .join(BLOCKS_DIR)
+            .join(index.to_string())
+    }
+
+    fn ecg_log(&mut self, store_id: &[u8]) -> Result<&mut File, StorageError> {
+        if !self.ecg_logs.contains_key(store_id) {
+            let dir = self.store_dir(store_id);
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(ECG_LOG_FILE);
+
+            // Drop any truncated entry left by a crash so that new entries stay readable.
+            if let Some(log) = read_file_optional(&path)? {
+                let (_, valid_len) = decode_log(&log);
+                if valid_len < log.len() {
+                    warn!("Truncating partially written ECG log entry at offset {valid_len}");
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(valid_len as u64)?;
+                }
+            }
+
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.ecg_logs.insert(store_id.to_vec(), file);
+        }
+        Ok(self
+            .ecg_logs
+            .get_mut(store_id)
+            .expect("Unreachable: We just inserted the log."))
+    }
+}
+
+impl Clone for FileSystemStorage {
+    // Clones share the root directory, but not open file handles.
+    fn clone(&self) -> Self {
+        FileSystemStorage {
+            root: self.root.clone(),
+            ecg_logs: BTreeMap::new(),
+        }
+    }
+}
+
+/// Atomically replace the file at `path` by writing to a temporary file and renaming it.
+fn write_file_atomic(path: &Path, contents: &[u8]) -> Result<(), StorageError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    write_file_atomic_with(path, contents, &options)
+}
+
+/// Like `write_file_atomic`, but only the owner of the file can read it.
+fn write_secret_file_atomic(path: &Path, contents: &[u8]) -> Result<(), StorageError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    write_file_atomic_with(path, contents, &options)
+}
+
+fn write_file_atomic_with(
+    path: &Path,
+    contents: &[u8],
+    options: &OpenOptions,
+) -> Result<(), StorageError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Read the file at `path`, returning `None` if it does not exist.
+fn read_file_optional(path: &Path) -> Result<Option<Vec<u8>>, StorageError> {
+    match fs::read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn encode_length(len: usize) -> Result<[u8; 4], StorageError> {
+    let len: u32 = len
+        .try_into()
+        .map_err(|_| StorageError::Corrupted(format!("Record is too large: {len} bytes")))?;
+    Ok(len.to_be_bytes())
+}
+
+/// Parse one length prefixed field from the log, returning `None` if the log is truncated.
+fn decode_field<'a>(log: &'a [u8], offset: &mut usize) -> Option<&'a [u8]> {
+    let len_bytes = log.get(*offset..*offset + 4)?;
+    let len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+    let field = log.get(*offset + 4..*offset + 4 + len)?;
+    *offset += 4 + len;
+    Some(field)
+}
+
+/// Parse the entries of an ECG log. Also returns the length of the log's valid prefix, which is
+/// shorter than the log if the last entry was only partially written.
+fn decode_log(log: &[u8]) -> (Vec<StoredECGNode>, usize) {
+    let mut nodes = vec![];
+    let mut offset = 0;
+    while offset < log.len() {
+        let mut next = offset;
+        let Some(header) = decode_field(log, &mut next) else {
+            break;
+        };
+        let Some(body) = decode_field(log, &mut next) else {
+            break;
+        };
+        nodes.push((header.to_vec(), body.to_vec()));
+        offset = next;
+    }
+    (nodes, offset)
+}
+
+impl Storage for FileSystemStorage {
+    fn list_stores(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut store_ids = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(|n| n.to_owned()) else {
+                continue;
+            };
+            let Ok(store_id) = hex::decode(&name) else {
+                continue;
+            };
+            if entry.path().join(METADATA_FILE).is_file() {
+                store_ids.push(store_id);
+            }
+        }
+        store_ids.sort();
+        Ok(store_ids)
+    }
+
+    fn write_metadata(&mut self, store_id: &[u8], metadata: &[u8]) -> Result<(), StorageError> {
+        write_file_atomic(&self.store_dir(store_id).join(METADATA_FILE), metadata)
+    }
+
+    fn read_metadata(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        read_file_optional(&self.store_dir(store_id).join(METADATA_FILE))
+    }
+
+    fn write_merkle_tree(
+        &mut self,
+        store_id: &[u8],
+        merkle_tree: &[u8],
+    ) -> Result<(), StorageError> {
+        write_file_atomic(
+            &self.store_dir(store_id).join(MERKLE_TREE_FILE),
+            merkle_tree,
+        )
+    }
+
+    fn read_merkle_tree(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        read_file_optional(&self.store_dir(store_id).join(MERKLE_TREE_FILE))
+    }
+
+    fn write_initial_state_block(
+        &mut self,
+        store_id: &[u8],
+        index: u64,
+        block: &[u8],
+    ) -> Result<(), StorageError> {
+        write_file_atomic(&self.block_path(store_id, index), block)
+    }
+
+    fn read_initial_state_block(
+        &self,
+        store_id: &[u8],
+        index: u64,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        read_file_optional(&self.block_path(store_id, index))
+    }
+
+    fn write_checkpoint(&mut self, store_id: &[u8], checkpoint: &[u8]) -> Result<(), StorageError> {
+        write_file_atomic(&self.store_dir(store_id).join(CHECKPOINT_FILE), checkpoint)
+    }
+
+    fn read_checkpoint(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        read_file_optional(&self.store_dir(store_id).join(CHECKPOINT_FILE))
+    }
+
+    fn write_keys(&mut self, store_id: &[u8], keys: &[u8]) -> Result<(), StorageError> {
+        write_secret_file_atomic(&self.store_dir(store_id).join(KEYS_FILE), keys)
+    }
+
+    fn read_keys(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        read_file_optional(&self.store_dir(store_id).join(KEYS_FILE))
+    }
+
+    fn append_ecg_node(
+        &mut self,
+        store_id: &[u8],
+        header: &[u8],
+        body: &[u8],
+    ) -> Result<(), StorageError> {
+        // Write the whole record at once so that a crash can only leave a truncated tail.
+        let mut record = Vec::with_capacity(8 + header.len() + body.len());
+        record.extend_from_slice(&encode_length(header.len())?);
+        record.extend_from_slice(header);
+        record.extend_from_slice(&encode_length(body.len())?);
+        record.extend_from_slice(body);
+
+        self.ecg_log(store_id)?.write_all(&record)?;
+        Ok(())
+    }
+
+    fn read_ecg_nodes(&self, store_id: &[u8]) -> Result<Vec<StoredECGNode>, StorageError> {
+        let Some(log) = read_file_optional(&self.store_dir(store_id).join(ECG_LOG_FILE))? else {
+            return Ok(vec![]);
+        };
+
+        let (nodes, valid_len) = decode_log(&log);
+        if valid_len < log.len() {
+            warn!("Ignoring truncated ECG log entry at offset {valid_len}");
+        }
+        Ok(nodes)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        for log in self.ecg_logs.values_mut() {
+            log.flush()?;
+            log.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::generate_nonce;
+
+    fn temp_storage() -> FileSystemStorage {
+        let nonce = generate_nonce();
+        let root = std::env::temp_dir().join(format!("odyssey-test-{}", hex::encode(&nonce[..8])));
+        FileSystemStorage::new(root).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut storage = temp_storage();
+        let store_id = [1, 2, 3];
+
+        assert_eq!(storage.read_metadata(&store_id).unwrap(), None);
+        assert!(storage.list_stores().unwrap().is_empty());
+
+        storage.write_metadata(&store_id, b"metadata").unwrap();
+        storage.write_merkle_tree(&store_id, b"merkle").unwrap();
+        storage
+            .write_initial_state_block(&store_id, 3, b"block")
+            .unwrap();
+        storage.append_ecg_node(&store_id, b"h1", b"b1").unwrap();
+        storage.append_ecg_node(&store_id, b"h2", b"").unwrap();
+        storage.write_checkpoint(&store_id, b"old").unwrap();
+        storage.write_checkpoint(&store_id, b"checkpoint").unwrap();
+        storage.write_keys(&store_id, b"keys").unwrap();
+        storage.flush().unwrap();
+
+        // Read back with a fresh handle.
+        let storage = storage.clone();
+        assert_eq!(storage.list_stores().unwrap(), vec![store_id.to_vec()]);
+        assert_eq!(
+            storage.read_metadata(&store_id).unwrap(),
+            Some(b"metadata".to_vec())
+        );
+        assert_eq!(
+            storage.read_merkle_tree(&store_id).unwrap(),
+            Some(b"merkle".to_vec())
+        );
+        assert_eq!(
+            storage.read_initial_state_block(&store_id, 3).unwrap(),
+            Some(b"block".to_vec())
+        );
+        assert_eq!(
+            storage.read_initial_state_block(&store_id, 0).unwrap(),
+            None
+        );
+        assert_eq!(
+            storage.read_checkpoint(&store_id).unwrap(),
+            Some(b"checkpoint".to_vec())
+        );
+        assert_eq!(
+            storage.read_keys(&store_id).unwrap(),
+            Some(b"keys".to_vec())
+        );
+        assert_eq!(
+            storage.read_ecg_nodes(&store_id).unwrap(),
+            vec![
+                (b"h1".to_vec(), b"b1".to_vec()),
+                (b"h2".to_vec(), b"".to_vec())
+            ]
+        );
+
+        fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_ecg_log() {
+        let mut storage = temp_storage();
+        let store_id = [4];
+        storage.write_metadata(&store_id, b"metadata").unwrap();
+        storage.append_ecg_node(&store_id, b"h1", b"b1").unwrap();
+        storage.flush().unwrap();
+
+        // Simulate a crash in the middle of appending.
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(storage.store_dir(&store_id).join(ECG_LOG_FILE))
+            .unwrap();
+        log.write_all(&[0, 0, 0, 10, 1, 2]).unwrap();
+
+        assert_eq!(
+            storage.read_ecg_nodes(&store_id).unwrap(),
+            vec![(b"h1".to_vec(), b"b1".to_vec())]
+        );
+
+        // Appending with a new handle drops the partial entry.
+        let mut storage = storage.clone();
+        storage.append_ecg_node(&store_id, b"h2", b"b2").unwrap();
+        assert_eq!(
+            storage.read_ecg_nodes(&store_id).unwrap(),
+            vec![
+                (b"h1".to_vec(), b"b1".to_vec()),
+                (b"h2".to_vec(), b"b2".to_vec())
+            ]
+        );
+
+        fs::remove_dir_all(storage.root()).unwrap();
+    }
+}
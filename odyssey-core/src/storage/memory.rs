@@ -1,11 +1,133 @@
-use crate::storage::Storage;
+use std::collections::BTreeMap;
 
-pub struct MemoryStorage {}
+use crate::storage::{Storage, StorageError, StoredECGNode};
+
+/// Storage that keeps everything in memory. Nothing survives once it is dropped.
+#[derive(Default)]
+pub struct MemoryStorage {
+    stores: BTreeMap<Vec<u8>, MemoryStore>,
+}
+
+#[derive(Default)]
+struct MemoryStore {
+    metadata: Option<Vec<u8>>,
+    merkle_tree: Option<Vec<u8>>,
+    initial_state_blocks: BTreeMap<u64, Vec<u8>>,
+    checkpoint: Option<Vec<u8>>,
+    keys: Option<Vec<u8>>,
+    ecg_nodes: Vec<StoredECGNode>,
+}
 
 impl MemoryStorage {
     pub fn new() -> MemoryStorage {
-        MemoryStorage {}
+        MemoryStorage {
+            stores: BTreeMap::new(),
+        }
+    }
+
+    fn store_mut(&mut self, store_id: &[u8]) -> &mut MemoryStore {
+        self.stores.entry(store_id.to_vec()).or_default()
     }
 }
 
-impl Storage for MemoryStorage {}
+impl Storage for MemoryStorage {
+    fn list_stores(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        let store_ids = self
+            .stores
+            .iter()
+            .filter(|(_, s)| s.metadata.is_some())
+            .map(|(store_id, _)| store_id.clone())
+            .collect();
+        Ok(store_ids)
+    }
+
+    fn write_metadata(&mut self, store_id: &[u8], metadata: &[u8]) -> Result<(), StorageError> {
+        self.store_mut(store_id).metadata = Some(metadata.to_vec());
+        Ok(())
+    }
+
+    fn read_metadata(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.stores.get(store_id).and_then(|s| s.metadata.clone()))
+    }
+
+    fn write_merkle_tree(
+        &mut self,
+        store_id: &[u8],
+        merkle_tree: &[u8],
+    ) -> Result<(), StorageError> {
+        self.store_mut(store_id).merkle_tree = Some(merkle_tree.to_vec());
+        Ok(())
+    }
+
+    fn read_merkle_tree(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self
+            .stores
+            .get(store_id)
+            .and_then(|s| s.merkle_tree.clone()))
+    }
+
+    fn write_initial_state_block(
+        &mut self,
+        store_id: &[u8],
+        index: u64,
+        block: &[u8],
+    ) -> Result<(), StorageError> {
+        self.store_mut(store_id)
+            .initial_state_blocks
+            .insert(index, block.to_vec());
+        Ok(())
+    }
+
+    fn read_initial_state_block(
+        &self,
+        store_id: &[u8],
+        index: u64,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self
+            .stores
+            .get(store_id)
+            .and_then(|s| s.initial_state_blocks.get(&index).cloned()))
+    }
+
+    fn write_checkpoint(&mut self, store_id: &[u8], checkpoint: &[u8]) -> Result<(), StorageError> {
+        self.store_mut(store_id).checkpoint = Some(checkpoint.to_vec());
+        Ok(())
+    }
+
+    fn read_checkpoint(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.stores.get(store_id).and_then(|s| s.checkpoint.clone()))
+    }
+
+    fn write_keys(&mut self, store_id: &[u8], keys: &[u8]) -> Result<(), StorageError> {
+        self.store_mut(store_id).keys = Some(keys.to_vec());
+        Ok(())
+    }
+
+    fn read_keys(&self, store_id: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.stores.get(store_id).and_then(|s| s.keys.clone()))
+    }
+
+    fn append_ecg_node(
+        &mut self,
+        store_id: &[u8],
+        header: &[u8],
+        body: &[u8],
+    ) -> Result<(), StorageError> {
+        self.store_mut(store_id)
+            .ecg_nodes
+            .push((header.to_vec(), body.to_vec()));
+        Ok(())
+    }
+
+    fn read_ecg_nodes(&self, store_id: &[u8]) -> Result<Vec<StoredECGNode>, StorageError> {
+        Ok(self
+            .stores
+            .get(store_id)
+            .map(|s| s.ecg_nodes.clone())
+            .unwrap_or_default())
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! Access control lists that decide which devices may read, write, and administer a store.
+//!
+//! A store's ACL starts with its owner (from the signed `MetadataHeader`) as the only admin. ECG
+//! headers can carry `AclChange`s, which are applied in a deterministic topological order, so
+//! every peer with the same ECG nodes agrees on the ACL.
+//!
+//! Members are devices, keyed by `DeviceId`. Users don't have their own keys yet, so ACL entries
+//! for users (covering all of a user's devices) are out of scope for now.
+
+use odyssey_crdt::CRDT;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{
+    auth::DeviceId,
+    store::ecg::{self, ECGHeader, ValidationError},
+};
+
+/// What a member of a store is allowed to do. Each role includes the permissions of the roles
+/// before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Role {
+    /// Can download the store and sync its updates, but isn't given the store's key so it can't
+    /// read them.
+    Relay,
+    /// Can also read the store, so it's given the store's key.
+    Reader,
+    /// Can also write operations to the store.
+    Writer,
+    /// Can also change the store's ACL.
+    Admin,
+}
+
+/// A change to a store's ACL, made by an admin.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AclChange {
+    /// Give the device the role, replacing any role it had.
+    Grant { device: DeviceId, role: Role },
+    /// Remove the device from the store.
+    Revoke { device: DeviceId },
+}
+
+/// The members of a store and their roles.
+// TODO: Key entries by user once users have their own keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Acl {
+    owner: DeviceId,
+    members: BTreeMap<DeviceId, Role>,
+}
+
+impl Acl {
+    /// The initial ACL of a store, where the owner is the only member.
+    pub fn new(owner: DeviceId) -> Acl {
+        Acl {
+            owner,
+            members: BTreeMap::from([(owner, Role::Admin)]),
+        }
+    }
+
+    /// The device's role, if it's a member.
+    pub fn role(&self, device: &DeviceId) -> Option<Role> {
+        self.members.get(device).copied()
+    }
+
+    pub fn can_sync(&self, device: &DeviceId) -> bool {
+        self.role(device).is_some()
+    }
+
+    pub fn can_read(&self, device: &DeviceId) -> bool {
+        self.role(device) >= Some(Role::Reader)
+    }
+
+    pub fn can_write(&self, device: &DeviceId) -> bool {
+        self.role(device) >= Some(Role::Writer)
+    }
+
+    pub fn is_admin(&self, device: &DeviceId) -> bool {
+        self.role(device) == Some(Role::Admin)
+    }
+
+    /// All members and their roles.
+    pub fn members(&self) -> &BTreeMap<DeviceId, Role> {
+        &self.members
+    }
+
+    /// Apply the changes in order. The owner is always an admin, so changes to the owner are
+    /// ignored.
+    pub(crate) fn apply(&mut self, changes: &[AclChange]) {
+        for change in changes {
+            match change {
+                AclChange::Grant { device, .. } | AclChange::Revoke { device }
+                    if *device == self.owner => {}
+                AclChange::Grant { device, role } => {
+                    self.members.insert(*device, *role);
+                }
+                AclChange::Revoke { device } => {
+                    self.members.remove(device);
+                }
+            }
+        }
+    }
+
+    /// The ACL after the given heads (and all of their ancestors) are applied. Unknown heads are
+    /// ignored.
+    pub(crate) fn at<'a, Header: ECGHeader, T: CRDT>(
+        owner: DeviceId,
+        ecg_state: &ecg::State<Header, T>,
+        heads: impl IntoIterator<Item = &'a Header::HeaderId>,
+    ) -> Acl
+    where
+        Header::HeaderId: 'a,
+    {
+        let heads: Vec<_> = heads.into_iter().collect();
+        let is_ancestor_of = |a: &Header::HeaderId, d: &Header::HeaderId| {
+            ecg_state.is_ancestor_of(a, d) == Some(true)
+        };
+
+        // ACL changes are rare, so a quadratic topological sort of just the nodes with changes is
+        // cheaper than ordering the whole ECG. Ties are broken by header id.
+        let mut remaining: Vec<_> = ecg_state
+            .acl_nodes()
+            .iter()
+            .filter(|n| heads.iter().any(|h| is_ancestor_of(n, h)))
+            .copied()
+            .collect();
+        remaining.sort();
+
+        let mut acl = Acl::new(owner);
+        while !remaining.is_empty() {
+            let next = remaining
+                .iter()
+                .position(|n| !remaining.iter().any(|m| m != n && is_ancestor_of(m, n)))
+                .expect("Unreachable: The ECG is acyclic.");
+            let header_id = remaining.remove(next);
+            let header = ecg_state
+                .get_header(&header_id)
+                .expect("Unreachable: ACL nodes are in the ECG.");
+            acl.apply(header.acl_changes());
+        }
+        acl
+    }
+
+    /// Check that the header's author may write it with this ACL, which must be the ACL at the
+    /// header's parents. Headers that change the ACL or share or rotate the store's key must be
+    /// written by an admin.
+    ///
+    /// Headers are only checked against their own parents, so a revoked admin can still make
+    /// changes that are concurrent with their revocation, and those changes are applied.
+    // TODO: Reject changes that are concurrent with revoking their author.
+    pub(crate) fn authorize<Header: ECGHeader>(
+        &self,
+        header: &Header,
+    ) -> Result<(), ValidationError> {
+        let author = header.get_author();
+        let is_authorized = if header.acl_changes().is_empty()
+            && header.wrapped_keys().is_empty()
+            && header.rotated_key().is_none()
+        {
+            self.can_write(&author)
+        } else {
+            self.is_admin(&author)
+        };
+        if is_authorized {
+            Ok(())
+        } else {
+            Err(ValidationError::Unauthorized)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use odyssey_crdt::register::LWW;
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::auth::{generate_identity, Identity};
+    use crate::store::ecg::{
+        v0::{Body, Header, HeaderId, OperationId},
+        ECGBody,
+    };
+    use crate::store::encryption::HeaderKeys;
+    use crate::time::CausalTime;
+    use crate::util::Sha256Hash;
+
+    type Id = HeaderId<Sha256Hash>;
+    type Op = OperationId<Id>;
+    type State = ecg::State<Header<Sha256Hash>, LWW<u64, bool>>;
+
+    fn header(
+        author: &Identity,
+        parents: &[Id],
+        acl_changes: Vec<AclChange>,
+    ) -> Header<Sha256Hash> {
+        let body: Body<Sha256Hash, CausalTime<Op>> =
+            <Body<_, _> as ECGBody<Op, _>>::new_body(vec![]);
+        let parents = parents.iter().copied().collect::<BTreeSet<_>>();
+        let keys = HeaderKeys {
+            epoch: None,
+            wrapped_keys: vec![],
+            rotated_key: None,
+        };
+        <Body<_, _> as ECGBody<Op, _>>::new_header(&body, &[], parents, author, acl_changes, keys)
+    }
+
+    fn insert(ecg_state: &mut State, header: Header<Sha256Hash>) -> Id {
+        let header_id = header.get_header_id();
+        assert!(ecg_state.insert_header(header, vec![]));
+        header_id
+    }
+
+    #[test]
+    fn test_acl_at() {
+        let owner = generate_identity();
+        let a = generate_identity();
+        let b = generate_identity();
+        let c = generate_identity();
+        let mut ecg_state = State::new();
+
+        let n1 = insert(
+            &mut ecg_state,
+            header(
+                &owner,
+                &[],
+                vec![
+                    AclChange::Grant {
+                        device: a.device_id(),
+                        role: Role::Writer,
+                    },
+                    AclChange::Grant {
+                        device: b.device_id(),
+                        role: Role::Admin,
+                    },
+                    AclChange::Grant {
+                        device: c.device_id(),
+                        role: Role::Relay,
+                    },
+                ],
+            ),
+        );
+        let n2 = insert(
+            &mut ecg_state,
+            header(
+                &b,
+                &[n1],
+                vec![
+                    AclChange::Revoke {
+                        device: a.device_id(),
+                    },
+                    AclChange::Revoke {
+                        device: owner.device_id(),
+                    },
+                ],
+            ),
+        );
+        let n3 = insert(&mut ecg_state, header(&a, &[n1], vec![]));
+
+        let initial = Acl::at(owner.device_id(), &ecg_state, &[]);
+        assert_eq!(initial, Acl::new(owner.device_id()));
+        assert!(!initial.can_read(&a.device_id()));
+
+        let at_n1 = Acl::at(owner.device_id(), &ecg_state, &[n1]);
+        assert_eq!(at_n1.role(&a.device_id()), Some(Role::Writer));
+        assert!(at_n1.is_admin(&b.device_id()));
+        assert!(at_n1.can_sync(&c.device_id()));
+        assert!(!at_n1.can_read(&c.device_id()));
+
+        // The owner can't be revoked.
+        let latest = Acl::at(owner.device_id(), &ecg_state, ecg_state.tips());
+        assert_eq!(ecg_state.tips(), &BTreeSet::from([n2, n3]));
+        assert_eq!(latest.role(&a.device_id()), None);
+        assert!(latest.is_admin(&owner.device_id()));
+        assert_eq!(latest.members().len(), 3);
+
+        // Writers can only write while they're members, and only admins can change the ACL.
+        assert_eq!(at_n1.authorize(&header(&a, &[n1], vec![])), Ok(()));
+        assert_eq!(
+            latest.authorize(&header(&a, &[n2, n3], vec![])),
+            Err(ValidationError::Unauthorized)
+        );
+        let grant = AclChange::Grant {
+            device: a.device_id(),
+            role: Role::Admin,
+        };
+        assert_eq!(
+            at_n1.authorize(&header(&a, &[n1], vec![grant.clone()])),
+            Err(ValidationError::Unauthorized)
+        );
+        assert_eq!(at_n1.authorize(&header(&b, &[n1], vec![grant])), Ok(()));
+    }
+}
@@ -4,17 +4,25 @@ use daggy::petgraph::visit::{
 use daggy::stable_dag::StableDag;
 use daggy::Walker;
 use odyssey_crdt::CRDT;
+use serde::{Deserialize, Serialize};
 use std::cmp::{self, Reverse};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use tracing::{debug, error};
 
+use crate::auth::{DeviceId, Identity};
+use crate::store::acl::AclChange;
+use crate::store::encryption::{HeaderKeys, WrappedKey};
+
+mod reachability;
 pub mod v0;
 
+use reachability::ReachabilityIndex;
+
 /// Trait that ECG headers (nodes?) must implement.
 pub trait ECGHeader {
-    type HeaderId: Ord + Copy + Debug;
+    type HeaderId: Ord + Copy + Debug + Serialize + for<'d> Deserialize<'d>;
 
     // /// Type identifying operations that implements CausalOrder so that it can be used as CRDT::Time.
     // type OperationId;
@@ -29,7 +37,34 @@ pub trait ECGHeader {
     /// Computes the identifier of the header.
     fn get_header_id(&self) -> Self::HeaderId;
 
-    fn validate_header(&self, header_id: Self::HeaderId) -> bool;
+    /// The device that wrote this node.
+    fn get_author(&self) -> DeviceId;
+
+    /// The number of operations in the corresponding body.
+    fn operations_count(&self) -> u8;
+
+    /// Changes to the store's ACL made by this node.
+    fn acl_changes(&self) -> &[AclChange];
+
+    /// The epoch of the store key that the body is encrypted with: the key rotation node that
+    /// introduced the key, or `None` for the store's initial key.
+    fn epoch(&self) -> Option<Self::HeaderId>;
+
+    /// Store keys, wrapped for devices that this node shares them with. If this node rotates the
+    /// store's key, they're the new key. Otherwise, they're the key of this node's epoch.
+    fn wrapped_keys(&self) -> &[WrappedKey];
+
+    /// If this node rotates the store's key, the key of this node's epoch encrypted with the new
+    /// key. The node's children are encrypted with the new key.
+    fn rotated_key(&self) -> Option<&[u8]>;
+
+    /// Check that this header's author signed it. Header ids are computed from the header, so
+    /// they always match it.
+    fn validate_header(&self) -> Result<(), ValidationError>;
+
+    /// Check that the encrypted body matches the operations hash of this header. This doesn't need
+    /// the store's key, so peers that only relay the store can check it too.
+    fn validate_encrypted_body(&self, encrypted_body: &[u8]) -> Result<(), ValidationError>;
 
     // // TODO: Can we return the following instead? impl Iterator<(T::Time, Item = T::Time)>
     // fn zip_operations_with_time<T>(&self, body: Self::Body) -> Vec<(T::Time, T::Op)>
@@ -42,6 +77,57 @@ pub trait ECGHeader {
     // fn get_operation_times<T>(&self, body: &Self::Body) -> Vec<T::Time> where T: CRDT;
 }
 
+/// The maximum number of operations in an ECG body, since operation positions are a `u8`.
+pub const MAX_BODY_OPERATIONS: usize = u8::MAX as usize;
+
+/// The maximum size of an ECG node, its serialized header plus its encrypted body. This bounds the
+/// size of the messages that deliver nodes to peers.
+pub const MAX_NODE_SIZE: usize = 1 << 20;
+
+/// Reasons an ECG node is rejected. Every variant means the node is malformed, so peers that send
+/// them are misbehaving.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The header's signature doesn't match its author.
+    InvalidSignature,
+    /// Not all of the header's parents are known.
+    UnknownParents,
+    /// The body could not be decrypted with the store's key.
+    UndecryptableBody,
+    /// The body could not be deserialized.
+    MalformedBody,
+    /// The hash of the body doesn't match the header's operations hash.
+    InvalidOperationsHash,
+    /// The number of operations in the body doesn't match the header's operations count.
+    InvalidOperationsCount,
+    /// The body isn't encrypted with the latest key epoch of the header's parents.
+    InvalidEpoch,
+    /// The header's author isn't allowed to write to the store, or to change its ACL.
+    Unauthorized,
+    /// The node is larger than `MAX_NODE_SIZE`.
+    NodeTooLarge,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::InvalidSignature => write!(f, "Invalid signature"),
+            ValidationError::UnknownParents => write!(f, "Unknown parents"),
+            ValidationError::UndecryptableBody => write!(f, "Body could not be decrypted"),
+            ValidationError::MalformedBody => write!(f, "Malformed body"),
+            ValidationError::InvalidOperationsHash => {
+                write!(f, "Operations hash does not match the body")
+            }
+            ValidationError::InvalidOperationsCount => {
+                write!(f, "Operations count does not match the body")
+            }
+            ValidationError::InvalidEpoch => write!(f, "Body is not encrypted with the latest key"),
+            ValidationError::Unauthorized => write!(f, "Author is not authorized"),
+            ValidationError::NodeTooLarge => write!(f, "Node is too large"),
+        }
+    }
+}
+
 pub trait ECGBody<Op, SerializedOp> {
     /// Header type associated with this body.
     type Header: ECGHeader;
@@ -59,8 +145,21 @@ pub trait ECGBody<Op, SerializedOp> {
     /// The number of operations in this body.
     fn operations_count(&self) -> u8;
 
+    /// Check that this body matches the operations count of its header. The operations hash is
+    /// checked against the encrypted body by `ECGHeader::validate_encrypted_body`.
+    fn validate_body(&self, header: &Self::Header) -> Result<(), ValidationError>;
+
     // fn new_header(&self, parents: BTreeSet<<Self::Header as ECGHeader>::HeaderId>) -> Self::Header
-    fn new_header(&self, parents: BTreeSet<<Self::Header as ECGHeader>::HeaderId>) -> Self::Header;
+    /// Create a header for this body, signed by `author`. `encrypted_body` is this body once it's
+    /// encrypted with the key of `keys.epoch`.
+    fn new_header(
+        &self,
+        encrypted_body: &[u8],
+        parents: BTreeSet<<Self::Header as ECGHeader>::HeaderId>,
+        author: &Identity,
+        acl_changes: Vec<AclChange>,
+        keys: HeaderKeys<<Self::Header as ECGHeader>::HeaderId>,
+    ) -> Self::Header;
     // fn new_header<HeaderId>(&self, parents: BTreeSet<HeaderId>) -> Self::Header
     // where
     //     // Self::Header: ECGHeader;
@@ -77,7 +176,7 @@ pub trait ECGBody<Op, SerializedOp> {
     // fn get_operation_times(&self, header: &Self::Header) -> Vec<T::Time>;
 }
 
-// Serialized ECG body
+// Serialized and encrypted ECG body
 pub(crate) type RawECGBody = Vec<u8>;
 
 #[derive(Clone, Debug)]
@@ -88,7 +187,7 @@ pub(crate) struct NodeInfo<Header> {
     depth: u64,
     /// The header this node is storing.
     header: Header,
-    /// Raw serialized and potentially encrypted operations.
+    /// Raw serialized and encrypted operations.
     operations: RawECGBody,
 }
 
@@ -115,6 +214,16 @@ pub struct UntypedState<HeaderId, Header> {
     /// Tips of the ECG (hashes of their headers).
     /// Invariant: All of these headers are in `node_info_map`.
     tips: BTreeSet<HeaderId>,
+
+    /// Index used to answer causal order queries between nodes.
+    /// Invariant: Contains exactly the headers in `node_info_map`.
+    reachability: ReachabilityIndex<HeaderId>,
+
+    /// Nodes that change the store's ACL, in the order they were inserted.
+    acl_nodes: Vec<HeaderId>,
+
+    /// Nodes that share or rotate the store's key, in the order they were inserted.
+    key_nodes: Vec<HeaderId>,
 }
 
 impl<HeaderId, Header> UntypedState<HeaderId, Header> {
@@ -226,6 +335,20 @@ impl<HeaderId, Header> UntypedState<HeaderId, Header> {
     }
 }
 
+/// Metadata about an ECG node.
+#[derive(Clone, Debug)]
+pub struct HeaderMetadata<Header: ECGHeader> {
+    pub header: Header,
+    /// The device that wrote the node.
+    pub author: DeviceId,
+    /// The (minimum) depth of the node in the ECG.
+    pub depth: u64,
+    /// Nodes that directly depend on this node.
+    pub children: Vec<Header::HeaderId>,
+    /// The number of operations in the node's body.
+    pub operation_count: u8,
+}
+
 #[derive(Debug)]
 pub struct State<Header: ECGHeader, T> {
     pub(crate) state: UntypedState<Header::HeaderId, Header>,
@@ -250,6 +373,9 @@ impl<Header: ECGHeader, T: CRDT> State<Header, T> {
             root_nodes: BTreeSet::new(),
             node_info_map: BTreeMap::new(),
             tips: BTreeSet::new(),
+            reachability: ReachabilityIndex::new(),
+            acl_nodes: vec![],
+            key_nodes: vec![],
         };
         State {
             state,
@@ -305,15 +431,11 @@ impl<Header: ECGHeader, T: CRDT> State<Header, T> {
         self.state.get_header_depth(n)
     }
 
+    /// Insert a node whose parents are all known. The header isn't validated here, so nodes from
+    /// peers have to be validated when they're received.
     pub fn insert_header(&mut self, header: Header, operations: RawECGBody) -> bool {
         let header_id = header.get_header_id();
 
-        // Validate header.
-        if !header.validate_header(header_id) {
-            debug!("Invalid header: {header_id:?}");
-            return false;
-        }
-
         // Check that the header is not already in the dependency_graph.
         if self.state.node_info_map.contains_key(&header_id) {
             debug!("Already have header: {header_id:?}");
@@ -388,41 +510,146 @@ impl<Header: ECGHeader, T: CRDT> State<Header, T> {
             return false;
         }
 
+        let header = &self.state.node_info_map[&header_id].header;
+        self.state
+            .reachability
+            .insert(header_id, header.get_parent_ids());
+        if !header.acl_changes().is_empty() {
+            self.state.acl_nodes.push(header_id);
+        }
+        if !header.wrapped_keys().is_empty() || header.rotated_key().is_some() {
+            self.state.key_nodes.push(header_id);
+        }
+
         true
     }
 
-    /// Perform a BFS to check if `ancestor` is an ancestor of `descendent`. Returns `None` if
-    /// either header id is not in the graph.
-    fn is_ancestor_of(
-        &self,
-        ancestor: &Header::HeaderId,
-        descendent: &Header::HeaderId,
-    ) -> Option<bool> {
-        let anid = self.state.node_info_map.get(ancestor)?.graph_index;
-        let dnid = self.state.node_info_map.get(descendent)?.graph_index;
+    /// All headers in a deterministic topological order, so that parents come before their
+    /// children.
+    pub fn log(&self) -> impl Iterator<Item = &Header> {
+        self.topological_order()
+            .into_iter()
+            .map(|header_id| &self.state.node_info_map[&header_id].header)
+    }
 
-        let mut queue = VecDeque::from([dnid]);
-        let mut visited = BTreeSet::from([dnid]);
+    /// All ancestors of the given header, or `None` if the header is unknown.
+    pub fn ancestors(&self, h: &Header::HeaderId) -> Option<BTreeSet<Header::HeaderId>> {
+        if !self.contains(h) {
+            return None;
+        }
+        let mut ancestors = self.ancestors_of([h]);
+        ancestors.remove(h);
+        Some(ancestors)
+    }
 
-        while let Some(nid) = queue.pop_front() {
-            if nid == anid {
-                return Some(true);
+    /// All descendants of the given header, or `None` if the header is unknown.
+    pub fn descendants(&self, h: &Header::HeaderId) -> Option<BTreeSet<Header::HeaderId>> {
+        let mut queue = VecDeque::from([*h]);
+        let mut descendants = BTreeSet::new();
+        while let Some(header_id) = queue.pop_front() {
+            for (_, child_id) in self.get_children_with_depth(&header_id)? {
+                if descendants.insert(child_id) {
+                    queue.push_back(child_id);
+                }
             }
+        }
+        Some(descendants)
+    }
 
-            for (_, pid) in self
-                .state
-                .dependency_graph
-                .parents(nid)
-                .iter(&self.state.dependency_graph)
-            {
-                if !visited.contains(&pid) {
-                    visited.insert(pid);
-                    queue.push_back(pid);
+    /// Headers that `to_heads` depend on but `from_heads` don't, in topological order. These are
+    /// the changes made between the two versions. Unknown heads are ignored.
+    pub fn diff(
+        &self,
+        from_heads: &BTreeSet<Header::HeaderId>,
+        to_heads: &BTreeSet<Header::HeaderId>,
+    ) -> Vec<Header::HeaderId> {
+        let from = self.ancestors_of(from_heads);
+        let to = self.ancestors_of(to_heads);
+        self.topological_order()
+            .into_iter()
+            .filter(|h| to.contains(h) && !from.contains(h))
+            .collect()
+    }
+
+    /// The given headers and all of their ancestors. Header ids that aren't in the graph are
+    /// ignored.
+    pub(crate) fn ancestors_of<'a>(
+        &self,
+        header_ids: impl IntoIterator<Item = &'a Header::HeaderId>,
+    ) -> BTreeSet<Header::HeaderId>
+    where
+        Header::HeaderId: 'a,
+    {
+        let mut queue: VecDeque<_> = header_ids
+            .into_iter()
+            .filter(|h| self.contains(h))
+            .copied()
+            .collect();
+        let mut ancestors: BTreeSet<_> = queue.iter().copied().collect();
+        while let Some(header_id) = queue.pop_front() {
+            for parent_id in self.get_parents(&header_id).unwrap_or_default() {
+                if ancestors.insert(parent_id) {
+                    queue.push_back(parent_id);
+                }
+            }
+        }
+        ancestors
+    }
+
+    /// All header ids in a deterministic topological order. Parents come before their children,
+    /// and ties are broken by header id.
+    pub(crate) fn topological_order(&self) -> Vec<Header::HeaderId> {
+        let graph = &self.state.dependency_graph;
+        let mut remaining_parents: BTreeMap<Header::HeaderId, usize> = self
+            .state
+            .node_info_map
+            .iter()
+            .map(|(header_id, info)| {
+                (
+                    *header_id,
+                    graph.parents(info.graph_index).iter(graph).count(),
+                )
+            })
+            .collect();
+        let mut ready = self.state.root_nodes.clone();
+        let mut order = Vec::with_capacity(remaining_parents.len());
+
+        while let Some(header_id) = ready.pop_first() {
+            order.push(header_id);
+            let graph_index = self.state.node_info_map[&header_id].graph_index;
+            for (_, child_idx) in graph.children(graph_index).iter(graph) {
+                let child_id = graph[child_idx];
+                let count = remaining_parents
+                    .get_mut(&child_id)
+                    .expect("Unreachable: Every node in the graph has node info.");
+                *count -= 1;
+                if *count == 0 {
+                    ready.insert(child_id);
                 }
             }
         }
 
-        Some(false)
+        order
+    }
+
+    /// Check if `ancestor` is `descendent` or one of its ancestors. Returns `None` if either
+    /// header id is not in the graph.
+    pub(crate) fn is_ancestor_of(
+        &self,
+        ancestor: &Header::HeaderId,
+        descendent: &Header::HeaderId,
+    ) -> Option<bool> {
+        self.state.reachability.is_ancestor_of(ancestor, descendent)
+    }
+
+    /// Nodes that change the store's ACL.
+    pub(crate) fn acl_nodes(&self) -> &[Header::HeaderId] {
+        &self.state.acl_nodes
+    }
+
+    /// Nodes that share or rotate the store's key.
+    pub(crate) fn key_nodes(&self) -> &[Header::HeaderId] {
+        &self.state.key_nodes
     }
 
     pub fn state(&self) -> &UntypedState<Header::HeaderId, Header> {
@@ -479,3 +706,47 @@ pub(crate) fn print_dag<Header: ECGHeader, T>(s: &State<Header, T>) {
     let d = Dot::with_config(&g, &[Config::EdgeNoLabel]);
     println!("{:?}", d);
 }
+
+#[cfg(test)]
+mod test {
+    use std::marker::PhantomData;
+
+    use odyssey_crdt::register::LWW;
+
+    use super::*;
+    use crate::store::ecg::v0::TestHeader;
+
+    #[test]
+    fn test_topological_order() {
+        let mut st = State::<TestHeader<LWW<(), ()>>, LWW<(), ()>>::new();
+        for (header_id, parent_ids) in [
+            (5, vec![]),
+            (1, vec![]),
+            (3, vec![5, 1]),
+            (2, vec![5]),
+            (4, vec![3, 2]),
+        ] {
+            let header = TestHeader {
+                header_id,
+                parent_ids,
+                phantom: PhantomData,
+            };
+            assert!(st.insert_header(header, vec![]));
+        }
+
+        assert_eq!(st.topological_order(), vec![1, 5, 2, 3, 4]);
+        assert_eq!(
+            st.log().map(|h| h.header_id).collect::<Vec<_>>(),
+            vec![1, 5, 2, 3, 4]
+        );
+        assert_eq!(st.ancestors(&3), Some(BTreeSet::from([1, 5])));
+        assert_eq!(st.ancestors(&6), None);
+        assert_eq!(st.descendants(&5), Some(BTreeSet::from([2, 3, 4])));
+        assert_eq!(st.descendants(&4), Some(BTreeSet::new()));
+        assert_eq!(
+            st.diff(&BTreeSet::from([2]), &BTreeSet::from([4])),
+            vec![1, 3, 4]
+        );
+        assert_eq!(st.diff(&BTreeSet::from([4]), &BTreeSet::from([2])), vec![]);
+    }
+}
@@ -0,0 +1,159 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+/// Index that answers whether one ECG node is an ancestor of another without walking the DAG.
+///
+/// Nodes are partitioned into chains. A node extends the chain of its first parent that is
+/// currently at the end of its chain, otherwise it starts a new chain. Every node is labelled with
+/// its chain and position in that chain, and refers to a vector clock that counts, for every other
+/// chain, how many of that chain's nodes are its ancestors. Only nodes that start a chain or merge
+/// multiple parents need a new vector clock, so linear histories don't allocate any.
+#[derive(Clone, Debug)]
+pub(crate) struct ReachabilityIndex<HeaderId> {
+    labels: BTreeMap<HeaderId, Label>,
+
+    /// Number of nodes in each chain.
+    chain_lengths: Vec<usize>,
+
+    /// Vector clocks indexed by chain. Missing entries are zero.
+    /// Invariant: The first clock is empty and is shared by root nodes.
+    clocks: Vec<Vec<usize>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Label {
+    chain: usize,
+    position: usize,
+    clock: usize,
+}
+
+impl<HeaderId: Ord + Copy> ReachabilityIndex<HeaderId> {
+    pub(crate) fn new() -> Self {
+        ReachabilityIndex {
+            labels: BTreeMap::new(),
+            chain_lengths: vec![],
+            clocks: vec![vec![]],
+        }
+    }
+
+    /// Index a new node. All of its parents must already be indexed.
+    pub(crate) fn insert(&mut self, header_id: HeaderId, parent_ids: &[HeaderId]) {
+        let parents: Vec<Label> = parent_ids
+            .iter()
+            .map(|parent_id| {
+                *self
+                    .labels
+                    .get(parent_id)
+                    .expect("Unreachable: Parents are indexed before their children.")
+            })
+            .collect();
+
+        let tail = parents
+            .iter()
+            .find(|l| l.position + 1 == self.chain_lengths[l.chain]);
+        let (chain, position) = match tail {
+            Some(l) => (l.chain, l.position + 1),
+            None => {
+                self.chain_lengths.push(0);
+                (self.chain_lengths.len() - 1, 0)
+            }
+        };
+        self.chain_lengths[chain] += 1;
+
+        let clock = match (tail, parents.as_slice()) {
+            // Extending the only parent's chain adds no knowledge of other chains.
+            (Some(_), [parent]) => parent.clock,
+            (_, []) => 0,
+            _ => {
+                let mut clock = vec![0; self.chain_lengths.len()];
+                for parent in &parents {
+                    for (c, count) in self.clocks[parent.clock].iter().enumerate() {
+                        clock[c] = cmp::max(clock[c], *count);
+                    }
+                    clock[parent.chain] = cmp::max(clock[parent.chain], parent.position + 1);
+                }
+                self.clocks.push(clock);
+                self.clocks.len() - 1
+            }
+        };
+
+        self.labels.insert(
+            header_id,
+            Label {
+                chain,
+                position,
+                clock,
+            },
+        );
+    }
+
+    /// Check if `ancestor` is `descendent` or one of its ancestors. Returns `None` if either
+    /// header id is not indexed.
+    pub(crate) fn is_ancestor_of(
+        &self,
+        ancestor: &HeaderId,
+        descendent: &HeaderId,
+    ) -> Option<bool> {
+        let a = self.labels.get(ancestor)?;
+        let d = self.labels.get(descendent)?;
+
+        if a.chain == d.chain {
+            return Some(a.position <= d.position);
+        }
+
+        let count = self.clocks[d.clock].get(a.chain).copied().unwrap_or(0);
+        Some(a.position < count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn test_reachability_matches_graph() {
+        // Pseudo-random DAG where each node picks up to three earlier parents.
+        let mut parents: Vec<Vec<u32>> = vec![];
+        let mut seed: u64 = 17;
+        let mut next = || {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize
+        };
+        for n in 0..200u32 {
+            let mut ps = BTreeSet::new();
+            if n > 0 && next() % 10 != 0 {
+                for _ in 0..1 + next() % 3 {
+                    let lo = (n as usize).saturating_sub(8);
+                    ps.insert((lo + next() % (n as usize - lo)) as u32);
+                }
+            }
+            parents.push(ps.into_iter().collect());
+        }
+
+        let mut index = ReachabilityIndex::new();
+        let mut ancestors: Vec<BTreeSet<u32>> = vec![];
+        for (n, ps) in parents.iter().enumerate() {
+            index.insert(n as u32, ps);
+            let mut a = BTreeSet::from([n as u32]);
+            for p in ps {
+                a.extend(&ancestors[*p as usize]);
+            }
+            ancestors.push(a);
+        }
+
+        for d in 0..200u32 {
+            for a in 0..200u32 {
+                assert_eq!(
+                    index.is_ancestor_of(&a, &d),
+                    Some(ancestors[d as usize].contains(&a)),
+                    "{a} -> {d}"
+                );
+            }
+        }
+        assert_eq!(index.is_ancestor_of(&0, &200), None);
+    }
+}
@@ -1,7 +1,7 @@
 use odyssey_crdt::{time::CausalState, CRDT};
 use rand::Rng;
 use serde::{
-    de::{MapAccess, Visitor},
+    de::{MapAccess, SeqAccess, Visitor},
     ser::{SerializeStruct, Serializer},
     Deserialize, Serialize,
 };
@@ -13,9 +13,14 @@ use std::{
 use typeable::Typeable;
 
 use crate::{
-    store::ecg::{self, ECGBody, ECGHeader},
+    auth::{DeviceId, Identity},
+    store::{
+        acl::AclChange,
+        ecg::{self, ECGBody, ECGHeader, ValidationError},
+        encryption::{HeaderKeys, WrappedKey},
+    },
     time::{CausalTime, ConcretizeTime},
-    util,
+    util::{self, encoding},
 };
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Typeable, Deserialize, Serialize)]
@@ -38,22 +43,69 @@ pub struct Header<Hash> {
     /// The maximum number of operations is 255.
     operations_count: u8,
 
-    /// The hash of (batched) operations in the corresponding body.
-    /// TODO: Eventually this should be of the encrypted body..
+    /// The hash of the corresponding encrypted body of (batched) operations.
     operations_hash: Hash,
-    // // TODO: DeviceId and UserId of device signing? Maybe whole auth chain?
-    // phantom: PhantomData<T>,
+
+    /// The epoch of the key that the body is encrypted with.
+    epoch: Option<HeaderId<Hash>>,
+
+    /// The device that wrote this node.
+    // TODO: UserId of device signing? Maybe whole auth chain?
+    author: DeviceId,
+
+    /// Changes to the store's ACL. Only admins may make them.
+    acl_changes: Vec<AclChange>,
+
+    /// The store's key, wrapped for the devices it's shared with. Only admins may share it.
+    wrapped_keys: Vec<WrappedKey>,
+
+    /// If this node rotates the store's key, the previous key encrypted with the new one. Only
+    /// admins may rotate the key.
+    rotated_key: Option<Vec<u8>>,
+
+    /// The author's signature of the header id.
+    signature: ed25519_dalek::Signature,
+}
+
+/// The fields of a `Header` that its id is computed from (everything but the signature).
+#[derive(Serialize)]
+struct UnsignedHeader<'a, Hash> {
+    nonce: u8,
+    parent_ids: &'a [HeaderId<Hash>],
+    operations_count: u8,
+    operations_hash: &'a Hash,
+    epoch: &'a Option<HeaderId<Hash>>,
+    author: &'a DeviceId,
+    acl_changes: &'a [AclChange],
+    wrapped_keys: &'a [WrappedKey],
+    rotated_key: &'a Option<Vec<u8>>,
+}
+
+impl<Hash> Header<Hash> {
+    fn unsigned(&self) -> UnsignedHeader<'_, Hash> {
+        UnsignedHeader {
+            nonce: self.nonce,
+            parent_ids: &self.parent_ids,
+            operations_count: self.operations_count,
+            operations_hash: &self.operations_hash,
+            epoch: &self.epoch,
+            author: &self.author,
+            acl_changes: &self.acl_changes,
+            wrapped_keys: &self.wrapped_keys,
+            rotated_key: &self.rotated_key,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Body<Hash, SerializedOp> {
     /// The operations in this ECG body.
-    /// Invariant: <= 256 operations
+    /// Invariant: <= 255 operations
     operations: Vec<SerializedOp>, // <CausalTime<T::Time>>>,
     phantom: PhantomData<fn(Hash)>,
 }
 
-// TODO: Define CBOR or rkyv properly
+// Fields are serialized in order so that bodies have a canonical encoding.
 impl<Hash, SerializedOp> Serialize for Body<Hash, SerializedOp>
 where
     SerializedOp: Serialize,
@@ -94,6 +146,20 @@ where
                 formatter.write_str("struct Body")
             }
 
+            // Canonical encodings serialize structs as sequences.
+            fn visit_seq<A>(self, mut seq: A) -> Result<Body<Hash, SerializedOp>, A::Error>
+            where
+                A: SeqAccess<'d>,
+            {
+                let operations = seq
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                Ok(Body {
+                    operations,
+                    phantom: PhantomData,
+                })
+            }
+
             fn visit_map<M>(self, mut m: M) -> Result<Body<Hash, SerializedOp>, M::Error>
             where
                 M: MapAccess<'d>,
@@ -129,7 +195,7 @@ impl<
     > ECGHeader for Header<Hash>
 where
     // <T as CRDT>::Op: Serialize,
-    Hash: Serialize, // TODO
+    Hash: Serialize + for<'d> Deserialize<'d>, // TODO
 {
     type HeaderId = HeaderId<Hash>;
     // type Body = Body<Hash, T>;
@@ -139,12 +205,46 @@ where
     }
 
     fn get_header_id(&self) -> HeaderId<Hash> {
-        HeaderId(tmp_hash(self))
+        HeaderId(encoding::hash(&self.unsigned()))
+    }
+
+    fn get_author(&self) -> DeviceId {
+        self.author
+    }
+
+    fn operations_count(&self) -> u8 {
+        self.operations_count
     }
 
-    fn validate_header(&self, header_id: HeaderId<Hash>) -> bool {
-        // TODO: Actually check this.
-        true
+    fn acl_changes(&self) -> &[AclChange] {
+        &self.acl_changes
+    }
+
+    fn epoch(&self) -> Option<HeaderId<Hash>> {
+        self.epoch
+    }
+
+    fn wrapped_keys(&self) -> &[WrappedKey] {
+        &self.wrapped_keys
+    }
+
+    fn rotated_key(&self) -> Option<&[u8]> {
+        self.rotated_key.as_deref()
+    }
+
+    fn validate_header(&self) -> Result<(), ValidationError> {
+        let header_id = self.get_header_id();
+        if !self.author.verify(header_id.0.as_ref(), &self.signature) {
+            return Err(ValidationError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    fn validate_encrypted_body(&self, encrypted_body: &[u8]) -> Result<(), ValidationError> {
+        if operations_hash::<Hash>(encrypted_body) != self.operations_hash {
+            return Err(ValidationError::InvalidOperationsHash);
+        }
+        Ok(())
     }
 
     // // TODO: Move this to ECG state?
@@ -188,7 +288,7 @@ where
     // }
 }
 
-const MAX_OPERATION_COUNT: usize = 256;
+const MAX_OPERATION_COUNT: usize = ecg::MAX_BODY_OPERATIONS;
 
 // impl<Hash, T> ECGBody<T::Op, <T::Op as ConcretizeTime<T::Time>>::Serialized> for Body<Hash, <T::Op as ConcretizeTime<T::Time>>::Serialized>
 impl<Hash, Op> ECGBody<Op, Op::Serialized> for Body<Hash, Op::Serialized>
@@ -198,7 +298,7 @@ where
     Op::Serialized: Serialize,
     // T::Op: ConcretizeTime<OperationId<HeaderId<Hash>>>,
     // <T::Op as ConcretizeTime<T::Time>>::Serialized: Serialize,
-    Hash: Clone + Copy + Debug + Ord + util::Hash + Serialize,
+    Hash: Clone + Copy + Debug + Ord + util::Hash + Serialize + for<'d> Deserialize<'d>,
 {
     type Header = Header<Hash>;
 
@@ -240,21 +340,62 @@ where
             .expect("Unreachable: Length is bound by MAX_OPERATION_COUNT.")
     }
 
-    fn new_header(&self, parents: BTreeSet<<Self::Header as ECGHeader>::HeaderId>) -> Self::Header {
+    fn validate_body(&self, header: &Self::Header) -> Result<(), ValidationError> {
+        if self.operations.len() != header.operations_count as usize {
+            return Err(ValidationError::InvalidOperationsCount);
+        }
+        Ok(())
+    }
+
+    fn new_header(
+        &self,
+        encrypted_body: &[u8],
+        parents: BTreeSet<<Self::Header as ECGHeader>::HeaderId>,
+        author: &Identity,
+        acl_changes: Vec<AclChange>,
+        keys: HeaderKeys<<Self::Header as ECGHeader>::HeaderId>,
+    ) -> Self::Header {
+        let HeaderKeys {
+            epoch,
+            wrapped_keys,
+            rotated_key,
+        } = keys;
         let mut rng = rand::thread_rng();
         let nonce = rng.gen();
 
         // Sort parent headers.
-        let parents = parents.into_iter().collect();
+        let parent_ids: Vec<_> = parents.into_iter().collect();
+        let operations_count = <Self as ECGBody<Op, Op::Serialized>>::operations_count(self);
+        let operations_hash = operations_hash(encrypted_body);
 
         // TODO: Check for hash conflicts and generate another nonce?
 
+        // Sign the header id.
+        let author_id = author.device_id();
+        let header_id: Hash = encoding::hash(&UnsignedHeader {
+            nonce,
+            parent_ids: &parent_ids,
+            operations_count,
+            operations_hash: &operations_hash,
+            epoch: &epoch,
+            author: &author_id,
+            acl_changes: &acl_changes,
+            wrapped_keys: &wrapped_keys,
+            rotated_key: &rotated_key,
+        });
+        let signature = author.sign(header_id.as_ref());
+
         Header {
-            parent_ids: parents,
+            parent_ids,
             nonce,
-            operations_count: <Self as ECGBody<Op, Op::Serialized>>::operations_count(self),
-            operations_hash: self.get_hash(),
-            // phantom: PhantomData,
+            operations_count,
+            operations_hash,
+            epoch,
+            author: author_id,
+            acl_changes,
+            wrapped_keys,
+            rotated_key,
+            signature,
         }
     }
 
@@ -279,22 +420,9 @@ where
     // }
 }
 
-impl<Hash: util::Hash, SerializedOp> Body<Hash, SerializedOp>
-where
-    SerializedOp: Serialize,
-{
-    fn get_hash(&self) -> Hash {
-        tmp_hash(self)
-    }
-}
-
-// TODO: Standardize how to hash/serialize. XXX
-fn tmp_hash<T: Serialize, Hash: util::Hash>(x: &T) -> Hash {
-    // JP: Better way to do this? Just serialize once?
-    let mut h = Hash::new();
-    let serialized = serde_cbor::ser::to_vec(&x).unwrap();
-    Hash::update(&mut h, serialized);
-    Hash::finalize(h)
+/// The operations hash of an encrypted body.
+fn operations_hash<Hash: util::Hash>(encrypted_body: &[u8]) -> Hash {
+    encoding::hash(encrypted_body)
 }
 
 // OperationID's are header ids and index (HeaderId, u8)
@@ -305,18 +433,39 @@ pub struct OperationId<HeaderId> {
     pub operation_position: u8,
 }
 
-impl<HeaderId> ConcretizeTime<HeaderId> for OperationId<HeaderId> {
+impl<HeaderId: Clone> ConcretizeTime<HeaderId> for OperationId<HeaderId> {
     type Serialized = CausalTime<OperationId<HeaderId>>;
 
     fn concretize_time(src: Self::Serialized, current_header: HeaderId) -> Self {
         match src {
             CausalTime::Current { operation_position } => OperationId {
                 header_id: Some(current_header),
-                operation_position,
+                // Bodies hold fewer than `u8::MAX` operations, so larger positions don't point to
+                // any operation either way.
+                operation_position: u8::try_from(operation_position).unwrap_or(u8::MAX),
             },
             CausalTime::Time(t) => t,
         }
     }
+
+    fn rebase_batch_time(src: Self::Serialized, previous_headers: &[HeaderId]) -> Self::Serialized {
+        let CausalTime::Current { operation_position } = src else {
+            return src;
+        };
+        let position = operation_position as usize;
+        match previous_headers.get(position / ecg::MAX_BODY_OPERATIONS) {
+            Some(header_id) => CausalTime::Time(OperationId {
+                header_id: Some(header_id.clone()),
+                operation_position: (position % ecg::MAX_BODY_OPERATIONS) as u8,
+            }),
+            None => {
+                let chunk_start = previous_headers.len() * ecg::MAX_BODY_OPERATIONS;
+                CausalTime::Current {
+                    operation_position: (position - chunk_start) as u32,
+                }
+            }
+        }
+    }
 }
 
 impl<HeaderId> OperationId<HeaderId> {
@@ -421,8 +570,38 @@ impl<A: CRDT> ECGHeader for TestHeader<A> {
         self.header_id
     }
 
-    fn validate_header(&self, header_id: Self::HeaderId) -> bool {
-        true
+    fn get_author(&self) -> DeviceId {
+        // Every test header is written by the same device.
+        Identity::from_seed(&[0; 32]).device_id()
+    }
+
+    fn operations_count(&self) -> u8 {
+        // Test headers don't track their bodies.
+        0
+    }
+
+    fn acl_changes(&self) -> &[AclChange] {
+        &[]
+    }
+
+    fn epoch(&self) -> Option<u32> {
+        None
+    }
+
+    fn wrapped_keys(&self) -> &[WrappedKey] {
+        &[]
+    }
+
+    fn rotated_key(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn validate_header(&self) -> Result<(), ValidationError> {
+        Ok(())
+    }
+
+    fn validate_encrypted_body(&self, _encrypted_body: &[u8]) -> Result<(), ValidationError> {
+        Ok(())
     }
 
     // fn new_header(parents: BTreeSet<Self::HeaderId>, _body: &Self::Body) -> Self {
@@ -444,3 +623,119 @@ impl<A: CRDT> ECGHeader for TestHeader<A> {
     //     v
     // }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::generate_identity;
+    use crate::store::encryption::StoreKey;
+    use crate::util::Sha256Hash;
+
+    type Op = OperationId<HeaderId<Sha256Hash>>;
+
+    #[test]
+    fn test_golden_vectors() {
+        let body: Body<Sha256Hash, CausalTime<Op>> =
+            <Body<_, _> as ECGBody<Op, _>>::new_body(vec![
+                CausalTime::current_time(0),
+                CausalTime::time(OperationId::new(None, 3)),
+            ]);
+        assert_eq!(
+            hex::encode(encoding::to_vec(&body)),
+            "0000000000000000020000000000000000000000010003"
+        );
+        let encrypted_body = [9; 40];
+        assert_eq!(
+            hex::encode(operations_hash::<Sha256Hash>(&encrypted_body)),
+            "c99e90812cb62c33c619f4e2bb50c291003b32ab956d976257280f2e748a2b41"
+        );
+
+        let author = Identity::from_seed(&[5; 32]);
+        let parent_ids = vec![HeaderId(Sha256Hash([1; 32]))];
+        let acl_changes = vec![AclChange::Revoke {
+            device: Identity::from_seed(&[6; 32]).device_id(),
+        }];
+        let epoch = Some(HeaderId(Sha256Hash([2; 32])));
+        let rotated_key = Some(vec![3; 4]);
+        let header_id: Sha256Hash = encoding::hash(&UnsignedHeader {
+            nonce: 7,
+            parent_ids: &parent_ids,
+            operations_count: 2,
+            operations_hash: &operations_hash(&encrypted_body),
+            epoch: &epoch,
+            author: &author.device_id(),
+            acl_changes: &acl_changes,
+            wrapped_keys: &[],
+            rotated_key: &rotated_key,
+        });
+        let header = Header {
+            nonce: 7,
+            parent_ids,
+            operations_count: 2,
+            operations_hash: operations_hash(&encrypted_body),
+            epoch,
+            author: author.device_id(),
+            acl_changes,
+            wrapped_keys: vec![],
+            rotated_key,
+            signature: author.sign(header_id.as_ref()),
+        };
+        assert_eq!(hex::encode(encoding::to_vec(&header)), "00070000000000000001010101010101010101010101010101010101010101010101010101010101010102c99e90812cb62c33c619f4e2bb50c291003b32ab956d976257280f2e748a2b4101020202020202020202020202020202020202020202020202020202020202020200000000000000206e7a1cdd29b0b78fd13af4c5598feff4ef2a97166e3ca6f2e4fbfccd80505bf100000000000000010000000100000000000000208a875fff1eb38451577acd5afee405456568dd7c89e090863a0557bc7af49f17000000000000000001000000000000000403030303c22a810e8080670fa387afadc5a20b29edda31f38bda82371770c46bf530e9aaefd43f4a1d912e1786d0dd9d27dd62ea7102d1d7096efd42c8c2d1a857c7de0c");
+        assert_eq!(
+            hex::encode(header.get_header_id().0),
+            "9fa54757ee4b68e79bc07918cb072fe5f6e5da32bcf022714ef2fb0d2cf5ea1f"
+        );
+        assert_eq!(header.get_header_id(), HeaderId(header_id));
+        assert_eq!(header.validate_header(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_node() {
+        let body: Body<Sha256Hash, CausalTime<Op>> =
+            <Body<_, _> as ECGBody<Op, _>>::new_body(vec![
+                CausalTime::current_time(0),
+                CausalTime::current_time(1),
+            ]);
+        let author = generate_identity();
+        let encrypted_body = StoreKey::generate().encrypt(&encoding::to_vec(&body));
+        let header = <Body<_, _> as ECGBody<Op, _>>::new_header(
+            &body,
+            &encrypted_body,
+            BTreeSet::new(),
+            &author,
+            vec![],
+            HeaderKeys {
+                epoch: None,
+                wrapped_keys: vec![],
+                rotated_key: None,
+            },
+        );
+        assert_eq!(header.validate_header(), Ok(()));
+        assert_eq!(header.validate_encrypted_body(&encrypted_body), Ok(()));
+        assert_eq!(
+            <Body<_, _> as ECGBody<Op, _>>::validate_body(&body, &header),
+            Ok(())
+        );
+
+        let mut forged = header.clone();
+        forged.author = generate_identity().device_id();
+        assert_eq!(
+            forged.validate_header(),
+            Err(ValidationError::InvalidSignature)
+        );
+
+        let mut bad_count = header.clone();
+        bad_count.operations_count = 1;
+        assert_eq!(
+            <Body<_, _> as ECGBody<Op, _>>::validate_body(&body, &bad_count),
+            Err(ValidationError::InvalidOperationsCount)
+        );
+
+        let mut other_body = encrypted_body.clone();
+        other_body[0] ^= 1;
+        assert_eq!(
+            header.validate_encrypted_body(&other_body),
+            Err(ValidationError::InvalidOperationsHash)
+        );
+    }
+}
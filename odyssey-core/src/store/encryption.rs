@@ -0,0 +1,572 @@
+//! End-to-end encryption of store contents.
+//!
+//! Every store has a symmetric `StoreKey` that encrypts its initial state and each ECG body.
+//! Hashes (the merkle tree and operations hashes) are of the ciphertexts, so peers without the key
+//! can still validate and relay the store. Keys are shared with members out of band
+//! (`StoreKey::to_bytes`), or in the store itself by wrapping them for a member's device key.
+//!
+//! Admins can rotate the key, for example when removing a member, so that removed members can't
+//! read new operations. A rotation node starts a new epoch: its children are encrypted with a new
+//! key that is wrapped for the remaining readers. The rotation node also records the previous key
+//! encrypted with the new one, so the latest key unlocks every older epoch.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use odyssey_crdt::CRDT;
+use rand::{rngs::OsRng, TryRngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{btree_map::Entry, BTreeMap};
+use std::fmt::{self, Debug, Display};
+use zeroize::Zeroize;
+
+use crate::auth::{DeviceId, Identity};
+use crate::store::ecg::{self, ECGHeader};
+
+/// Size in bytes of a store key.
+pub const STORE_KEY_SIZE: usize = 32;
+/// Size of the random nonce that prefixes every ciphertext.
+const NONCE_SIZE: usize = 24;
+/// Context for the keys that wrap store keys.
+const WRAP_INFO: &[u8] = b"odyssey store key wrap v0";
+
+/// A ciphertext that couldn't be decrypted. It was encrypted with a different key or was tampered
+/// with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecryptionError;
+
+impl Display for DecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to decrypt")
+    }
+}
+
+impl std::error::Error for DecryptionError {}
+
+/// Symmetric key that encrypts a store's contents.
+#[derive(Clone, PartialEq, Eq)]
+pub struct StoreKey {
+    key: [u8; STORE_KEY_SIZE],
+}
+
+impl Debug for StoreKey {
+    // Don't leak the key into logs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StoreKey(..)")
+    }
+}
+
+impl Drop for StoreKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl StoreKey {
+    /// Generate a new random key.
+    pub(crate) fn generate() -> StoreKey {
+        let mut key = [0; STORE_KEY_SIZE];
+        OsRng
+            .try_fill_bytes(&mut key)
+            .expect("Key generation failed");
+        StoreKey { key }
+    }
+
+    /// Recreate a key that was exported with `StoreKey::to_bytes`.
+    pub fn from_bytes(key: [u8; STORE_KEY_SIZE]) -> StoreKey {
+        StoreKey { key }
+    }
+
+    /// Export the key to share it out of band. Anyone with the key can read the store, so keep it
+    /// secret.
+    pub fn to_bytes(&self) -> [u8; STORE_KEY_SIZE] {
+        self.key
+    }
+
+    /// Encrypt the plaintext with a random nonce, which prefixes the returned ciphertext.
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_SIZE];
+        OsRng
+            .try_fill_bytes(&mut nonce)
+            .expect("Nonce generation failed");
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .expect("Unreachable: Encryption only fails for oversized plaintexts.");
+
+        let mut encrypted = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        encrypted
+    }
+
+    /// Decrypt a ciphertext created by `StoreKey::encrypt`.
+    pub(crate) fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        if encrypted.len() < NONCE_SIZE {
+            return Err(DecryptionError);
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&self.key));
+        cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| DecryptionError)
+    }
+
+    /// Encrypt another key with this one, so that anyone with this key can read the other key.
+    pub(crate) fn encrypt_key(&self, key: &StoreKey) -> Vec<u8> {
+        self.encrypt(&key.key)
+    }
+
+    /// Decrypt a key encrypted by `StoreKey::encrypt_key`.
+    pub(crate) fn decrypt_key(&self, encrypted_key: &[u8]) -> Option<StoreKey> {
+        let mut plaintext = self.decrypt(encrypted_key).ok()?;
+        let key = plaintext
+            .as_slice()
+            .try_into()
+            .ok()
+            .map(StoreKey::from_bytes);
+        plaintext.zeroize();
+        key
+    }
+
+    /// Wrap the key so that only the recipient can unwrap it.
+    pub(crate) fn wrap(&self, recipient: &DeviceId) -> WrappedKey {
+        let mut ephemeral_secret = [0; 32];
+        OsRng
+            .try_fill_bytes(&mut ephemeral_secret)
+            .expect("Key generation failed");
+        let ephemeral_key =
+            x25519_dalek::x25519(ephemeral_secret, x25519_dalek::X25519_BASEPOINT_BYTES);
+        let mut shared_secret =
+            x25519_dalek::x25519(ephemeral_secret, recipient.key_agreement_key());
+        ephemeral_secret.zeroize();
+
+        let mut wrapping_key = derive_wrapping_key(&shared_secret, &ephemeral_key, recipient);
+        shared_secret.zeroize();
+        // Each wrapping key is only used once, so the nonce is fixed.
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&wrapping_key));
+        wrapping_key.zeroize();
+        let ciphertext = cipher
+            .encrypt(&Default::default(), self.key.as_slice())
+            .expect("Unreachable: Encryption only fails for oversized plaintexts.");
+
+        WrappedKey {
+            recipient: *recipient,
+            ephemeral_key,
+            ciphertext,
+        }
+    }
+}
+
+/// A store key encrypted for a single device, so that it can be shared in the store's ECG.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct WrappedKey {
+    /// The device that can unwrap the key.
+    recipient: DeviceId,
+    /// Ephemeral X25519 public key that the wrapping key is agreed with.
+    ephemeral_key: [u8; 32],
+    /// The store key, encrypted with the wrapping key.
+    ciphertext: Vec<u8>,
+}
+
+impl WrappedKey {
+    pub fn recipient(&self) -> &DeviceId {
+        &self.recipient
+    }
+
+    /// Unwrap the key with the recipient's identity. Returns `None` if the identity isn't the
+    /// recipient's or the wrapped key was tampered with.
+    pub(crate) fn unwrap(&self, identity: &Identity) -> Option<StoreKey> {
+        if identity.device_id() != self.recipient {
+            return None;
+        }
+        let mut shared_secret = identity.diffie_hellman(&self.ephemeral_key);
+        // Reject low order ephemeral keys, which make the shared secret predictable.
+        if shared_secret == [0; 32] {
+            return None;
+        }
+
+        let mut wrapping_key =
+            derive_wrapping_key(&shared_secret, &self.ephemeral_key, &self.recipient);
+        shared_secret.zeroize();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&wrapping_key));
+        wrapping_key.zeroize();
+        let mut plaintext = cipher
+            .decrypt(&Default::default(), self.ciphertext.as_slice())
+            .ok()?;
+        let key = plaintext
+            .as_slice()
+            .try_into()
+            .ok()
+            .map(StoreKey::from_bytes);
+        plaintext.zeroize();
+        key
+    }
+}
+
+/// The keys that an ECG header records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderKeys<HeaderId> {
+    /// The epoch of the key that the body is encrypted with.
+    pub epoch: Option<HeaderId>,
+    /// Keys wrapped for the devices that the node shares them with: the new key if the node
+    /// rotates the store's key, and otherwise the key of `epoch`.
+    pub wrapped_keys: Vec<WrappedKey>,
+    /// If the node rotates the store's key, the key of `epoch` encrypted with the new key.
+    pub rotated_key: Option<Vec<u8>>,
+}
+
+/// The store keys we know, by epoch. An epoch is the key rotation node that introduced the key, or
+/// `None` for the key that the store was created with.
+#[derive(Clone, Debug)]
+pub(crate) struct StoreKeys<HeaderId> {
+    keys: BTreeMap<Option<HeaderId>, StoreKey>,
+}
+
+impl<HeaderId> Default for StoreKeys<HeaderId> {
+    fn default() -> Self {
+        StoreKeys {
+            keys: BTreeMap::new(),
+        }
+    }
+}
+
+impl<HeaderId: Ord + Copy> StoreKeys<HeaderId> {
+    /// The keys of a new store.
+    pub(crate) fn new(initial_key: StoreKey) -> Self {
+        StoreKeys {
+            keys: BTreeMap::from([(None, initial_key)]),
+        }
+    }
+
+    pub(crate) fn get(&self, epoch: &Option<HeaderId>) -> Option<&StoreKey> {
+        self.keys.get(epoch)
+    }
+
+    pub(crate) fn insert(&mut self, epoch: Option<HeaderId>, key: StoreKey) {
+        self.keys.insert(epoch, key);
+    }
+
+    /// The key that the initial state is encrypted with. We can read the store once we have it.
+    pub(crate) fn initial(&self) -> Option<&StoreKey> {
+        self.get(&None)
+    }
+
+    /// The key that new ECG nodes are encrypted with.
+    pub(crate) fn latest<Header: ECGHeader<HeaderId = HeaderId>, T: CRDT>(
+        &self,
+        ecg_state: &ecg::State<Header, T>,
+    ) -> Option<&StoreKey> {
+        self.get(&epoch_at(ecg_state, ecg_state.tips()))
+    }
+
+    /// Learn the keys that the ECG shares with this identity, and the keys that were rotated out by
+    /// keys we know. Returns whether we learned any keys.
+    pub(crate) fn update<Header: ECGHeader<HeaderId = HeaderId>, T: CRDT>(
+        &mut self,
+        identity: &Identity,
+        ecg_state: &ecg::State<Header, T>,
+    ) -> bool {
+        let mut learned = false;
+        // Each pass can unlock older epochs, so repeat until nothing changes.
+        loop {
+            let count = self.keys.len();
+            for header_id in ecg_state.key_nodes() {
+                let header = ecg_state
+                    .get_header(header_id)
+                    .expect("Unreachable: Key nodes are in the ECG.");
+                let shared_epoch = if header.rotated_key().is_some() {
+                    Some(*header_id)
+                } else {
+                    header.epoch()
+                };
+                if let Entry::Vacant(entry) = self.keys.entry(shared_epoch) {
+                    if let Some(key) = header
+                        .wrapped_keys()
+                        .iter()
+                        .find_map(|k| k.unwrap(identity))
+                    {
+                        entry.insert(key);
+                    }
+                }
+
+                let rotated_out_key = header
+                    .rotated_key()
+                    .zip(self.keys.get(&Some(*header_id)))
+                    .and_then(|(rotated_key, new_key)| new_key.decrypt_key(rotated_key));
+                if let Some(key) = rotated_out_key {
+                    self.keys.entry(header.epoch()).or_insert(key);
+                }
+            }
+            if self.keys.len() == count {
+                return learned;
+            }
+            learned = true;
+        }
+    }
+
+    /// Add a key that was shared out of band. Its epoch is found by trying to decrypt the initial
+    /// state and the keys that rotations replaced with it. Returns whether it's one of the store's
+    /// keys.
+    pub(crate) fn insert_shared<Header: ECGHeader<HeaderId = HeaderId>, T: CRDT>(
+        &mut self,
+        key: StoreKey,
+        ecg_state: &ecg::State<Header, T>,
+        initial_state: &[u8],
+    ) -> bool {
+        let epoch = if key.decrypt(initial_state).is_ok() {
+            None
+        } else {
+            let rotation = ecg_state.key_nodes().iter().find(|header_id| {
+                ecg_state
+                    .get_header(header_id)
+                    .and_then(|header| header.rotated_key())
+                    .is_some_and(|rotated_key| key.decrypt_key(rotated_key).is_some())
+            });
+            match rotation {
+                Some(header_id) => Some(*header_id),
+                None => return false,
+            }
+        };
+        self.keys.entry(epoch).or_insert(key);
+        true
+    }
+
+    /// Serialize the keys to persist them. The keys aren't encrypted, so the encoding is as
+    /// secret as the keys themselves.
+    pub(crate) fn encode(&self) -> Vec<u8>
+    where
+        HeaderId: Serialize,
+    {
+        let keys: Vec<_> = self
+            .keys
+            .iter()
+            .map(|(epoch, key)| (epoch, key.to_bytes()))
+            .collect();
+        serde_cbor::to_vec(&keys)
+            .expect("Unreachable: Epochs and key bytes always serialize to CBOR.")
+    }
+
+    /// Deserialize keys that were persisted with `StoreKeys::encode`.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self>
+    where
+        HeaderId: for<'d> Deserialize<'d>,
+    {
+        let keys: Vec<(Option<HeaderId>, [u8; STORE_KEY_SIZE])> =
+            serde_cbor::from_slice(bytes).ok()?;
+        let keys = keys
+            .into_iter()
+            .map(|(epoch, key)| (epoch, StoreKey::from_bytes(key)))
+            .collect();
+        Some(StoreKeys { keys })
+    }
+}
+
+/// The key epoch after the given heads (and all of their ancestors): the latest key rotation among
+/// them. Concurrent rotations are ordered by header id. Unknown heads are ignored.
+///
+/// Concurrent rotations don't rotate each other's keys, so a device that one of them removes can
+/// still read nodes in the epoch of the other one, if the other rotation shares its key with it.
+// TODO: Rotate the key again when merging concurrent rotations.
+pub(crate) fn epoch_at<'a, Header: ECGHeader, T: CRDT>(
+    ecg_state: &ecg::State<Header, T>,
+    heads: impl IntoIterator<Item = &'a Header::HeaderId>,
+) -> Option<Header::HeaderId>
+where
+    Header::HeaderId: 'a,
+{
+    let heads: Vec<_> = heads.into_iter().collect();
+    let is_ancestor_of =
+        |a: &Header::HeaderId, d: &Header::HeaderId| ecg_state.is_ancestor_of(a, d) == Some(true);
+
+    let rotations: Vec<_> = ecg_state
+        .key_nodes()
+        .iter()
+        .filter(|n| {
+            ecg_state
+                .get_header(n)
+                .is_some_and(|h| h.rotated_key().is_some())
+        })
+        .filter(|n| heads.iter().any(|h| is_ancestor_of(n, h)))
+        .collect();
+    rotations
+        .iter()
+        .filter(|n| !rotations.iter().any(|m| m != *n && is_ancestor_of(n, m)))
+        .max()
+        .map(|n| **n)
+}
+
+/// Derive the key that wraps a store key for the recipient.
+fn derive_wrapping_key(
+    shared_secret: &[u8; 32],
+    ephemeral_key: &[u8; 32],
+    recipient: &DeviceId,
+) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(Some(ephemeral_key), shared_secret);
+    let mut key = [0; 32];
+    hkdf.expand_multi_info(&[WRAP_INFO, recipient.as_bytes()], &mut key)
+        .expect("Unreachable: 32 bytes is a valid HKDF-SHA256 output length.");
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use odyssey_crdt::register::LWW;
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::auth::generate_identity;
+    use crate::store::acl::{AclChange, Role};
+    use crate::store::ecg::{
+        v0::{Body, Header, HeaderId, OperationId},
+        ECGBody,
+    };
+    use crate::time::CausalTime;
+    use crate::util::Sha256Hash;
+
+    type Id = HeaderId<Sha256Hash>;
+    type Op = OperationId<Id>;
+    type State = ecg::State<Header<Sha256Hash>, LWW<u64, bool>>;
+
+    fn insert(
+        ecg_state: &mut State,
+        author: &Identity,
+        parents: &[Id],
+        acl_changes: Vec<AclChange>,
+        keys: HeaderKeys<Id>,
+    ) -> Id {
+        let body: Body<Sha256Hash, CausalTime<Op>> =
+            <Body<_, _> as ECGBody<Op, _>>::new_body(vec![]);
+        let parents = parents.iter().copied().collect::<BTreeSet<_>>();
+        let header = <Body<_, _> as ECGBody<Op, _>>::new_header(
+            &body,
+            &[],
+            parents,
+            author,
+            acl_changes,
+            keys,
+        );
+        let header_id = header.get_header_id();
+        assert!(ecg_state.insert_header(header, vec![]));
+        header_id
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let key = StoreKey::generate();
+        let encrypted = key.encrypt(b"hello");
+        assert_ne!(key.encrypt(b"hello"), encrypted);
+        assert_eq!(key.decrypt(&encrypted), Ok(b"hello".to_vec()));
+
+        assert_eq!(
+            StoreKey::generate().decrypt(&encrypted),
+            Err(DecryptionError)
+        );
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(key.decrypt(&tampered), Err(DecryptionError));
+        assert_eq!(key.decrypt(&encrypted[..10]), Err(DecryptionError));
+    }
+
+    #[test]
+    fn test_wrap_round_trip() {
+        let key = StoreKey::generate();
+        let recipient = generate_identity();
+        let wrapped = key.wrap(&recipient.device_id());
+        assert_eq!(wrapped.unwrap(&recipient), Some(key));
+        assert_eq!(wrapped.unwrap(&generate_identity()), None);
+
+        // Another device can't claim the wrapped key.
+        let mut redirected = wrapped.clone();
+        let other = generate_identity();
+        redirected.recipient = other.device_id();
+        assert_eq!(redirected.unwrap(&other), None);
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let owner = generate_identity();
+        let reader = generate_identity();
+        let removed = generate_identity();
+        let initial_key = StoreKey::generate();
+        let new_key = StoreKey::generate();
+        let initial_state = initial_key.encrypt(b"initial state");
+        let mut ecg_state = State::new();
+
+        let n1 = insert(
+            &mut ecg_state,
+            &owner,
+            &[],
+            [&reader, &removed]
+                .iter()
+                .map(|m| AclChange::Grant {
+                    device: m.device_id(),
+                    role: Role::Reader,
+                })
+                .collect(),
+            HeaderKeys {
+                epoch: None,
+                wrapped_keys: vec![
+                    initial_key.wrap(&reader.device_id()),
+                    initial_key.wrap(&removed.device_id()),
+                ],
+                rotated_key: None,
+            },
+        );
+        let n2 = insert(
+            &mut ecg_state,
+            &owner,
+            &[n1],
+            vec![AclChange::Revoke {
+                device: removed.device_id(),
+            }],
+            HeaderKeys {
+                epoch: None,
+                wrapped_keys: vec![new_key.wrap(&reader.device_id())],
+                rotated_key: Some(new_key.encrypt_key(&initial_key)),
+            },
+        );
+        let n3 = insert(
+            &mut ecg_state,
+            &reader,
+            &[n2],
+            vec![],
+            HeaderKeys {
+                epoch: Some(n2),
+                wrapped_keys: vec![],
+                rotated_key: None,
+            },
+        );
+
+        assert_eq!(epoch_at(&ecg_state, &[]), None);
+        assert_eq!(epoch_at(&ecg_state, &[n1]), None);
+        assert_eq!(epoch_at(&ecg_state, &[n2]), Some(n2));
+        assert_eq!(epoch_at(&ecg_state, ecg_state.tips()), Some(n2));
+        assert_eq!(ecg_state.tips(), &BTreeSet::from([n3]));
+
+        // Remaining members learn both keys, but removed members only learn the initial key.
+        let mut keys = StoreKeys::default();
+        assert!(keys.update(&reader, &ecg_state));
+        assert!(!keys.update(&reader, &ecg_state));
+        assert_eq!(keys.initial(), Some(&initial_key));
+        assert_eq!(keys.latest(&ecg_state), Some(&new_key));
+
+        let mut keys = StoreKeys::default();
+        assert!(keys.update(&removed, &ecg_state));
+        assert_eq!(keys.initial(), Some(&initial_key));
+        assert_eq!(keys.latest(&ecg_state), None);
+
+        // The latest key shared out of band unlocks the keys it replaced.
+        let mut keys = StoreKeys::default();
+        assert!(!keys.insert_shared(StoreKey::generate(), &ecg_state, &initial_state));
+        assert!(keys.insert_shared(new_key.clone(), &ecg_state, &initial_state));
+        assert_eq!(keys.get(&Some(n2)), Some(&new_key));
+        assert!(keys.update(&generate_identity(), &ecg_state));
+        assert_eq!(keys.initial(), Some(&initial_key));
+
+        let decoded = StoreKeys::<Id>::decode(&keys.encode()).unwrap();
+        assert_eq!(decoded.get(&None), Some(&initial_key));
+        assert_eq!(decoded.get(&Some(n2)), Some(&new_key));
+    }
+}
@@ -7,22 +7,25 @@ use std::fmt::Debug;
 use std::{
     collections::{BTreeMap, BTreeSet},
     ops::Range,
+    sync::Arc,
 };
 use tokio::{
     sync::{
-        mpsc::{UnboundedReceiver, UnboundedSender},
+        mpsc::{self, error::TrySendError, UnboundedReceiver, UnboundedSender},
         oneshot::{self, Sender},
+        watch,
     },
     task::JoinHandle,
 };
 use tracing::{debug, error, warn};
 use typeable::{TypeId, Typeable};
 
+use crate::storage::{Storage, StorageError};
 use crate::store::v0::BLOCK_SIZE;
 use crate::time::ConcretizeTime;
 use crate::util::merkle_tree::{MerkleTree, Potential};
 use crate::{
-    auth::DeviceId,
+    auth::{DeviceId, Identity},
     core::{OdysseyType, SharedState},
     network::{
         multiplexer::{run_miniprotocol_async, SpawnMultiplexerTask},
@@ -33,17 +36,26 @@ use crate::{
         store_peer::v0::{MsgStoreSyncRequest, StoreSync, StoreSyncCommand},
     },
     store::{
-        ecg::{ECGBody, ECGHeader, RawECGBody},
-        v0::{BLOCK_REQUEST_LIMIT, MERKLE_REQUEST_LIMIT},
+        acl::{Acl, AclChange, Role},
+        ecg::{ECGBody, ECGHeader, RawECGBody, ValidationError},
+        encryption::{epoch_at, HeaderKeys, StoreKey, StoreKeys},
+        v0::{decrypt_initial_state, BLOCK_REQUEST_LIMIT, MERKLE_REQUEST_LIMIT},
     },
-    util::{self, compress_consecutive_into_ranges},
+    util::{self, compress_consecutive_into_ranges, encoding},
 };
 
+pub mod acl;
 pub mod ecg;
+pub mod encryption;
 pub mod v0; // TODO: Move this to network::protocol
 
 pub use v0::{MetadataBody, MetadataHeader, Nonce};
 
+/// Channel that a peer waiting for ECG updates is sent the ECG on. It's sent `None` if the peer may
+/// no longer sync the store.
+type ECGSubscriber<Header> =
+    oneshot::Sender<Option<ecg::UntypedState<<Header as ECGHeader>::HeaderId, Header>>>;
+
 pub struct State<StoreId, Header: ecg::ECGHeader, T: CRDT, Hash> {
     // Peers that also have this store (that we are potentially connected to?).
     peers: BTreeMap<DeviceId, PeerInfo<Header::HeaderId, Header>>, // BTreeSet<DeviceId>,
@@ -57,9 +69,21 @@ pub struct State<StoreId, Header: ecg::ECGHeader, T: CRDT, Hash> {
             oneshot::Sender<Option<Vec<Option<Vec<u8>>>>>,
         ),
     >,
-    ecg_subscribers:
-        BTreeMap<DeviceId, oneshot::Sender<ecg::UntypedState<Header::HeaderId, Header>>>,
+    /// Peers waiting for ECG updates.
+    ecg_subscribers: BTreeMap<DeviceId, ECGSubscriber<Header>>,
     // listeners: Vec<UnboundedSender<StateUpdate<Header, T>>>,
+    /// Storage that the store is persisted to.
+    storage: Box<dyn Storage + Send>,
+    /// Number of ECG nodes to apply between persisted checkpoints. Zero disables checkpoints.
+    checkpoint_interval: u64,
+    /// Identity of this device, which keys shared in the ECG are unwrapped with.
+    identity: Identity,
+    /// The store's keys that we know. Without the initial key, we can only relay the store to
+    /// other peers.
+    keys: StoreKeys<Header::HeaderId>,
+    /// A key that was shared out of band, but whose epoch we can't tell until we've downloaded
+    /// more of the store.
+    pending_key: Option<StoreKey>,
 }
 
 // States are:
@@ -84,11 +108,11 @@ pub enum StateMachine<StoreId, Header: ecg::ECGHeader, T: CRDT, Hash> {
     Syncing {
         metadata: MetadataHeader<Hash>,
         merkle_tree: MerkleTree<Hash>,
-        initial_state: Vec<u8>, // Or just T?
+        /// The encrypted initial state.
+        initial_state: Vec<u8>,
         ecg_state: ecg::State<Header, T>,
-        decrypted_state: DecryptedState<Header, T>, // Temporary
-                                                    // decrypted_state: Option<DecryptedState<Header, T>>, // JP: Is this actually used?
-                                                    // Does it make sense?
+        /// `None` if we don't have the store's key, so we only relay the store.
+        decrypted_state: Option<DecryptedState<Header, T>>,
     },
 }
 
@@ -96,9 +120,66 @@ pub struct DecryptedState<Header: ecg::ECGHeader, T: CRDT> {
     /// Latest ECG application state we've seen.
     latest_state: T,
 
-    /// Headers corresponding to the latest ECG application state.
-    // TODO: Remove this.
+    /// Headers corresponding to the latest ECG application state. These are the tips of the ECG
+    /// nodes that have been applied.
     latest_headers: BTreeSet<Header::HeaderId>,
+
+    /// Checkpoints that `latest_state` is rebuilt from when operations can't be applied
+    /// incrementally, oldest first. The first one is the state before any ECG nodes are applied.
+    checkpoints: Vec<Checkpoint<Header::HeaderId, T>>,
+
+    /// Number of ECG nodes applied since the last checkpoint.
+    nodes_since_checkpoint: u64,
+}
+
+impl<Header: ecg::ECGHeader, T: CRDT + Clone> DecryptedState<Header, T> {
+    fn new(initial_state: T) -> Self {
+        let initial_checkpoint = Checkpoint {
+            headers: BTreeSet::new(),
+            state: initial_state,
+        };
+        DecryptedState::from_checkpoint(initial_checkpoint, None)
+    }
+
+    /// Start from the newest checkpoint, falling back to the initial one.
+    fn from_checkpoint(
+        initial_checkpoint: Checkpoint<Header::HeaderId, T>,
+        checkpoint: Option<Checkpoint<Header::HeaderId, T>>,
+    ) -> Self {
+        let mut checkpoints = vec![initial_checkpoint];
+        checkpoints.extend(checkpoint);
+        let newest = checkpoints
+            .last()
+            .expect("Unreachable: There's always an initial checkpoint.");
+        DecryptedState {
+            latest_state: newest.state.clone(),
+            latest_headers: newest.headers.clone(),
+            checkpoints,
+            nodes_since_checkpoint: 0,
+        }
+    }
+
+    /// Take a checkpoint of the latest state. Only the initial and newest checkpoints are kept.
+    fn checkpoint(&mut self) -> &Checkpoint<Header::HeaderId, T> {
+        self.checkpoints.truncate(1);
+        self.checkpoints.push(Checkpoint {
+            headers: self.latest_headers.clone(),
+            state: self.latest_state.clone(),
+        });
+        self.nodes_since_checkpoint = 0;
+        self.checkpoints
+            .last()
+            .expect("Unreachable: We just pushed a checkpoint.")
+    }
+}
+
+/// A snapshot of the application state, along with the ECG nodes that were applied to it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Checkpoint<HeaderId: Ord, T> {
+    /// Tips of the ECG nodes that were applied to `state`. The checkpoint includes these nodes and
+    /// all of their ancestors.
+    headers: BTreeSet<HeaderId>,
+    state: T,
 }
 
 /// Information about a peer.
@@ -182,25 +263,27 @@ impl<T> PeerStatus<T> {
 }
 
 impl<
-        StoreId: Copy + Eq,
-        Header: ecg::ECGHeader + Clone + Debug,
+        StoreId: Copy + Eq + AsRef<[u8]>,
+        Header: ecg::ECGHeader + Clone + Debug + Serialize,
         T: CRDT + Clone,
-        Hash: util::Hash + Debug + Into<StoreId>,
+        Hash: util::Hash + Debug + Into<StoreId> + Serialize,
     > State<StoreId, Header, T, Hash>
 {
     /// Initialize a new store with the given state. This initializes the header, including
     /// generating a random nonce.
-    pub fn new_syncing(initial_state: T) -> State<StoreId, Header, T, Hash>
+    pub fn new_syncing(
+        initial_state: T,
+        storage: Box<dyn Storage + Send>,
+        owner: &Identity,
+    ) -> State<StoreId, Header, T, Hash>
     where
         T: Serialize + Typeable,
     {
-        let init_body = MetadataBody::new(&initial_state);
+        let key = StoreKey::generate();
+        let init_body = MetadataBody::new(&initial_state, &key);
         debug!("Initialized body: {:?}", init_body);
-        let store_header = MetadataHeader::generate::<T>(&init_body);
-        let decrypted_state = DecryptedState {
-            latest_state: initial_state,
-            latest_headers: BTreeSet::new(),
-        };
+        let store_header = MetadataHeader::generate::<T>(&init_body, owner);
+        let decrypted_state = DecryptedState::new(initial_state);
 
         let (merkle_tree, initial_state) = init_body.build();
 
@@ -209,7 +292,7 @@ impl<
             merkle_tree,
             initial_state,
             ecg_state: ecg::State::new(),
-            decrypted_state, // : Some(decrypted_state),
+            decrypted_state: Some(decrypted_state),
         };
         State {
             peers: BTreeMap::new(),
@@ -218,20 +301,205 @@ impl<
             merkle_subscribers: BTreeMap::new(),
             block_subscribers: BTreeMap::new(),
             ecg_subscribers: BTreeMap::new(),
+            storage,
+            checkpoint_interval: 0,
+            identity: owner.clone(),
+            keys: StoreKeys::new(key),
+            pending_key: None,
         }
     }
 
-    /// Create a new store with the given store id that is downloading the store's header.
-    pub(crate) fn new_downloading(store_id: StoreId) -> Self {
-        let state_machine = StateMachine::DownloadingMetadata { store_id };
+    /// Load a store with the given store id from storage. Any parts of the store that haven't been
+    /// persisted yet (or fail to load) are downloaded from peers. Keys that weren't persisted are
+    /// unwrapped from the ECG if they were shared with this device.
+    pub(crate) fn load<OT>(
+        store_id: StoreId,
+        storage: Box<dyn Storage + Send>,
+        identity: &Identity,
+    ) -> Self
+    where
+        OT: OdysseyType<ECGHeader = Header>,
+        T: CRDT<Time = OT::Time> + for<'d> Deserialize<'d>,
+        Header: for<'d> Deserialize<'d>,
+        Hash: for<'d> Deserialize<'d>,
+        OT::ECGBody<T>: for<'d> Deserialize<'d>
+            + ECGBody<
+                T::Op,
+                <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+                Header = OT::ECGHeader,
+            >,
+        T::Op: ConcretizeTime<<Header as ECGHeader>::HeaderId>,
+    {
+        let keys = match storage.read_keys(store_id.as_ref()) {
+            Ok(Some(keys)) => StoreKeys::decode(&keys).unwrap_or_else(|| {
+                error!("Failed to load store keys from storage: Invalid keys");
+                StoreKeys::default()
+            }),
+            Ok(None) => StoreKeys::default(),
+            Err(err) => {
+                error!("Failed to load store keys from storage: {err}");
+                StoreKeys::default()
+            }
+        };
+        let state_machine =
+            match load_state_machine::<OT, _, _, _>(store_id, storage.as_ref(), &keys) {
+                Ok(state_machine) => state_machine,
+                Err(err) => {
+                    error!("Failed to load store from storage: {err}");
+                    StateMachine::DownloadingMetadata { store_id }
+                }
+            };
 
-        State {
+        let mut store = State {
             peers: BTreeMap::new(),
             state_machine,
             metadata_subscribers: BTreeMap::new(),
             merkle_subscribers: BTreeMap::new(),
             block_subscribers: BTreeMap::new(),
             ecg_subscribers: BTreeMap::new(),
+            storage,
+            checkpoint_interval: 0,
+            identity: identity.clone(),
+            keys,
+            pending_key: None,
+        };
+        if store.learn_keys() {
+            store.persist_keys();
+            store.rebuild_decrypted_state::<OT>(&mut vec![]);
+        }
+        store
+    }
+
+    /// Set how many ECG nodes are applied between persisted checkpoints. Zero disables checkpoints.
+    pub(crate) fn set_checkpoint_interval(&mut self, checkpoint_interval: u64) {
+        self.checkpoint_interval = checkpoint_interval;
+    }
+
+    /// Persist a checkpoint of the latest state once `checkpoint_interval` ECG nodes have been
+    /// applied since the last one.
+    fn checkpoint_if_needed(&mut self)
+    where
+        T: Serialize,
+        Header::HeaderId: Serialize,
+    {
+        if self.checkpoint_interval == 0 {
+            return;
+        }
+        let store_id = self.store_id();
+        let StateMachine::Syncing {
+            decrypted_state: Some(decrypted_state),
+            ..
+        } = &mut self.state_machine
+        else {
+            return;
+        };
+        if decrypted_state.nodes_since_checkpoint < self.checkpoint_interval {
+            return;
+        }
+        // A user's CRDT may fail to serialize, in which case the store keeps running without it.
+        let checkpoint = match serde_cbor::to_vec(decrypted_state.checkpoint()) {
+            Ok(checkpoint) => checkpoint,
+            Err(err) => {
+                error!("Failed to serialize checkpoint: {err}");
+                return;
+            }
+        };
+
+        // Make sure the ECG nodes the checkpoint includes are persisted first.
+        self.flush_storage();
+        if let Err(err) = self
+            .storage
+            .write_checkpoint(store_id.as_ref(), &checkpoint)
+        {
+            error!("Failed to persist checkpoint: {err}");
+        }
+    }
+
+    /// Take back the store's storage, dropping the store.
+    pub(crate) fn into_storage(self) -> Box<dyn Storage + Send> {
+        self.storage
+    }
+
+    /// Flush the store's storage so that everything persisted so far is durable.
+    fn flush_storage(&mut self) {
+        if let Err(err) = self.storage.flush() {
+            error!("Failed to flush store storage: {err}");
+        }
+    }
+
+    /// Persist everything we have for the store (excluding the ECG).
+    pub(crate) fn persist_store(&mut self) {
+        self.persist_metadata();
+        self.persist_merkle_tree();
+        self.persist_initial_state();
+        self.persist_keys();
+    }
+
+    /// Persist the store's keys that we know.
+    fn persist_keys(&mut self) {
+        let keys = self.keys.encode();
+        let store_id = self.store_id();
+        if let Err(err) = self.storage.write_keys(store_id.as_ref(), &keys) {
+            error!("Failed to persist store keys: {err}");
+        }
+    }
+
+    /// Persist the store's metadata header, if we have it.
+    fn persist_metadata(&mut self) {
+        let Some(metadata) = self.metadata() else {
+            return;
+        };
+        let metadata = serde_cbor::to_vec(metadata)
+            .expect("Unreachable: Store types always serialize to CBOR.");
+        let store_id = self.store_id();
+        if let Err(err) = self.storage.write_metadata(store_id.as_ref(), &metadata) {
+            error!("Failed to persist store metadata: {err}");
+        }
+    }
+
+    /// Persist the store's merkle tree, if we have it.
+    fn persist_merkle_tree(&mut self) {
+        let Some(merkle_tree) = self.merkle_tree() else {
+            return;
+        };
+        let merkle_tree = serde_cbor::to_vec(merkle_tree)
+            .expect("Unreachable: Store types always serialize to CBOR.");
+        let store_id = self.store_id();
+        if let Err(err) = self
+            .storage
+            .write_merkle_tree(store_id.as_ref(), &merkle_tree)
+        {
+            error!("Failed to persist store merkle tree: {err}");
+        }
+    }
+
+    /// Persist all of the store's initial state blocks that we have.
+    fn persist_initial_state(&mut self) {
+        let store_id = self.store_id();
+        match &self.state_machine {
+            StateMachine::DownloadingMetadata { .. } | StateMachine::DownloadingMerkle { .. } => {}
+            StateMachine::DownloadingInitialState { initial_state, .. } => {
+                for (i, block) in initial_state.iter().enumerate() {
+                    if let Some(block) = block {
+                        persist_initial_state_block(
+                            self.storage.as_mut(),
+                            store_id.as_ref(),
+                            i as u64,
+                            block,
+                        );
+                    }
+                }
+            }
+            StateMachine::Syncing { initial_state, .. } => {
+                for (i, block) in initial_state.chunks(BLOCK_SIZE as usize).enumerate() {
+                    persist_initial_state_block(
+                        self.storage.as_mut(),
+                        store_id.as_ref(),
+                        i as u64,
+                        block,
+                    );
+                }
+            }
         }
     }
 
@@ -263,6 +531,15 @@ impl<
             }); // , ecg_status});
     }
 
+    /// Forget a peer that disconnected, along with any requests it is waiting on.
+    fn remove_peer(&mut self, peer: &DeviceId) {
+        self.peers.remove(peer);
+        self.metadata_subscribers.remove(peer);
+        self.merkle_subscribers.remove(peer);
+        self.block_subscribers.remove(peer);
+        self.ecg_subscribers.remove(peer);
+    }
+
     /// Helper to update a known peer to initializing.
     fn update_peer_to_initializing<A>(
         &mut self,
@@ -272,11 +549,8 @@ impl<
         A: Debug,
     {
         let Some(info) = self.peers.get_mut(peer) else {
-            error!(
-                "Invariant violated. Attempted to initialize an unknown peer: {}",
-                peer
-            );
-            panic!();
+            debug!("Ignoring initialization of an unknown peer: {peer}");
+            return;
         };
         let status = direction_lambda(info);
         if status.is_known() {
@@ -306,12 +580,10 @@ impl<
     ) where
         A: Debug,
     {
+        // The peer may have disconnected before its sync miniprotocol registered.
         let Some(info) = self.peers.get_mut(peer) else {
-            error!(
-                "Invariant violated. Attempted to initialize an unknown peer: {}",
-                peer
-            );
-            panic!();
+            debug!("Ignoring sync registration of an unknown peer: {peer}");
+            return;
         };
         let status = direction_lambda(info);
         match status {
@@ -347,13 +619,37 @@ impl<
         self.update_peer_to_syncing(peer, |info| &mut info.outgoing_status, sender);
     }
 
+    /// Start syncing with a peer, as if our sync miniprotocol with them had started. Returns the
+    /// channel that sync requests to the peer are sent on.
+    #[cfg(test)]
+    pub(crate) fn start_syncing_with(
+        &mut self,
+        peer: DeviceId,
+    ) -> UnboundedReceiver<StoreSyncCommand<Header::HeaderId, Header>> {
+        let (sender_peer, recv_peer) = mpsc::unbounded_channel();
+        self.insert_known_peer(peer);
+        self.update_peer_to_initializing_outgoing(&peer);
+        self.update_peer_to_syncing_outgoing(
+            &peer,
+            OutgoingPeerStatus {
+                sender_peer,
+                is_outstanding: true,
+            },
+        );
+        recv_peer
+    }
+
+    /// The key that the store's root ECG nodes are encrypted with, if we have it.
+    #[cfg(test)]
+    pub(crate) fn initial_key(&self) -> Option<&StoreKey> {
+        self.keys.initial()
+    }
+
     fn update_outgoing_peer_to_ready(&mut self, peer: &DeviceId) {
+        // Responses can arrive after the peer disconnected.
         let Some(info) = self.peers.get_mut(peer) else {
-            error!(
-                "Invariant violated. Attempted to update an unknown peer: {}",
-                peer
-            );
-            panic!();
+            debug!("Ignoring a response from an unknown peer: {peer}");
+            return;
         };
         match info.outgoing_status {
             PeerStatus::Initializing => {
@@ -385,7 +681,11 @@ impl<
 
             // Mark as outstanding.
             s.is_outstanding = true;
-            s.sender_peer.send(message).expect("TODO");
+            if s.sender_peer.send(message).is_err() {
+                // The sync with the peer ended (the peer closed the stream or disconnected).
+                debug!("Failed to send sync request to peer.");
+                i.outgoing_status = PeerStatus::Known;
+            }
         }
 
         // Get peers (of this store) without outstanding requests.
@@ -482,13 +782,21 @@ impl<
         peer: DeviceId,
         response_chan: Sender<HandlePeerResponse<MetadataHeader<Hash>>>,
     ) {
-        if let Some(metadata) = self.metadata() {
+        if !self.peer_can_sync(&peer) {
+            let _ = response_chan.send(HandlePeerResponse::Reject);
+        } else if let Some(metadata) = self.metadata() {
             // We have the metadata so share it with the peer.
-            response_chan.send(Ok(*metadata)).expect("TODO");
+            let _ = response_chan.send(HandlePeerResponse::Response(*metadata));
         } else {
             // We don't have the metadata so tell them to wait.
             let (send_chan, recv_chan) = oneshot::channel();
-            response_chan.send(Err(recv_chan)).expect("TODO");
+            if response_chan
+                .send(HandlePeerResponse::Wait(recv_chan))
+                .is_err()
+            {
+                // The peer disconnected.
+                return;
+            }
 
             // Register the wait channel.
             self.metadata_subscribers.insert(peer, send_chan); // JP: Safe to drop old one?
@@ -502,17 +810,27 @@ impl<
         response_chan: Sender<HandlePeerResponse<Vec<Hash>>>,
     ) {
         debug!("Received merkle peer request for node_ids: {node_ids:?}");
+        if !self.peer_can_sync(&peer) {
+            let _ = response_chan.send(HandlePeerResponse::Reject);
+            return;
+        }
         let hashes = self
             .merkle_tree()
             .map(|merkle_tree| handle_merkle_peer_request_helper(merkle_tree, &node_ids));
 
         if let Some(node_hashes) = hashes {
             // We have the hashes so share it with the peer.
-            response_chan.send(Ok(node_hashes)).expect("TODO");
+            let _ = response_chan.send(HandlePeerResponse::Response(node_hashes));
         } else {
             // We don't have the hashes so tell them to wait.
             let (send_chan, recv_chan) = oneshot::channel();
-            response_chan.send(Err(recv_chan)).expect("TODO");
+            if response_chan
+                .send(HandlePeerResponse::Wait(recv_chan))
+                .is_err()
+            {
+                // The peer disconnected.
+                return;
+            }
 
             // Register the wait channel.
             self.merkle_subscribers.insert(peer, (node_ids, send_chan)); // JP: Safe to drop old one?
@@ -525,15 +843,25 @@ impl<
         block_ids: Vec<Range<u64>>,
         response_chan: Sender<HandlePeerResponse<Vec<Option<Vec<u8>>>>>,
     ) {
+        if !self.peer_can_sync(&peer) {
+            let _ = response_chan.send(HandlePeerResponse::Reject);
+            return;
+        }
         let blocks = handle_block_peer_request_helper(&self.state_machine, &block_ids);
 
         if let Some(blocks) = blocks {
             // We have the blocks so share it with the peer.
-            response_chan.send(Ok(blocks)).expect("TODO");
+            let _ = response_chan.send(HandlePeerResponse::Response(blocks));
         } else {
             // We don't have the blocks so tell them to wait.
             let (send_chan, recv_chan) = oneshot::channel();
-            response_chan.send(Err(recv_chan)).expect("TODO");
+            if response_chan
+                .send(HandlePeerResponse::Wait(recv_chan))
+                .is_err()
+            {
+                // The peer disconnected.
+                return;
+            }
 
             // Register the wait channel.
             self.block_subscribers.insert(peer, (block_ids, send_chan)); // JP: Safe to drop old one?
@@ -544,8 +872,15 @@ impl<
         &mut self,
         peer: DeviceId,
         tips: Option<BTreeSet<Header::HeaderId>>,
-        response_chan: oneshot::Sender<ecg::UntypedState<Header::HeaderId, Header>>,
+        response_chan: ECGSubscriber<Header>,
     ) {
+        // Subscribers are checked again once we're syncing, so only reject peers we know can't
+        // sync the store.
+        if self.acl().is_some_and(|acl| !acl.can_sync(&peer)) {
+            let _ = response_chan.send(None);
+            return;
+        }
+
         // Respond immediately if peer thread is stale (or they requested it immediately with None).
         if let StateMachine::Syncing { ecg_state, .. } = &self.state_machine {
             let respond_immediately = if let Some(tips) = tips {
@@ -558,7 +893,7 @@ impl<
 
             if respond_immediately {
                 debug!("Responding immediately with ECG state.");
-                response_chan.send(ecg_state.state.clone()).expect("TODO");
+                let _ = response_chan.send(Some(ecg_state.state.clone()));
 
                 return;
             }
@@ -583,7 +918,7 @@ impl<
         &mut self,
         peer: DeviceId,
         metadata: MetadataHeader<Hash>,
-        listeners: &[UnboundedSender<StateUpdate<Header, T>>],
+        listeners: &mut Vec<Listener<Header, T>>,
     ) where
         T: for<'d> Deserialize<'d>,
     {
@@ -593,8 +928,7 @@ impl<
         self.update_outgoing_peer_to_ready(&peer);
 
         // Validate metadata.
-        let is_valid = metadata.validate_store_id(self.store_id());
-        warn!("TODO: Validate signature.");
+        let is_valid = metadata.validate_store_id(self.store_id()) && metadata.validate_signature();
         warn!("TODO: Validate type id.");
 
         if !is_valid {
@@ -612,13 +946,14 @@ impl<
             metadata,
         };
         debug!("Updated state machine"); // : {:?}", self.state_machine);
+        self.persist_metadata();
 
         // Send metadata to any peers that are waiting.
         let subs = std::mem::take(&mut self.metadata_subscribers);
         for (sub_peer, sub) in subs {
             let peer_knows = sub_peer == peer;
             let msg = if peer_knows { None } else { Some(metadata) };
-            sub.send(msg).expect("TODO");
+            let _ = sub.send(msg);
         }
 
         // If there's only one block, we already know the entire merkle tree so move onto downloading blocks.
@@ -649,12 +984,152 @@ impl<
         }
     }
 
+    /// The tips of the store's ECG, once we've downloaded it.
+    fn ecg_tips(&self) -> Option<&BTreeSet<Header::HeaderId>> {
+        match &self.state_machine {
+            StateMachine::Syncing { ecg_state, .. } => Some(ecg_state.tips()),
+            _ => None,
+        }
+    }
+
+    /// The store's current ACL. We only know it once we've downloaded the store's ECG.
+    fn acl(&self) -> Option<Acl> {
+        match &self.state_machine {
+            StateMachine::Syncing {
+                metadata,
+                ecg_state,
+                ..
+            } => Some(Acl::at(metadata.owner, ecg_state, ecg_state.tips())),
+            _ => None,
+        }
+    }
+
+    /// Whether we may share the store with the peer. We can't tell who may sync the store until
+    /// we've downloaded its ECG, so we don't share anything until then.
+    fn peer_can_sync(&self, peer: &DeviceId) -> bool {
+        self.acl().is_some_and(|acl| acl.can_sync(peer))
+    }
+
+    /// Add a key that was shared out of band. Returns whether the key was accepted, which it isn't
+    /// if it isn't one of the store's keys. Keys for stores that are still downloading are
+    /// accepted, and checked once the store is downloaded.
+    fn set_key<OT>(&mut self, key: StoreKey, listeners: &mut Vec<Listener<Header, T>>) -> bool
+    where
+        OT: OdysseyType<ECGHeader = Header>,
+        T: CRDT<Time = OT::Time> + for<'d> Deserialize<'d>,
+        OT::ECGBody<T>: for<'d> Deserialize<'d>
+            + ECGBody<
+                T::Op,
+                <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+                Header = OT::ECGHeader,
+            >,
+        T::Op: ConcretizeTime<<Header as ECGHeader>::HeaderId>,
+    {
+        let StateMachine::Syncing {
+            initial_state,
+            ecg_state,
+            ..
+        } = &self.state_machine
+        else {
+            self.pending_key = Some(key);
+            return true;
+        };
+        if !self.keys.insert_shared(key, ecg_state, initial_state) {
+            warn!("Ignoring key that isn't one of the store's keys");
+            return false;
+        }
+        // The key may unlock older epochs.
+        self.keys.update(&self.identity, ecg_state);
+        self.persist_keys();
+        self.rebuild_decrypted_state::<OT>(listeners);
+        true
+    }
+
+    /// Learn the keys that the ECG shares with this device, and find the epoch of a pending key.
+    /// Returns whether we learned any keys.
+    fn learn_keys(&mut self) -> bool {
+        let StateMachine::Syncing {
+            initial_state,
+            ecg_state,
+            ..
+        } = &self.state_machine
+        else {
+            return false;
+        };
+        let mut learned = false;
+        if let Some(key) = self.pending_key.take() {
+            if self
+                .keys
+                .insert_shared(key.clone(), ecg_state, initial_state)
+            {
+                learned = true;
+            } else {
+                self.pending_key = Some(key);
+            }
+        }
+        self.keys.update(&self.identity, ecg_state) || learned
+    }
+
+    /// Rebuild the decrypted state from the initial state and every ECG node that we have the key
+    /// of, and send it to listeners. This is needed when we learn keys, since nodes we couldn't
+    /// decrypt were skipped. Does nothing until we have the store's initial key.
+    fn rebuild_decrypted_state<OT>(&mut self, listeners: &mut Vec<Listener<Header, T>>)
+    where
+        OT: OdysseyType<ECGHeader = Header>,
+        T: CRDT<Time = OT::Time> + for<'d> Deserialize<'d>,
+        OT::ECGBody<T>: for<'d> Deserialize<'d>
+            + ECGBody<
+                T::Op,
+                <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+                Header = OT::ECGHeader,
+            >,
+        T::Op: ConcretizeTime<<Header as ECGHeader>::HeaderId>,
+    {
+        let StateMachine::Syncing {
+            metadata,
+            initial_state,
+            ecg_state,
+            decrypted_state,
+            ..
+        } = &mut self.state_machine
+        else {
+            return;
+        };
+        let Some(initial_key) = self.keys.initial() else {
+            return;
+        };
+        let Some(initial_state) = decrypt_initial_state::<T>(initial_key, initial_state) else {
+            warn!("Failed to decrypt the initial state with the store's key");
+            return;
+        };
+
+        // Checkpoints may have skipped nodes, so start over from the initial state.
+        let mut state = DecryptedState::new(initial_state);
+        state.latest_state =
+            apply_from_checkpoint::<OT, T>(&state.checkpoints[0], &self.keys, ecg_state, |_| true);
+        state.latest_headers = ecg_state.tips().clone();
+        state.nodes_since_checkpoint = ecg_state.log().count() as u64;
+
+        let acl = Acl::at(metadata.owner, ecg_state, ecg_state.tips());
+        // The ECG didn't change, so ECG subscribers don't need an update.
+        update_listeners(
+            &mut BTreeMap::new(),
+            &acl,
+            listeners,
+            Some(&state.latest_state),
+            ecg_state,
+            None,
+            None,
+        );
+        *decrypted_state = Some(state);
+    }
+
     fn handle_received_merkle_hashes(
         &mut self,
         peer: DeviceId,
         node_ids: Vec<Range<u64>>,
         their_node_hashes: Vec<Hash>,
-        listeners: &[UnboundedSender<StateUpdate<Header, T>>],
+        listeners: &mut Vec<Listener<Header, T>>,
     ) where
         T: for<'d> Deserialize<'d>,
     {
@@ -699,7 +1174,7 @@ impl<
         peer: DeviceId,
         block_ids: Vec<Range<u64>>,
         their_blocks: Vec<Option<Vec<u8>>>,
-        listeners: &[UnboundedSender<StateUpdate<Header, T>>],
+        listeners: &mut Vec<Listener<Header, T>>,
     ) where
         T: for<'d> Deserialize<'d>,
     {
@@ -713,6 +1188,7 @@ impl<
         }
 
         // Update state.
+        let store_id = self.store_id();
         let (initial_state, merkle_tree) = if let StateMachine::DownloadingInitialState {
             ref mut initial_state,
             ref merkle_tree,
@@ -733,6 +1209,12 @@ impl<
                     if block.is_none() {
                         // TODO: Credit peer with this block. If not valid, penalize peer.
                         if merkle_tree.validate_chunk(i, &their_block) {
+                            persist_initial_state_block(
+                                self.storage.as_mut(),
+                                store_id.as_ref(),
+                                i,
+                                &their_block,
+                            );
                             *block = Some(their_block);
                         } else {
                             warn!("TODO: Peer sent us an invalid block");
@@ -750,12 +1232,13 @@ impl<
         self.update_state_to_syncing(peer, listeners);
     }
 
-    fn handle_received_ecg_operations<OT>(
+    pub(crate) fn handle_received_ecg_operations<OT>(
         &mut self,
         peer: DeviceId,
         operations: Vec<(Header, RawECGBody)>,
-        listeners: &[UnboundedSender<StateUpdate<Header, T>>],
-    ) where
+        listeners: &mut Vec<Listener<Header, T>>,
+    ) -> Vec<(Header::HeaderId, ValidationError)>
+    where
         OT: OdysseyType<ECGHeader = Header>,
         T: CRDT<Time = OT::Time> + Debug,
         OT::ECGBody<T>: for<'d> Deserialize<'d>
@@ -767,13 +1250,15 @@ impl<
             >, // ECGBody<T, Header = OT::ECGHeader> +
         // T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
         T::Op: ConcretizeTime<<Header as ECGHeader>::HeaderId>,
+        T: Serialize + for<'d> Deserialize<'d>,
+        Header::HeaderId: Serialize,
     {
         // Mark peer as ready.
         self.update_outgoing_peer_to_ready(&peer);
 
-        warn!("TODO: Validate operations from peer");
-
+        let store_id = self.store_id();
         let StateMachine::Syncing {
+            ref metadata,
             ref mut ecg_state,
             ref mut decrypted_state,
             ..
@@ -781,38 +1266,124 @@ impl<
         else {
             unreachable!("We must be syncing");
         };
+        let owner = metadata.owner;
+        // Bodies are only decrypted (and validated) if we can read the store.
+        let is_readable = decrypted_state.is_some();
+        let keys = &mut self.keys;
+        let mut learned_keys = false;
+
+        // Parse and apply all operations. Nodes may arrive before their parents, so hold on to
+        // them until a pass doesn't apply anything new.
+        let mut applied = vec![];
+        let mut rejected = vec![];
+        let mut waiting = operations;
+        loop {
+            let waiting_count = waiting.len();
+            waiting.retain_mut(|(header, raw_operations)| {
+                let is_ready = header
+                    .get_parent_ids()
+                    .iter()
+                    .all(|parent_id| ecg_state.contains(parent_id));
+                if !is_ready {
+                    return true;
+                }
 
-        // Parse and apply all operations.
-        operations.into_iter().for_each(|(header, raw_operations)| {
-            let operations = serde_cbor::from_slice(&raw_operations)
-                .expect("TODO: Peer gave us improperly serialized operations");
-            debug!("Applying operations {operations:?}");
-
-            // TODO: Get rid of this clone.
-            let success = ecg_state.insert_header(header.clone(), raw_operations);
-            if !success {
-                debug!("Failed to insert operations from peer.");
-            } else {
-                apply_operations::<OT, _>(decrypted_state, ecg_state, &header, operations);
+                let raw_operations = std::mem::take(raw_operations);
+                let operations = match validate_ecg_node::<OT, T>(
+                    header,
+                    &raw_operations,
+                    is_readable.then_some(&*keys),
+                )
+                .and_then(|operations| {
+                    if header.epoch() != epoch_at(ecg_state, header.get_parent_ids()) {
+                        return Err(ValidationError::InvalidEpoch);
+                    }
+                    Acl::at(owner, ecg_state, header.get_parent_ids()).authorize(header)?;
+                    Ok(operations)
+                }) {
+                    Ok(operations) => operations,
+                    Err(err) => {
+                        rejected.push((header.get_header_id(), err));
+                        return false;
+                    }
+                };
+                debug!("Applying operations {operations:?}");
+
+                // TODO: Get rid of this clone.
+                let success = ecg_state.insert_header(header.clone(), raw_operations);
+                if !success {
+                    debug!("Failed to insert operations from peer.");
+                } else {
+                    persist_ecg_node(
+                        self.storage.as_mut(),
+                        store_id.as_ref(),
+                        ecg_state,
+                        &header.get_header_id(),
+                    );
+                    if let (Some(decrypted_state), Some(operations)) =
+                        (decrypted_state.as_mut(), operations)
+                    {
+                        apply_operations::<OT, _>(
+                            decrypted_state,
+                            keys,
+                            ecg_state,
+                            header,
+                            operations,
+                        );
+                    }
+                    // Learn new keys right away, so that the node's children can be decrypted.
+                    if !header.wrapped_keys().is_empty() || header.rotated_key().is_some() {
+                        learned_keys |= keys.update(&self.identity, ecg_state);
+                    }
+                    applied.push(header.get_header_id());
+                }
+                false
+            });
+            if waiting.is_empty() || waiting.len() == waiting_count {
+                break;
             }
-        });
-        debug!("New decrypted state {:?}", decrypted_state.latest_state);
+        }
+        rejected.extend(
+            waiting
+                .iter()
+                .map(|(header, _)| (header.get_header_id(), ValidationError::UnknownParents)),
+        );
+
+        let latest_state = decrypted_state.as_ref().map(|d| &d.latest_state);
+        debug!("New decrypted state {:?}", latest_state);
 
         // Update listeners (except peer).
+        let ecg_state = &*ecg_state;
+        let keys = &*keys;
+        let acl = Acl::at(owner, ecg_state, ecg_state.tips());
+        let new_operations = is_readable
+            .then_some(move || concretized_operations::<OT, T>(keys, ecg_state, &applied));
         update_listeners(
             &mut self.ecg_subscribers,
+            &acl,
             listeners,
-            &decrypted_state.latest_state,
+            latest_state,
             ecg_state,
+            new_operations.as_ref().map(|f| f as &dyn Fn() -> _),
             Some(peer),
         );
+
+        self.checkpoint_if_needed();
+
+        // Start reading nodes (or the whole store) that we learned the keys of.
+        if self.learn_keys() || learned_keys {
+            self.persist_keys();
+            self.rebuild_decrypted_state::<OT>(listeners);
+        }
+
+        rejected
     }
 
     // Precondition: State is StateMachine::DownloadingMerkle.
     fn update_state_to_downloading_initial_state(
         &mut self,
         peer: DeviceId,
-        listeners: &[UnboundedSender<StateUpdate<Header, T>>],
+        listeners: &mut Vec<Listener<Header, T>>,
     ) where
         T: for<'d> Deserialize<'d>,
     {
@@ -848,6 +1419,7 @@ impl<
             }
             _ => unreachable!("We already checked that we're downloading merkle"),
         };
+        self.persist_merkle_tree();
 
         // Send node hashes to any peers that are waiting.
         let subs = std::mem::take(&mut self.merkle_subscribers);
@@ -861,7 +1433,7 @@ impl<
             } else {
                 Some(handle_merkle_peer_request_helper(merkle_tree, &node_ids))
             };
-            sub.send(msg).expect("TODO");
+            let _ = sub.send(msg);
         }
 
         // If there's no initial state, we already know all the blocks so move onto syncing.
@@ -870,13 +1442,12 @@ impl<
         }
     }
 
-    fn update_state_to_syncing(
-        &mut self,
-        peer: DeviceId,
-        listeners: &[UnboundedSender<StateUpdate<Header, T>>],
-    ) where
+    fn update_state_to_syncing(&mut self, peer: DeviceId, listeners: &mut Vec<Listener<Header, T>>)
+    where
         T: for<'d> Deserialize<'d>,
     {
+        let keys = &mut self.keys;
+        let pending_key = &mut self.pending_key;
         replace_with_or_abort(&mut self.state_machine, |sm| match sm {
             StateMachine::DownloadingInitialState {
                 metadata,
@@ -889,13 +1460,20 @@ impl<
                     .flatten()
                     .flatten()
                     .collect::<Vec<u8>>();
-                let Ok(latest_state) = serde_cbor::de::from_slice::<T>(&initial_state) else {
-                    todo!("TODO: The store is invalid. Initial state does not parse.");
-                };
-                let decrypted_state = DecryptedState {
-                    latest_state,
-                    latest_headers: BTreeSet::new(),
-                };
+                // The ECG is still empty, so we can only tell if a pending key is the initial key.
+                if let Some(key) = pending_key.take() {
+                    if !keys.insert_shared(key.clone(), &ecg_state, &initial_state) {
+                        *pending_key = Some(key);
+                    }
+                }
+                // Without the initial key, we only relay the store.
+                let decrypted_state = keys.initial().and_then(|key| {
+                    let latest_state = decrypt_initial_state::<T>(key, &initial_state);
+                    if latest_state.is_none() {
+                        warn!("Failed to decrypt the initial state with the store's key");
+                    }
+                    latest_state.map(DecryptedState::new)
+                });
                 StateMachine::Syncing {
                     metadata,
                     merkle_tree,
@@ -906,9 +1484,12 @@ impl<
             }
             _ => unreachable!("We already checked that we're downloading the initial state"),
         });
+        // Keys could only be checked once we had the initial state.
+        self.persist_keys();
 
         // Update listeners.
         let StateMachine::Syncing {
+            metadata,
             ecg_state,
             decrypted_state,
             ..
@@ -916,11 +1497,15 @@ impl<
         else {
             unreachable!("We just set our state to syncing")
         };
+        // The ECG is empty, so the ACL is the initial one.
+        let acl = Acl::new(metadata.owner);
         update_listeners(
             &mut self.ecg_subscribers,
+            &acl,
             listeners,
-            &decrypted_state.latest_state,
+            decrypted_state.as_ref().map(|d| &d.latest_state),
             ecg_state,
+            None,
             Some(peer),
         );
 
@@ -930,36 +1515,284 @@ impl<
             // JP: Do we need to keep sub_peer here?
             let msg = handle_block_peer_request_helper(&self.state_machine, &block_ids)
                 .expect("Unreachable: We just set our state to syncing");
-            sub.send(Some(msg)).expect("TODO");
+            let _ = sub.send(Some(msg));
         }
     }
 }
 
+/// Deserialize a value that was persisted to storage.
+fn decode_stored<A: for<'d> Deserialize<'d>>(bytes: &[u8], what: &str) -> Result<A, StorageError> {
+    serde_cbor::from_slice(bytes)
+        .map_err(|err| StorageError::Corrupted(format!("Invalid {what}: {err}")))
+}
+
+/// Load as much of the store's state machine from storage as possible.
+fn load_state_machine<OT, StoreId, T, Hash>(
+    store_id: StoreId,
+    storage: &(dyn Storage + Send),
+    keys: &StoreKeys<<OT::ECGHeader as ECGHeader>::HeaderId>,
+) -> Result<StateMachine<StoreId, OT::ECGHeader, T, Hash>, StorageError>
+where
+    OT: OdysseyType,
+    StoreId: Copy + Eq + AsRef<[u8]>,
+    T: CRDT<Time = OT::Time> + Clone + for<'d> Deserialize<'d>,
+    Hash: util::Hash + Debug + Into<StoreId> + Serialize + for<'d> Deserialize<'d>,
+    OT::ECGHeader: Clone,
+    OT::ECGBody<T>: for<'d> Deserialize<'d>
+        + ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = OT::ECGHeader,
+        >,
+    T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+{
+    // Load and validate the metadata.
+    let Some(metadata) = storage.read_metadata(store_id.as_ref())? else {
+        return Ok(StateMachine::DownloadingMetadata { store_id });
+    };
+    let metadata: MetadataHeader<Hash> = decode_stored(&metadata, "metadata")?;
+    if !metadata.validate_store_id(store_id) {
+        return Err(StorageError::Corrupted(
+            "Metadata does not match the store id".into(),
+        ));
+    }
+    if !metadata.validate_signature() {
+        return Err(StorageError::Corrupted(
+            "Metadata is not signed by its owner".into(),
+        ));
+    }
+
+    // Load the merkle tree. If there's only one block, the merkle tree is just the root.
+    let merkle_tree = match storage.read_merkle_tree(store_id.as_ref())? {
+        Some(merkle_tree) => decode_stored(&merkle_tree, "merkle tree")?,
+        None => {
+            let partial_merkle_tree =
+                MerkleTree::new_with_capacity(metadata.merkle_root, metadata.block_count());
+            match partial_merkle_tree.try_complete() {
+                Some(merkle_tree) => merkle_tree,
+                None => {
+                    return Ok(StateMachine::DownloadingMerkle {
+                        metadata,
+                        partial_merkle_tree,
+                    })
+                }
+            }
+        }
+    };
+    let merkle_tree: MerkleTree<Hash> = merkle_tree;
+    if merkle_tree.merkle_root() != metadata.merkle_root {
+        return Err(StorageError::Corrupted(
+            "Merkle tree does not match the metadata".into(),
+        ));
+    }
+
+    // Load the initial state blocks.
+    let mut initial_state = vec![];
+    for i in 0..metadata.block_count() {
+        let block = storage
+            .read_initial_state_block(store_id.as_ref(), i)?
+            .filter(|block| merkle_tree.validate_chunk(i, block));
+        initial_state.push(block);
+    }
+    if initial_state.iter().any(|b| b.is_none()) {
+        return Ok(StateMachine::DownloadingInitialState {
+            metadata,
+            merkle_tree,
+            initial_state,
+        });
+    }
+    let initial_state: Vec<u8> = initial_state.into_iter().flatten().flatten().collect();
+
+    let mut ecg_nodes = vec![];
+    for (header, raw_operations) in storage.read_ecg_nodes(store_id.as_ref())? {
+        let header: OT::ECGHeader = decode_stored(&header, "ECG header")?;
+        ecg_nodes.push((header, raw_operations));
+    }
+
+    // Without the initial key, we only relay the store.
+    let Some(initial_key) = keys.initial() else {
+        let mut ecg_state = ecg::State::new();
+        for (header, raw_operations) in ecg_nodes {
+            if !ecg_state.insert_header(header, raw_operations) {
+                warn!("Skipping persisted ECG node that could not be inserted.");
+            }
+        }
+        return Ok(StateMachine::Syncing {
+            metadata,
+            merkle_tree,
+            initial_state,
+            ecg_state,
+            decrypted_state: None,
+        });
+    };
+    let latest_state: T = decrypt_initial_state(initial_key, &initial_state)
+        .ok_or_else(|| StorageError::Corrupted("Invalid initial state".into()))?;
+    let initial_checkpoint = Checkpoint {
+        headers: BTreeSet::new(),
+        state: latest_state,
+    };
+
+    // Start from the latest checkpoint if we have every ECG node it includes.
+    let checkpoint = match storage.read_checkpoint(store_id.as_ref())? {
+        Some(checkpoint) => {
+            let checkpoint: Checkpoint<<OT::ECGHeader as ECGHeader>::HeaderId, T> =
+                decode_stored(&checkpoint, "checkpoint")?;
+            // Only the graph is needed to find which nodes the checkpoint includes.
+            let mut dag = ecg::State::<OT::ECGHeader, T>::new();
+            for (header, _) in &ecg_nodes {
+                dag.insert_header(header.clone(), vec![]);
+            }
+            if checkpoint.headers.iter().all(|h| dag.contains(h)) {
+                let included = dag.ancestors_of(&checkpoint.headers);
+                Some((checkpoint, included))
+            } else {
+                warn!("Ignoring checkpoint that includes ECG nodes that were not persisted.");
+                None
+            }
+        }
+        None => None,
+    };
+    let (checkpoint, included) = checkpoint.unzip();
+    let included = included.unwrap_or_default();
+    let mut decrypted_state = DecryptedState::from_checkpoint(initial_checkpoint, checkpoint);
+
+    // Replay the ECG nodes that aren't in the checkpoint.
+    let mut ecg_state = ecg::State::new();
+    for (header, raw_operations) in ecg_nodes {
+        let header_id = header.get_header_id();
+        let operations = decrypt_body::<OT, T>(keys, &header, &raw_operations);
+        if !ecg_state.insert_header(header.clone(), raw_operations) {
+            warn!("Skipping persisted ECG node that could not be inserted.");
+            continue;
+        }
+        if included.contains(&header_id) {
+            continue;
+        }
+        match operations {
+            Ok(operations) => apply_operations::<OT, _>(
+                &mut decrypted_state,
+                keys,
+                &ecg_state,
+                &header,
+                operations,
+            ),
+            Err(err) => warn!("Skipping persisted ECG node {header_id:?}: {err}"),
+        }
+    }
+
+    Ok(StateMachine::Syncing {
+        metadata,
+        merkle_tree,
+        initial_state,
+        ecg_state,
+        decrypted_state: Some(decrypted_state),
+    })
+}
+
+/// Persist an initial state block, logging any failure.
+fn persist_initial_state_block(
+    storage: &mut dyn Storage,
+    store_id: &[u8],
+    index: u64,
+    block: &[u8],
+) {
+    if let Err(err) = storage.write_initial_state_block(store_id, index, block) {
+        error!("Failed to persist initial state block {index}: {err}");
+    }
+}
+
+/// Persist an ECG node that was just inserted into the ECG state, logging any failure.
+fn persist_ecg_node<Header: ECGHeader + Serialize, T>(
+    storage: &mut dyn Storage,
+    store_id: &[u8],
+    ecg_state: &ecg::State<Header, T>,
+    header_id: &Header::HeaderId,
+) {
+    let Some(node) = ecg_state.state.get_node(header_id) else {
+        error!("Attempted to persist an ECG node that does not exist.");
+        return;
+    };
+    let header = serde_cbor::to_vec(node.header())
+        .expect("Unreachable: Store types always serialize to CBOR.");
+    if let Err(err) = storage.append_ecg_node(store_id, &header, node.operations()) {
+        error!("Failed to persist ECG node: {err}");
+    }
+}
+
+/// Headers of applied ECG nodes, each with its concretized operations.
+type AppliedOperations<Header, T> = Vec<(Header, Vec<<T as CRDT>::Op>)>;
+
+/// Send the updated state to listeners and ECG subscribers. ECG subscribers that can't sync the
+/// store with the current `acl` are rejected.
+/// `new_operations` computes the operations that were just applied. If it is `None`, every
+/// listener is sent a snapshot, including ones that subscribed to operations. If we can't decrypt
+/// the store (`latest_state` is `None`), only ECG subscribers are updated.
 fn update_listeners<Header: ecg::ECGHeader + Clone + Debug, T: CRDT + Clone>(
-    ecg_subscribers: &mut BTreeMap<
-        DeviceId,
-        oneshot::Sender<ecg::UntypedState<Header::HeaderId, Header>>,
-    >,
-    listeners: &[UnboundedSender<StateUpdate<Header, T>>],
-    latest_state: &T,
+    ecg_subscribers: &mut BTreeMap<DeviceId, ECGSubscriber<Header>>,
+    acl: &Acl,
+    listeners: &mut Vec<Listener<Header, T>>,
+    latest_state: Option<&T>,
     ecg_state: &ecg::State<Header, T>,
+    new_operations: Option<&dyn Fn() -> AppliedOperations<Header, T>>,
     from_peer: Option<DeviceId>,
 ) {
-    for l in listeners {
-        let snapshot: StateUpdate<Header, T> = StateUpdate::Snapshot {
-            snapshot: latest_state.clone(),
-            ecg_state: ecg_state.clone(),
-        };
-        l.send(snapshot).expect("TODO");
+    if let Some(latest_state) = latest_state {
+        // Updates are built once and shared by every listener.
+        let has_operations_listeners = listeners
+            .iter()
+            .any(|l| l.mode == SubscriptionMode::Operations);
+        let operations = new_operations
+            .filter(|_| has_operations_listeners)
+            .map(|new_operations| Arc::new(new_operations()));
+        let mut snapshot = None;
+        listeners.retain(|l| {
+            let update = match (l.mode, &operations) {
+                (SubscriptionMode::Operations, Some(operations)) => {
+                    if operations.is_empty() {
+                        return true;
+                    }
+                    StateUpdate::Operations {
+                        operations: operations.clone(),
+                    }
+                }
+                _ => {
+                    let (snapshot, ecg_state) = snapshot.get_or_insert_with(|| {
+                        (Arc::new(latest_state.clone()), Arc::new(ecg_state.clone()))
+                    });
+                    StateUpdate::Snapshot {
+                        snapshot: snapshot.clone(),
+                        ecg_state: ecg_state.clone(),
+                    }
+                }
+            };
+            match l.send_state.send(update) {
+                Ok(()) => true,
+                Err(TrySendError::Closed(_)) => {
+                    debug!("Removing state subscriber since its receiver was dropped");
+                    false
+                }
+                // A later snapshot catches the subscriber up, but skipped operations are lost, so
+                // close the subscription instead.
+                Err(TrySendError::Full(_)) => match l.mode {
+                    SubscriptionMode::Snapshots => true,
+                    SubscriptionMode::Operations => {
+                        warn!("Removing operations subscriber since it fell behind");
+                        false
+                    }
+                },
+            }
+        });
     }
 
     // Send updated state to one-time subscribers.
     // warn!("TODO: Do we always want to update ECG subscribers here? Ex: We may not want to when transitioning from downloading to syncing"); JP: Maybe this is ok since our peer_store won't have anything to share and will resubscribe.
     let subs = std::mem::take(ecg_subscribers);
     for (sub_peer, sub) in subs {
+        if !acl.can_sync(&sub_peer) {
+            let _ = sub.send(None);
         // Skip notifying subscriber if they told us about this update.
-        if Some(sub_peer) != from_peer {
-            sub.send(ecg_state.state.clone()).expect("TODO");
+        } else if Some(sub_peer) != from_peer {
+            let _ = sub.send(Some(ecg_state.state.clone()));
         } else {
             warn!("TODO: Add headers that they sent us to their_known.");
             // Need to add back subscriber.
@@ -1007,6 +1840,7 @@ async fn manage_peers<OT: OdysseyType, T: CRDT<Time = OT::Time> + Clone + Send +
 
         // Create closure that spawns task to sync store with peer.
         let send_commands = send_commands.clone();
+        let shared_state = shared_state.clone();
         let spawn_task = Box::new(move |_party, stream_id, sender, receiver| {
             // Create miniprotocol
             // Spawn task that syncs store with peer.
@@ -1021,11 +1855,16 @@ async fn manage_peers<OT: OdysseyType, T: CRDT<Time = OT::Time> + Clone + Send +
                     peer: peer_id,
                     send_peer,
                 };
-                send_commands.send(register_cmd).expect("TODO");
+                // If the store closed, the miniprotocol stops right away.
+                let _ = send_commands.send(register_cmd);
 
                 // Start miniprotocol as server.
                 let mp = StoreSync::<OT::Hash, _, _>::new_server(peer_id, recv_peer, send_commands);
-                run_miniprotocol_async::<_, OT>(mp, false, stream_id, sender, receiver).await;
+                let result =
+                    run_miniprotocol_async::<_, OT>(mp, false, stream_id, sender, receiver).await;
+                if let Err(err) = result {
+                    shared_state.report_peer_error(peer_id, &err);
+                }
 
                 debug!("Store sync with peer (with initiative) exited.")
 
@@ -1050,35 +1889,433 @@ async fn manage_peers<OT: OdysseyType, T: CRDT<Time = OT::Time> + Clone + Send +
             store_id,
             spawn_task,
         };
-        command_chan.send(cmd).expect("TODO");
+        if command_chan.send(cmd).is_err() {
+            // The peer disconnected since we looked it up.
+            store.remove_peer(&peer_id);
+        }
+    }
+}
+
+/// The headers and concretized operations of the given ECG nodes, in order. Bodies are decrypted
+/// again from the ECG state, so this is only worth calling if someone wants them.
+fn concretized_operations<OT: OdysseyType, T>(
+    keys: &StoreKeys<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    ecg_state: &ecg::State<OT::ECGHeader, T>,
+    header_ids: &[<OT::ECGHeader as ECGHeader>::HeaderId],
+) -> AppliedOperations<OT::ECGHeader, T>
+where
+    OT::ECGHeader: Clone,
+    T: CRDT<Time = OT::Time>,
+    T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    OT::ECGBody<T>: for<'d> Deserialize<'d>
+        + ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = OT::ECGHeader,
+        >,
+{
+    header_ids
+        .iter()
+        .filter_map(|header_id| {
+            let node = ecg_state.state.get_node(header_id)?;
+            let body = decrypt_body::<OT, T>(keys, node.header(), node.operations()).ok()?;
+            Some((node.header().clone(), body.operations(*header_id).collect()))
+        })
+        .collect()
+}
+
+/// Decrypt and deserialize an ECG body with the key of its header's epoch.
+fn decrypt_body<OT: OdysseyType, T>(
+    keys: &StoreKeys<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    header: &OT::ECGHeader,
+    encrypted_body: &[u8],
+) -> Result<OT::ECGBody<T>, ValidationError>
+where
+    T: CRDT,
+    T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    OT::ECGBody<T>: for<'d> Deserialize<'d>,
+{
+    let body = keys
+        .get(&header.epoch())
+        .ok_or(ValidationError::UndecryptableBody)?
+        .decrypt(encrypted_body)
+        .map_err(|_| ValidationError::UndecryptableBody)?;
+    encoding::from_slice(&body).map_err(|_| ValidationError::MalformedBody)
+}
+
+/// Validate an ECG node received from a peer. Its parents must already be in the ECG state. If we
+/// have the key of the node's epoch, the body is also decrypted and validated. Otherwise we can
+/// only check that the header commits to the encrypted body.
+fn validate_ecg_node<OT: OdysseyType, T>(
+    header: &OT::ECGHeader,
+    encrypted_body: &[u8],
+    keys: Option<&StoreKeys<<OT::ECGHeader as ECGHeader>::HeaderId>>,
+) -> Result<Option<OT::ECGBody<T>>, ValidationError>
+where
+    T: CRDT<Time = OT::Time>,
+    T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    OT::ECGBody<T>: for<'d> Deserialize<'d>
+        + ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = OT::ECGHeader,
+        >,
+{
+    if node_size(header, encrypted_body) > ecg::MAX_NODE_SIZE {
+        return Err(ValidationError::NodeTooLarge);
     }
+    header.validate_header()?;
+    header.validate_encrypted_body(encrypted_body)?;
+    let Some(keys) = keys.filter(|keys| keys.get(&header.epoch()).is_some()) else {
+        return Ok(None);
+    };
+    let body = decrypt_body::<OT, T>(keys, header, encrypted_body)?;
+    body.validate_body(header)?;
+    Ok(Some(body))
+}
+
+/// The size of an ECG node when it's sent to peers.
+fn node_size<Header: Serialize>(header: &Header, encrypted_body: &[u8]) -> usize {
+    serde_cbor::to_vec(header).map_or(usize::MAX, |header| header.len()) + encrypted_body.len()
 }
 
+/// Apply an ECG node that was just inserted into the ECG state. Its operations are applied on top
+/// of the latest state, unless the CRDT's concurrent operations don't commute and the node is
+/// concurrent with nodes that were already applied. Then the state is replayed from the
+/// checkpoint.
 fn apply_operations<OT: OdysseyType, T>(
     decrypted_state: &mut DecryptedState<OT::ECGHeader, T>,
+    keys: &StoreKeys<<OT::ECGHeader as ECGHeader>::HeaderId>,
     ecg_state: &ecg::State<OT::ECGHeader, T>,
     operation_header: &OT::ECGHeader,
     operation_body: OT::ECGBody<T>,
 ) where
-    T: CRDT<Time = OT::Time>,
+    T: CRDT<Time = OT::Time> + Clone,
     // T::Op<CausalTime<T::Time>>: Serialize,
     T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
-    OT::ECGBody<T>: ECGBody<
-        T::Op,
-        <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
-        Header = OT::ECGHeader,
-    >,
+    OT::ECGBody<T>: for<'d> Deserialize<'d>
+        + ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = OT::ECGHeader,
+        >,
 {
+    let header_id = operation_header.get_header_id();
+    let parents = operation_header.get_parent_ids();
+    let needs_replay = !T::CONCURRENT_OPERATIONS_COMMUTE
+        && decrypted_state
+            .latest_headers
+            .iter()
+            .any(|h| !parents.contains(h) && ecg_state.is_ancestor_of(h, &header_id) != Some(true));
+
+    // Update the tips of the applied nodes.
+    for parent_id in parents {
+        decrypted_state.latest_headers.remove(parent_id);
+    }
+    decrypted_state.latest_headers.insert(header_id);
+    decrypted_state.nodes_since_checkpoint += 1;
+
+    if needs_replay {
+        debug!("Replaying operations since {header_id:?} is concurrent with applied operations");
+        replay_operations::<OT, T>(decrypted_state, keys, ecg_state);
+        return;
+    }
+
     let causal_state = OT::to_causal_state(ecg_state);
-    for operation in operation_body.operations(operation_header.get_header_id()) {
+    for operation in operation_body.operations(header_id) {
         replace_with_or_abort(&mut decrypted_state.latest_state, |s| {
             s.apply(causal_state, operation)
         });
     }
 }
 
+/// Rebuild the latest state after a node arrived that is concurrent with applied nodes. Starts
+/// from the newest checkpoint whose nodes still come first in the deterministic topological order,
+/// and applies the rest of the ECG nodes in that order.
+fn replay_operations<OT: OdysseyType, T>(
+    decrypted_state: &mut DecryptedState<OT::ECGHeader, T>,
+    keys: &StoreKeys<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    ecg_state: &ecg::State<OT::ECGHeader, T>,
+) where
+    T: CRDT<Time = OT::Time> + Clone,
+    T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    OT::ECGBody<T>: for<'d> Deserialize<'d>
+        + ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = OT::ECGHeader,
+        >,
+{
+    let checkpoint = newest_prefix_checkpoint(&decrypted_state.checkpoints, ecg_state, |_| true);
+
+    decrypted_state.latest_state =
+        apply_from_checkpoint::<OT, T>(checkpoint, keys, ecg_state, |_| true);
+    decrypted_state.latest_headers = ecg_state.tips().clone();
+}
+
+/// The state at the given heads, obtained by applying exactly the ancestors of the heads (and the
+/// heads themselves). Returns `None` if any of the heads are unknown.
+fn state_at<OT: OdysseyType, T>(
+    decrypted_state: &DecryptedState<OT::ECGHeader, T>,
+    keys: &StoreKeys<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    ecg_state: &ecg::State<OT::ECGHeader, T>,
+    heads: &BTreeSet<<OT::ECGHeader as ECGHeader>::HeaderId>,
+) -> Option<T>
+where
+    T: CRDT<Time = OT::Time> + Clone,
+    T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    OT::ECGBody<T>: for<'d> Deserialize<'d>
+        + ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = OT::ECGHeader,
+        >,
+{
+    if !heads.iter().all(|h| ecg_state.contains(h)) {
+        return None;
+    }
+    if *heads == decrypted_state.latest_headers {
+        return Some(decrypted_state.latest_state.clone());
+    }
+
+    // Start from the newest checkpoint that only includes nodes the heads depend on.
+    let ancestors = ecg_state.ancestors_of(heads);
+    let checkpoint = newest_prefix_checkpoint(&decrypted_state.checkpoints, ecg_state, |h| {
+        ancestors.contains(h)
+    });
+
+    Some(apply_from_checkpoint::<OT, T>(
+        checkpoint,
+        keys,
+        ecg_state,
+        |h| ancestors.contains(h),
+    ))
+}
+
+/// The newest checkpoint whose nodes come first in the deterministic topological order of the ECG
+/// nodes that satisfy `should_apply`. Applying the rest of those nodes to its state in that order
+/// then gives the same state as applying all of them, even if concurrent operations don't commute.
+/// A checkpoint stops being a prefix when a node that is concurrent with its nodes arrives later
+/// and sorts before some of them.
+fn newest_prefix_checkpoint<'a, Header: ECGHeader, T: CRDT>(
+    checkpoints: &'a [Checkpoint<Header::HeaderId, T>],
+    ecg_state: &ecg::State<Header, T>,
+    should_apply: impl Fn(&Header::HeaderId) -> bool,
+) -> &'a Checkpoint<Header::HeaderId, T> {
+    let order: Vec<_> = ecg_state
+        .topological_order()
+        .into_iter()
+        .filter(|h| should_apply(h))
+        .collect();
+    checkpoints
+        .iter()
+        .rev()
+        .find(|c| {
+            if !c.headers.iter().all(|h| ecg_state.contains(h)) {
+                return false;
+            }
+            let included = ecg_state.ancestors_of(&c.headers);
+            included.len() <= order.len()
+                && order[..included.len()].iter().all(|h| included.contains(h))
+        })
+        .expect("Unreachable: The initial checkpoint doesn't include any nodes.")
+}
+
+/// Apply the ECG nodes that the checkpoint doesn't include and that satisfy `should_apply` to the
+/// checkpoint's state, in a deterministic topological order. Nodes that can't be decrypted are
+/// skipped.
+fn apply_from_checkpoint<OT: OdysseyType, T>(
+    checkpoint: &Checkpoint<<OT::ECGHeader as ECGHeader>::HeaderId, T>,
+    keys: &StoreKeys<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    ecg_state: &ecg::State<OT::ECGHeader, T>,
+    should_apply: impl Fn(&<OT::ECGHeader as ECGHeader>::HeaderId) -> bool,
+) -> T
+where
+    T: CRDT<Time = OT::Time> + Clone,
+    T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    OT::ECGBody<T>: for<'d> Deserialize<'d>
+        + ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = OT::ECGHeader,
+        >,
+{
+    let included = ecg_state.ancestors_of(&checkpoint.headers);
+    let mut state = checkpoint.state.clone();
+
+    let causal_state = OT::to_causal_state(ecg_state);
+    for header_id in ecg_state.topological_order() {
+        if included.contains(&header_id) || !should_apply(&header_id) {
+            continue;
+        }
+        let node = ecg_state
+            .state
+            .get_node(&header_id)
+            .expect("Unreachable: The node is in the topological order.");
+        let body = match decrypt_body::<OT, T>(keys, node.header(), node.operations()) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Skipping ECG node {header_id:?}: {err}");
+                continue;
+            }
+        };
+        for operation in body.operations(header_id) {
+            state = state.apply(causal_state, operation);
+        }
+    }
+    state
+}
+
+/// Apply operations created by this device on top of `parents`: create and sign the header,
+/// insert it into the ECG, persist it, update the state, and notify listeners. The store's key is
+/// wrapped for devices that `acl_changes` lets read the store. If `rotate_key` is set, the header
+/// starts a new key epoch instead, and the new key is wrapped for every device that can read the
+/// store after `acl_changes`. Does nothing unless the store is syncing, we have its latest key, and
+/// the ACL lets the author write the header. Returns the id of the new header if the operations
+/// were applied.
+fn apply_local_operation<OT: OdysseyType, T>(
+    store: &mut State<OT::StoreId, OT::ECGHeader, T, OT::Hash>,
+    listeners: &mut Vec<Listener<OT::ECGHeader, T>>,
+    parents: BTreeSet<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    operation_body: OT::ECGBody<T>,
+    author: &Identity,
+    acl_changes: Vec<AclChange>,
+    rotate_key: bool,
+) -> Option<<OT::ECGHeader as ECGHeader>::HeaderId>
+where
+    OT::ECGHeader: Clone + Serialize,
+    <OT::ECGHeader as ECGHeader>::HeaderId: Serialize,
+    T: CRDT<Time = OT::Time> + Clone + Serialize,
+    T::Op: ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>,
+    OT::ECGBody<T>: Serialize
+        + for<'d> Deserialize<'d>
+        + ECGBody<
+            T::Op,
+            <T::Op as ConcretizeTime<<OT::ECGHeader as ECGHeader>::HeaderId>>::Serialized,
+            Header = OT::ECGHeader,
+        >,
+{
+    let StateMachine::Syncing {
+        metadata,
+        ecg_state,
+        decrypted_state: Some(decrypted_state),
+        ..
+    } = &mut store.state_machine
+    else {
+        warn!("Can't apply operations until the store is downloaded and we have its key");
+        return None;
+    };
+    if !parents.iter().all(|p| ecg_state.contains(p)) {
+        warn!("Can't apply operations on top of unknown parents");
+        return None;
+    }
+    let epoch = epoch_at(ecg_state, &parents);
+    let Some(key) = store.keys.get(&epoch) else {
+        warn!("Can't apply operations since we don't have the store's latest key");
+        return None;
+    };
+
+    let (new_key, wrapped_keys, rotated_key) = if rotate_key {
+        // Share a new key with every device that can read the store after this node.
+        let new_key = StoreKey::generate();
+        let mut acl = Acl::at(metadata.owner, ecg_state, &parents);
+        acl.apply(&acl_changes);
+        let wrapped_keys = acl
+            .members()
+            .iter()
+            .filter(|(_, role)| **role >= Role::Reader)
+            .map(|(device, _)| new_key.wrap(device))
+            .collect();
+        let rotated_key = new_key.encrypt_key(key);
+        (Some(new_key), wrapped_keys, Some(rotated_key))
+    } else {
+        // Share the store's key with the devices that can now read it.
+        let wrapped_keys = acl_changes
+            .iter()
+            .filter_map(|change| match change {
+                AclChange::Grant { device, role } if *role >= Role::Reader => {
+                    Some(key.wrap(device))
+                }
+                _ => None,
+            })
+            .collect();
+        (None, wrapped_keys, None)
+    };
+    let keys = HeaderKeys {
+        epoch,
+        wrapped_keys,
+        rotated_key,
+    };
+    let encrypted_body = key.encrypt(&encoding::to_vec(&operation_body));
+    let operation_header =
+        operation_body.new_header(&encrypted_body, parents, author, acl_changes, keys);
+
+    if node_size(&operation_header, &encrypted_body) > ecg::MAX_NODE_SIZE {
+        warn!("Not applying operations since their ECG node is too large");
+        return None;
+    }
+
+    let acl = Acl::at(metadata.owner, ecg_state, operation_header.get_parent_ids());
+    if let Err(err) = acl.authorize(&operation_header) {
+        warn!(
+            "Not applying operations by {}: {err}",
+            operation_header.get_author()
+        );
+        return None;
+    }
+
+    // Update ECG state.
+    let header_id = operation_header.get_header_id();
+    if !ecg_state.insert_header(operation_header.clone(), encrypted_body) {
+        warn!("Failed to insert ECG node {header_id:?}");
+        return None;
+    }
+    persist_ecg_node(
+        store.storage.as_mut(),
+        metadata.store_id::<OT::StoreId>().as_ref(),
+        ecg_state,
+        &header_id,
+    );
+    if let Some(new_key) = new_key {
+        store.keys.insert(Some(header_id), new_key);
+    }
+
+    // Update state.
+    // Operation ID/time is function of tips, current operation, ...? How do we
+    // do batching? (HeaderId(h) | Self, Index(u8)) ? This requires having all
+    // the batched operations?
+    apply_operations::<OT, _>(
+        decrypted_state,
+        &store.keys,
+        ecg_state,
+        &operation_header,
+        operation_body,
+    );
+
+    // Send state to subscribers.
+    let acl = Acl::at(metadata.owner, ecg_state, ecg_state.tips());
+    let keys = &store.keys;
+    update_listeners(
+        &mut store.ecg_subscribers,
+        &acl,
+        listeners,
+        Some(&decrypted_state.latest_state),
+        ecg_state,
+        Some(&|| concretized_operations::<OT, T>(keys, ecg_state, &[header_id])),
+        None,
+    );
+
+    if rotate_key {
+        store.persist_keys();
+    }
+    store.checkpoint_if_needed();
+    Some(header_id)
+}
+
 /// Run the handler that owns this store and manages its state. This handler is typically run in
 /// its own tokio thread.
+/// When the store is closed, returns the channel to acknowledge the close on once the store is
+/// deregistered.
 pub(crate) async fn run_handler<OT: OdysseyType, T>(
     mut store: State<OT::StoreId, OT::ECGHeader, T, OT::Hash>,
     mut recv_commands: UnboundedReceiver<StoreCommand<OT::ECGHeader, OT::ECGBody<T>, T>>,
@@ -1089,7 +2326,9 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
         UntypedStoreCommand<OT::Hash, <OT::ECGHeader as ECGHeader>::HeaderId, OT::ECGHeader>,
     >,
     shared_state: SharedState<OT::StoreId>,
-) where
+    send_acl: watch::Sender<Option<Acl>>,
+) -> Option<oneshot::Sender<()>>
+where
     <OT as OdysseyType>::ECGHeader:
         Send + Sync + Clone + Serialize + for<'d> Deserialize<'d> + 'static,
     // <<OT as OdysseyType>::ECGHeader as ECGHeader>::Body: ECGBody<T> + Send,
@@ -1106,9 +2345,11 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
     <<OT as OdysseyType>::ECGHeader as ECGHeader>::HeaderId:
         Send + Serialize + for<'d> Deserialize<'d>,
     // T::Op<CausalTime<T::Time>>: Serialize,
-    T: CRDT<Time = OT::Time> + Debug + Clone + Send + 'static + for<'d> Deserialize<'d>,
+    T: CRDT<Time = OT::Time> + Debug + Clone + Send + 'static + Serialize + for<'d> Deserialize<'d>,
 {
-    let mut listeners: Vec<UnboundedSender<StateUpdate<OT::ECGHeader, T>>> = vec![];
+    let mut listeners: Vec<Listener<OT::ECGHeader, T>> = vec![];
+    // The ECG tips the published ACL was computed at.
+    let mut acl_tips = None;
 
     // TODO: Check when done
     loop {
@@ -1116,75 +2357,65 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
             cmd_m = recv_commands.recv() => {
                 let Some(cmd) = cmd_m else {
                     error!("Failed to receive StoreCommand");
-                    return;
+                    return None;
                 };
 
                 // // Rank and connect to a few peers.
                 // manage_peers::<OT,T>(&mut store, &shared_state).await;
 
                 match cmd {
-                    StoreCommand::Apply {
-                        operation_header,
-                        operation_body,
-                    } => {
-                        store.state_machine = match store.state_machine {
-                            // StateMachine::DownloadingMetadata { store_id } => {
-                            //     // JP: Should we ever apply an operation if we're still downloading the store??
-                            //     warn!("Is this unreachable?");
-
-                            //     // Rank and connect to a few peers.
-                            //     manage_peers::<OT,T>(&mut store, &shared_state).await;
-
-                            //     StateMachine::DownloadingMetadata { store_id }
-                            // }
-                            // StateMachine::DownloadingMerkle { metadata, piece_hashes } => {
-                            //     // JP: Should we ever apply an operation if we're still downloading the store??
-                            //     warn!("Is this unreachable?");
-
-                            //     // Rank and connect to a few peers.
-                            //     manage_peers::<OT,T>(&mut store, &shared_state).await;
-
-                            //     StateMachine::DownloadingMerkle { metadata, piece_hashes }
-                            // }
-                            // StateMachine::DownloadingInitialState { metadata, piece_hashes, initial_state } => {
-                            //     // JP: Should we ever apply an operation if we're still downloading the store??
-                            //     warn!("Is this unreachable?");
-
-                            //     // Rank and connect to a few peers.
-                            //     manage_peers::<OT,T>(&mut store, &shared_state).await;
-
-                            //     StateMachine::DownloadingInitialState { metadata, piece_hashes, initial_state }
-                            // }
-                            StateMachine::Syncing { metadata, merkle_tree, initial_state , ecg_state, decrypted_state } => {
-                                let mut ecg_state = ecg_state;
-                                let mut decrypted_state = decrypted_state;
-
-                                // Update ECG state.
-                                let serialized_operations = serde_cbor::to_vec(&operation_body).expect("TODO");
-                                let success = ecg_state.insert_header(operation_header.clone(), serialized_operations);
-                                if !success {
-                                    todo!("Invalid header"); // : {:?}", operation_header);
-                                }
-
-                                // Update state.
-                                // TODO: Get new time?.. Or take it as an argument
-                                // Operation ID/time is function of tips, current operation, ...? How do we
-                                // do batching? (HeaderId(h) | Self, Index(u8)) ? This requires having all
-                                // the batched operations?
-                                apply_operations::<OT, _>(&mut decrypted_state, &ecg_state, &operation_header, operation_body);
-
-                                // Send state to subscribers.
-                                update_listeners(&mut store.ecg_subscribers, &listeners, &decrypted_state.latest_state, &ecg_state, None);
-
-                                StateMachine::Syncing { metadata, merkle_tree, initial_state, ecg_state, decrypted_state }
+                    StoreCommand::Apply { parents, operation_body, author, response_chan } => {
+                        // Dropping the response channel tells the caller the operation wasn't applied.
+                        if let Some(header_id) = apply_local_operation::<OT, T>(&mut store, &mut listeners, parents, operation_body, &author, vec![], false) {
+                            let _ = response_chan.send(header_id);
+                        }
+                    }
+                    StoreCommand::ApplyToTips { operation_body, author, acl_changes, rotate_key, response_chan } => {
+                        // Use the current tips as parents, so that the operation comes after every
+                        // operation we know of.
+                        if let StateMachine::Syncing { ecg_state, .. } = &store.state_machine {
+                            let parents = ecg_state.tips().clone();
+                            // Dropping the response channel tells the caller the operation wasn't applied.
+                            if let Some(header_id) = apply_local_operation::<OT, T>(&mut store, &mut listeners, parents, operation_body, &author, acl_changes, rotate_key) {
+                                let _ = response_chan.send(header_id);
                             }
-                            _ => {
-                                warn!("JP: Does this ever happen?");
-                                store.state_machine
+                        } else {
+                            // Dropping the response channel tells the caller the operation wasn't applied.
+                            warn!("Can't apply operations until the store is downloaded");
+                        }
+                    }
+                    StoreCommand::StateAt { heads, response_chan } => {
+                        let state = match &store.state_machine {
+                            StateMachine::Syncing { ecg_state, decrypted_state: Some(decrypted_state), .. } => {
+                                state_at::<OT, T>(decrypted_state, &store.keys, ecg_state, &heads)
                             }
+                            _ => None,
                         };
+                        let _ = response_chan.send(state);
+                    }
+                    StoreCommand::Key { response_chan } => {
+                        // Dropping the response channel tells the caller we don't have the key.
+                        if let StateMachine::Syncing { ecg_state, .. } = &store.state_machine {
+                            if let Some(key) = store.keys.latest(ecg_state) {
+                                let _ = response_chan.send(key.clone());
+                            }
+                        }
+                    }
+                    StoreCommand::SetKey { key, response_chan } => {
+                        let _ = response_chan.send(store.set_key::<OT>(key, &mut listeners));
+                    }
+                    StoreCommand::Acl { response_chan } => {
+                        if let Some(acl) = store.acl() {
+                            let _ = response_chan.send(acl);
+                        }
                     }
-                    StoreCommand::SubscribeState { send_state } => {
+                    StoreCommand::QueryECG { query } => {
+                        // Dropping the query tells the caller the ECG isn't available yet.
+                        if let StateMachine::Syncing { ecg_state, .. } = &store.state_machine {
+                            query(ecg_state);
+                        }
+                    }
+                    StoreCommand::SubscribeState { send_state, mode } => {
                         // Send current state.
                         let snapshot = match &store.state_machine {
                             StateMachine::DownloadingMetadata { .. } => {
@@ -1202,24 +2433,30 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
                                 };
                                 StateUpdate::Downloading { percent }
                             }
-                            StateMachine::Syncing { ref ecg_state, ref decrypted_state, .. } => {
+                            StateMachine::Syncing { ref ecg_state, decrypted_state: Some(ref decrypted_state), .. } => {
                                 StateUpdate::Snapshot {
-                                    snapshot: decrypted_state.latest_state.clone(),
-                                    ecg_state: ecg_state.clone(),
+                                    snapshot: Arc::new(decrypted_state.latest_state.clone()),
+                                    ecg_state: Arc::new(ecg_state.clone()),
                                 }
                             }
+                            StateMachine::Syncing { decrypted_state: None, .. } => StateUpdate::Encrypted,
                         };
-                        send_state.send(snapshot).expect("TODO");
-
-                        // Register this subscriber.
-                        listeners.push(send_state);
+                        // Register this subscriber, unless it's already gone.
+                        if send_state.send(snapshot).is_ok() {
+                            listeners.push(Listener { send_state, mode });
+                        }
+                    }
+                    StoreCommand::Close { response_chan } => {
+                        store.flush_storage();
+                        debug!("Closed store: {}", store.store_id());
+                        return Some(response_chan);
                     }
                 }
             }
             cmd_m = recv_commands_untyped.recv() => {
                 let Some(cmd) = cmd_m else {
                     error!("Failed to receive UntypedStoreCommand");
-                    return;
+                    return None;
                 };
                 match cmd {
                     // Called when:
@@ -1248,7 +2485,12 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
                         // Insert peer as known if we don't know them (since they're requesting the store).
                         store.insert_known_peer(peer);
 
-                        let response = {
+                        // Peers are checked again for each request, so only refuse peers we know
+                        // can't sync the store.
+                        let response = if store.acl().is_some_and(|acl| !acl.can_sync(&peer)) {
+                            debug!("Peer ({peer}) can't sync the store");
+                            None
+                        } else {
                             // Check if already syncing with this peer. (JP: What if they're both already "Initializing"? Potential race condition where they don't sync)
                             if let Some(status) = store.peers.get(&peer) {
                                 if status.incoming_status.is_known() {
@@ -1257,6 +2499,7 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
 
                                     // Create closure that spawns task to sync store with peer.
                                     let send_commands_untyped = send_commands_untyped.clone();
+                                    let shared_state = shared_state.clone();
                                     let spawn_task: Box<SpawnMultiplexerTask> = Box::new(move |party, stream_id, sender, receiver| {
                                         // Create miniprotocol
                                         // Spawn task that syncs store with peer.
@@ -1268,11 +2511,15 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
                                             let register_cmd = UntypedStoreCommand::RegisterIncomingPeerSyncing {
                                                 peer,
                                             };
-                                            send_commands_untyped.send(register_cmd).expect("TODO");
+                                            // If the store closed, the miniprotocol stops right away.
+                                            let _ = send_commands_untyped.send(register_cmd);
 
                                             // Start miniprotocol as client.
                                             let mp = StoreSync::<OT::Hash, _, _>::new_client(peer, send_commands_untyped);
-                                            run_miniprotocol_async::<_, OT>(mp, true, stream_id, sender, receiver).await;
+                                            let result = run_miniprotocol_async::<_, OT>(mp, true, stream_id, sender, receiver).await;
+                                            if let Err(err) = result {
+                                                shared_state.report_peer_error(peer, &err);
+                                            }
                                             debug!("Store sync with peer (without initiative) exited.")
                                         })
                                     });
@@ -1288,7 +2535,7 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
                             }
                         };
 
-                        response_chan.send(response).or(Err(())).expect("TODO");
+                        let _ = response_chan.send(response);
                     }
                     UntypedStoreCommand::RegisterOutgoingPeerSyncing{ peer, send_peer } => {
                         // JP: Maybe send_peer actually isn't needed??? We could construct oneshots???
@@ -1302,6 +2549,13 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
                         // Sync with peer(s). Do this for all commands??
                         store.send_sync_requests();
                     }
+                    UntypedStoreCommand::UnregisterPeer { peer } => {
+                        debug!("Received UntypedStoreCommand::UnregisterPeer: {:?}", peer);
+                        store.remove_peer(&peer);
+
+                        // Re-request anything that was outstanding with the peer from other peers.
+                        store.send_sync_requests();
+                    }
                     UntypedStoreCommand::RegisterIncomingPeerSyncing{ peer } => {
                         // JP: Maybe this actually isn't needed??? We could construct oneshots for every request..
 
@@ -1318,52 +2572,170 @@ pub(crate) async fn run_handler<OT: OdysseyType, T>(
                         store.handle_block_peer_request(peer, request, response_chan);
                     }
                     UntypedStoreCommand::ReceivedMetadata { peer, metadata } => {
-                        store.handle_received_metadata(peer, metadata, &listeners);
+                        store.handle_received_metadata(peer, *metadata, &mut listeners);
                         store.send_sync_requests();
                     }
                     UntypedStoreCommand::ReceivedMerkleHashes { peer, ranges, nodes } => {
-                        store.handle_received_merkle_hashes(peer, ranges, nodes, &listeners);
+                        store.handle_received_merkle_hashes(peer, ranges, nodes, &mut listeners);
                         store.send_sync_requests();
                     }
                     UntypedStoreCommand::ReceivedInitialStateBlocks { peer, ranges, blocks } => {
-                        store.handle_received_initial_state_blocks(peer, ranges, blocks, &listeners);
+                        store.handle_received_initial_state_blocks(peer, ranges, blocks, &mut listeners);
                         store.send_sync_requests();
                     }
                     UntypedStoreCommand::ReceivedECGOperations { peer, operations } => {
-                        store.handle_received_ecg_operations::<OT>(peer, operations, &listeners);
+                        let rejected = store.handle_received_ecg_operations::<OT>(peer, operations, &mut listeners);
+                        for (header_id, err) in rejected {
+                            warn!("Rejected ECG node {header_id:?} from peer {peer}: {err}");
+                            shared_state.report_invalid_ecg_node(peer, err);
+                        }
                         store.send_sync_requests();
                     }
                     UntypedStoreCommand::SubscribeECG { peer, tips, response_chan } => {
                         store.handle_ecg_subscribe(peer, tips, response_chan);
                     }
+                    UntypedStoreCommand::Close { response_chan } => {
+                        store.flush_storage();
+                        debug!("Closed store: {}", store.store_id());
+                        return Some(response_chan);
+                    }
                 }
             }
         }
+
+        // Publish the ACL so that peer managers only advertise the store to peers that can sync it.
+        // The ACL only changes with the ECG, so it's only recomputed when the ECG's tips change.
+        let tips = store.ecg_tips();
+        if tips != acl_tips.as_ref() {
+            acl_tips = tips.cloned();
+            let acl = store.acl();
+            send_acl.send_if_modified(|current| {
+                let is_modified = *current != acl;
+                *current = acl;
+                is_modified
+            });
+        }
     }
     debug!("Store thread exiting.");
 }
 
-pub(crate) enum StoreCommand<Header: ECGHeader, Body, T> {
+/// A query that is run against a store's ECG by the store's handler.
+pub(crate) type ECGQuery<Header, T> = Box<dyn FnOnce(&ecg::State<Header, T>) + Send>;
+
+pub(crate) enum StoreCommand<Header: ECGHeader, Body, T: CRDT> {
+    /// Apply an operation on top of the given parents. Responds with the id of the new header.
     Apply {
-        operation_header: Header, // <Hash, T>,
-        operation_body: Body,     // <Hash, T>,
+        parents: BTreeSet<Header::HeaderId>,
+        operation_body: Body,
+        /// Identity that signs the new header.
+        author: Identity,
+        response_chan: oneshot::Sender<Header::HeaderId>,
+    },
+    /// Apply an operation whose parents are the store's current tips. Responds with the id of the
+    /// new header.
+    ApplyToTips {
+        operation_body: Body,
+        /// Identity that signs the new header.
+        author: Identity,
+        /// Changes to the store's ACL that the new header makes.
+        acl_changes: Vec<AclChange>,
+        /// Whether the new header rotates the store's key.
+        rotate_key: bool,
+        response_chan: oneshot::Sender<Header::HeaderId>,
+    },
+    /// Get the store's latest key, which unlocks older keys too. The response channel is dropped if
+    /// we don't have it.
+    Key {
+        response_chan: oneshot::Sender<StoreKey>,
+    },
+    /// Add a key that was shared out of band. Responds with whether the key was accepted.
+    SetKey {
+        key: StoreKey,
+        response_chan: oneshot::Sender<bool>,
     },
-    // TODO: Support unsubscribe.
+    /// Get the store's current ACL. The response channel is dropped if the store is still
+    /// downloading.
+    Acl { response_chan: oneshot::Sender<Acl> },
+    /// Materialize the state at the given heads. Responds with `None` if any of the heads are
+    /// unknown, the store is still downloading, or we don't have its key.
+    StateAt {
+        heads: BTreeSet<Header::HeaderId>,
+        response_chan: oneshot::Sender<Option<T>>,
+    },
+    /// Run a query against the store's ECG. The query is dropped if the store is still
+    /// downloading.
+    QueryECG { query: ECGQuery<Header, T> },
+    /// Subscribe to state updates. The subscription is removed once its receiver is dropped.
     SubscribeState {
-        send_state: UnboundedSender<StateUpdate<Header, T>>,
+        send_state: StateSender<Header, T>,
+        mode: SubscriptionMode,
     },
+    /// Flush the store's storage and stop its handler, which ends its syncs with peers.
+    Close { response_chan: oneshot::Sender<()> },
 }
 
-pub enum StateUpdate<Header: ECGHeader, T> {
+pub enum StateUpdate<Header: ECGHeader, T: CRDT> {
     Downloading {
         // Percent of the state that we've downloaded (0 - 100).
         percent: u64,
     },
+    /// The state and its ECG. These are shared by every subscriber that's sent the update.
     Snapshot {
-        snapshot: T,
-        ecg_state: ecg::State<Header, T>,
+        snapshot: Arc<T>,
+        ecg_state: Arc<ecg::State<Header, T>>,
         // TODO: ECG DAG
     },
+    /// The store is downloaded, but we don't have its key so we can't read it. A snapshot is sent
+    /// once we get the key.
+    Encrypted,
+    /// The ECG nodes that were applied since the last update, in the order they were applied,
+    /// with their concretized operations. Only sent to subscribers of operations, who receive a
+    /// snapshot first.
+    Operations {
+        operations: Arc<AppliedOperations<Header, T>>,
+    },
+}
+
+/// What a state subscriber is sent when the store changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SubscriptionMode {
+    /// A snapshot of the whole state.
+    Snapshots,
+    /// Only the newly applied operations.
+    Operations,
+}
+
+/// A subscriber to a store's state.
+pub(crate) struct Listener<Header: ECGHeader, T: CRDT> {
+    send_state: StateSender<Header, T>,
+    mode: SubscriptionMode,
+}
+
+/// Channel that a subscriber's state updates are sent on.
+pub(crate) enum StateSender<Header: ECGHeader, T: CRDT> {
+    Unbounded(UnboundedSender<StateUpdate<Header, T>>),
+    /// Updates are not sent while the channel is full.
+    Bounded(mpsc::Sender<StateUpdate<Header, T>>),
+    /// Only the latest update is kept.
+    Latest(watch::Sender<StateUpdate<Header, T>>),
+}
+
+impl<Header: ECGHeader, T: CRDT> StateSender<Header, T> {
+    /// Send an update without blocking the store.
+    fn send(
+        &self,
+        update: StateUpdate<Header, T>,
+    ) -> Result<(), TrySendError<StateUpdate<Header, T>>> {
+        match self {
+            StateSender::Unbounded(chan) => {
+                chan.send(update).map_err(|err| TrySendError::Closed(err.0))
+            }
+            StateSender::Bounded(chan) => chan.try_send(update),
+            StateSender::Latest(chan) => {
+                chan.send(update).map_err(|err| TrySendError::Closed(err.0))
+            }
+        }
+    }
 }
 
 // trait UntypedCRDT: CRDT<Op = dyn Any, Time = dyn Any> {} // Any + Sized + 'static +
@@ -1372,7 +2744,15 @@ pub enum StateUpdate<Header: ECGHeader, T> {
 // struct UntypedStoreCommand(StoreCommand<dyn UntypedECGHeader, dyn UntypedCRDT>);
 // struct UntypedStoreCommand(StoreCommand<dyn UntypedECGHeader, dyn Any>);
 
-type HandlePeerResponse<Response> = Result<Response, oneshot::Receiver<Option<Response>>>;
+/// A store's response to a peer's request.
+pub(crate) enum HandlePeerResponse<Response> {
+    Response(Response),
+    /// We don't have the response yet, so wait for it on the channel. `None` means we won't send
+    /// it.
+    Wait(oneshot::Receiver<Option<Response>>),
+    /// The peer isn't allowed to make the request.
+    Reject,
+}
 
 /// Untyped variant of `StoreCommand` since existentials don't work.
 // #[derive(Debug)]
@@ -1381,6 +2761,10 @@ pub(crate) enum UntypedStoreCommand<Hash, HeaderId, Header> {
     RegisterPeers {
         peers: Vec<DeviceId>,
     },
+    /// The connection to a peer was torn down.
+    UnregisterPeer {
+        peer: DeviceId,
+    },
     /// Request store to sync with peer. Store can refuse.
     SyncWithPeer {
         peer: DeviceId,
@@ -1399,7 +2783,7 @@ pub(crate) enum UntypedStoreCommand<Hash, HeaderId, Header> {
     },
     ReceivedMetadata {
         peer: DeviceId,
-        metadata: MetadataHeader<Hash>,
+        metadata: Box<MetadataHeader<Hash>>,
     },
     ReceivedMerkleHashes {
         peer: DeviceId,
@@ -1418,7 +2802,12 @@ pub(crate) enum UntypedStoreCommand<Hash, HeaderId, Header> {
     SubscribeECG {
         peer: DeviceId,
         tips: Option<BTreeSet<HeaderId>>,
-        response_chan: oneshot::Sender<ecg::UntypedState<HeaderId, Header>>,
+        /// Responds with `None` if the peer can't sync the store.
+        response_chan: oneshot::Sender<Option<ecg::UntypedState<HeaderId, Header>>>,
+    },
+    /// Flush the store's storage and stop its handler, which ends its syncs with peers.
+    Close {
+        response_chan: oneshot::Sender<()>,
     },
 }
 
@@ -1491,3 +2880,55 @@ fn handle_peer_request_range_helper<T: Clone>(slice: &[T], ids: &[Range<u64>]) -
         .collect();
     hashes
 }
+
+#[cfg(test)]
+mod test {
+    use odyssey_crdt::register::LWW;
+
+    use super::*;
+    use crate::auth::generate_identity;
+    use crate::store::ecg::v0::Header;
+    use crate::util::Sha256Hash;
+
+    type T = LWW<u64, bool>;
+
+    #[test]
+    fn test_dropped_listeners_are_removed() {
+        let (send_kept, mut recv_kept) = mpsc::unbounded_channel();
+        let (send_dropped, recv_dropped) = mpsc::unbounded_channel();
+        let (send_latest, recv_latest) = watch::channel(StateUpdate::Downloading { percent: 0 });
+        let mut listeners: Vec<Listener<Header<Sha256Hash>, T>> = vec![
+            Listener {
+                send_state: StateSender::Unbounded(send_kept),
+                mode: SubscriptionMode::Snapshots,
+            },
+            Listener {
+                send_state: StateSender::Unbounded(send_dropped),
+                mode: SubscriptionMode::Operations,
+            },
+            Listener {
+                send_state: StateSender::Latest(send_latest),
+                mode: SubscriptionMode::Snapshots,
+            },
+        ];
+        drop(recv_dropped);
+        drop(recv_latest);
+
+        let acl = Acl::new(generate_identity().device_id());
+        update_listeners(
+            &mut BTreeMap::new(),
+            &acl,
+            &mut listeners,
+            Some(&LWW::new(0, false)),
+            &ecg::State::new(),
+            None,
+            None,
+        );
+
+        assert_eq!(listeners.len(), 1);
+        assert!(matches!(
+            recv_kept.try_recv(),
+            Ok(StateUpdate::Snapshot { .. })
+        ));
+    }
+}
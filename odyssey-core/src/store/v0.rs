@@ -3,8 +3,10 @@ use std::fmt::Debug;
 use tracing::warn;
 use typeable::{TypeId, Typeable};
 
+use crate::auth::{DeviceId, Identity};
+use crate::store::encryption::StoreKey;
 use crate::util::merkle_tree::MerkleTree;
-use crate::util::{generate_nonce, Hash};
+use crate::util::{encoding, generate_nonce, Hash};
 use crate::{protocol, util};
 
 // pub struct Store<Id, T> {
@@ -55,49 +57,82 @@ pub struct MetadataHeader<Hash> {
     /// Type of state that the store holds.
     pub store_type: TypeId,
 
-    /// Size in bytes of the initial state.
+    /// Size in bytes of the encrypted initial state.
     pub initial_state_size: u64,
 
-    /// Hash (merkle root) of the hashes of the initial state's blocks.
+    /// Hash (merkle root) of the hashes of the encrypted initial state's blocks.
     pub merkle_root: Hash, // TODO: Make this an actual binary (or 512-ary) tree?
-                           //
-                           // TODO:
-                           // Owner?
-                           // Encryption options
-                           // Access control options?
+    //
+    // TODO:
+    // Encryption options
+    // Access control options?
+    /// The device that created the store.
+    pub owner: DeviceId,
+
+    /// The owner's signature of the store id.
+    pub signature: ed25519_dalek::Signature,
 }
 
-// TODO: Signature of MetadataHeader by `owner`.
+/// The fields of a `MetadataHeader` that the store id is computed from (everything but the
+/// signature).
+#[derive(Serialize)]
+struct UnsignedMetadataHeader<'a, Hash> {
+    nonce: &'a Nonce,
+    protocol_version: protocol::Version,
+    store_type: TypeId,
+    initial_state_size: u64,
+    merkle_root: &'a Hash,
+    owner: &'a DeviceId,
+}
 
-impl<H: Hash + Debug> MetadataHeader<H> {
-    pub fn generate<T: Typeable>(initial_state: &MetadataBody<H>) -> MetadataHeader<H> {
+impl<H: Hash + Debug + Serialize> MetadataHeader<H> {
+    pub fn generate<T: Typeable>(
+        initial_state: &MetadataBody<H>,
+        owner: &Identity,
+    ) -> MetadataHeader<H> {
         let nonce = generate_nonce();
         let protocol_version = protocol::LATEST_VERSION;
         let store_type = T::type_ident();
         let initial_state_size = initial_state.initial_state.len() as u64;
         let merkle_root = initial_state.merkle_root();
+        let owner_id = owner.device_id();
+        let store_id: H = encoding::hash(&UnsignedMetadataHeader {
+            nonce: &nonce,
+            protocol_version,
+            store_type,
+            initial_state_size,
+            merkle_root: &merkle_root,
+            owner: &owner_id,
+        });
         MetadataHeader {
             nonce,
             protocol_version,
             store_type,
             initial_state_size,
             merkle_root,
+            owner: owner_id,
+            signature: owner.sign(store_id.as_ref()),
         }
     }
 
-    /// Compute the store id for the `MetadataHeader`.
-    /// This function must be updated any time `MetadataHeader` is updated.
+    fn hash(&self) -> H {
+        encoding::hash(&UnsignedMetadataHeader {
+            nonce: &self.nonce,
+            protocol_version: self.protocol_version,
+            store_type: self.store_type,
+            initial_state_size: self.initial_state_size,
+            merkle_root: &self.merkle_root,
+            owner: &self.owner,
+        })
+    }
+
+    /// Compute the store id for the `MetadataHeader`, which is the hash of the canonical encoding
+    /// of everything but the signature.
     pub fn store_id<StoreId>(&self) -> StoreId
     where
         H: Into<StoreId>,
     {
-        let mut h = H::new();
-        H::update(&mut h, self.nonce);
-        H::update(&mut h, [self.protocol_version.as_byte()]);
-        H::update(&mut h, self.store_type);
-        H::update(&mut h, self.initial_state_size.to_be_bytes());
-        H::update(&mut h, self.merkle_root);
-        H::finalize(h).into()
+        self.hash().into()
     }
 
     /// Validate the metadata with respect to the store id.
@@ -109,6 +144,11 @@ impl<H: Hash + Debug> MetadataHeader<H> {
         store_id == self.store_id()
     }
 
+    /// Check that the owner signed the metadata.
+    pub fn validate_signature(&self) -> bool {
+        self.owner.verify(self.hash().as_ref(), &self.signature)
+    }
+
     pub fn block_count(&self) -> u64 {
         self.initial_state_size.div_ceil(BLOCK_SIZE)
     }
@@ -117,15 +157,19 @@ impl<H: Hash + Debug> MetadataHeader<H> {
 #[derive(Debug)] // , Deserialize, Serialize)]
                  // TODO: Get rid of this? Or rename it? InitialStateBuilder?
 pub struct MetadataBody<Hash> {
-    /// Serialized (and encrypted) initial state of the store.
-    //  TODO: Eventually merkelize the initial state in chunks.
+    /// Serialized and encrypted initial state of the store.
     initial_state: Vec<u8>,
     merkle_tree: MerkleTree<Hash>,
 }
 
 impl<H: Hash + Debug> MetadataBody<H> {
-    pub(crate) fn new<T: Serialize>(initial_state: &T) -> MetadataBody<H> {
-        let initial_state = serde_cbor::to_vec(initial_state).expect("TODO");
+    /// Serialize and encrypt the initial state. The blocks are of the encrypted state, so peers
+    /// without the key can still download and validate them.
+    pub(crate) fn new<T: Serialize>(initial_state: &T, key: &StoreKey) -> MetadataBody<H> {
+        MetadataBody::from_encrypted(key.encrypt(&encoding::to_vec(initial_state)))
+    }
+
+    fn from_encrypted(initial_state: Vec<u8>) -> MetadataBody<H> {
         let merkle_tree = MerkleTree::from_chunks(initial_state.chunks(BLOCK_SIZE as usize));
         MetadataBody {
             initial_state,
@@ -142,7 +186,94 @@ impl<H: Hash + Debug> MetadataBody<H> {
     }
 }
 
+/// Decrypt and deserialize a store's initial state. Returns `None` if it wasn't encrypted with the
+/// key or doesn't parse.
+pub(crate) fn decrypt_initial_state<T: for<'d> Deserialize<'d>>(
+    key: &StoreKey,
+    initial_state: &[u8],
+) -> Option<T> {
+    let initial_state = key.decrypt(initial_state).ok()?;
+    encoding::from_slice(&initial_state).ok()
+}
+
 // pub type StoreId = [u8; 32];
 // pub type TypeId = [u8; 32];
 // pub type Hash = [u8; 32];
 pub type Nonce = [u8; 32];
+
+#[cfg(test)]
+mod test {
+    use odyssey_crdt::{map::twopmap::TwoPMap, register::LWW};
+
+    use super::*;
+    use crate::auth::generate_identity;
+    use crate::util::Sha256Hash;
+
+    #[test]
+    fn test_golden_vectors() {
+        let initial_state = LWW::new(1u64, "hello".to_string());
+        let body = MetadataBody::<Sha256Hash>::from_encrypted(encoding::to_vec(&initial_state));
+        assert_eq!(
+            hex::encode(&body.initial_state),
+            "000000000000000001000000000000000568656c6c6f"
+        );
+        assert_eq!(
+            hex::encode(body.merkle_root()),
+            "5b039ffd7bfe423d2b8b1b2c629f2207a25c13a3c0e02235fbcd2b34e87e6920"
+        );
+
+        let owner = Identity::from_seed(&[5; 32]);
+        let unsigned = UnsignedMetadataHeader {
+            nonce: &[3; 32],
+            protocol_version: protocol::Version::V0,
+            store_type: TypeId::new([4; 32]),
+            initial_state_size: body.initial_state.len() as u64,
+            merkle_root: &body.merkle_root(),
+            owner: &owner.device_id(),
+        };
+        let store_id: Sha256Hash = encoding::hash(&unsigned);
+        let metadata = MetadataHeader {
+            nonce: [3; 32],
+            protocol_version: protocol::Version::V0,
+            store_type: TypeId::new([4; 32]),
+            initial_state_size: body.initial_state.len() as u64,
+            merkle_root: body.merkle_root(),
+            owner: owner.device_id(),
+            signature: owner.sign(store_id.as_ref()),
+        };
+        assert_eq!(
+            hex::encode(metadata.store_id::<Sha256Hash>()),
+            "a979c8cbdbbe765de06aa2170f6ac297a6dd51d2db5bfac3f89a1445b4906f25"
+        );
+        assert!(metadata.validate_signature());
+    }
+
+    #[test]
+    fn test_swapped_owner() {
+        let body = MetadataBody::<Sha256Hash>::new(&LWW::new(1u64, true), &StoreKey::generate());
+        let metadata = MetadataHeader::generate::<LWW<u64, bool>>(&body, &generate_identity());
+        let store_id: Sha256Hash = metadata.store_id();
+        assert!(metadata.validate_store_id(store_id));
+        assert!(metadata.validate_signature());
+
+        let mut swapped = metadata;
+        swapped.owner = generate_identity().device_id();
+        assert!(!swapped.validate_store_id(store_id));
+        assert!(!swapped.validate_signature());
+    }
+
+    #[test]
+    fn test_initial_state_roundtrip() {
+        let initial_state = TwoPMap::<u64, LWW<u64, u8>>::new();
+        let key = StoreKey::generate();
+        let body = MetadataBody::<Sha256Hash>::new(&initial_state, &key);
+        let decoded: TwoPMap<u64, LWW<u64, u8>> =
+            decrypt_initial_state(&key, &body.initial_state).unwrap();
+        assert_eq!(encoding::to_vec(&decoded), encoding::to_vec(&initial_state));
+        assert!(decrypt_initial_state::<TwoPMap<u64, LWW<u64, u8>>>(
+            &StoreKey::generate(),
+            &body.initial_state
+        )
+        .is_none());
+    }
+}
@@ -3,12 +3,14 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum CausalTime<Time> {
-    Current { operation_position: u8 }, // Points to the current ECG node.
-    Time(Time),                         // Points to another ECG node.
+    // Points to the current batch of operations. Large batches are split across several ECG
+    // nodes, so the position is relative to the start of the batch.
+    Current { operation_position: u32 },
+    Time(Time), // Points to another ECG node.
 }
 
 impl<Time> CausalTime<Time> {
-    pub fn current_time(operation_position: u8) -> CausalTime<Time> {
+    pub fn current_time(operation_position: u32) -> CausalTime<Time> {
         CausalTime::Current { operation_position }
     }
 
@@ -21,6 +23,11 @@ pub trait ConcretizeTime<HeaderId> {
     type Serialized;
 
     fn concretize_time(src: Self::Serialized, current_header: HeaderId) -> Self;
+
+    /// Make the batch relative positions in `src` relative to the ECG node it's applied in.
+    /// `previous_headers` are the nodes that hold the earlier chunks of the batch, each with
+    /// `MAX_BODY_OPERATIONS` operations. Positions in those chunks are replaced by their time.
+    fn rebase_batch_time(src: Self::Serialized, previous_headers: &[HeaderId]) -> Self::Serialized;
 }
 
 impl<HeaderId, T: ConcretizeTime<HeaderId>, V> ConcretizeTime<HeaderId> for LWW<T, V> {
@@ -32,6 +39,13 @@ impl<HeaderId, T: ConcretizeTime<HeaderId>, V> ConcretizeTime<HeaderId> for LWW<
             value: src.value,
         }
     }
+
+    fn rebase_batch_time(src: Self::Serialized, previous_headers: &[HeaderId]) -> Self::Serialized {
+        LWW {
+            time: T::rebase_batch_time(src.time, previous_headers),
+            value: src.value,
+        }
+    }
 }
 
 impl<
@@ -58,4 +72,20 @@ impl<
             },
         }
     }
+
+    fn rebase_batch_time(src: Self::Serialized, previous_headers: &[HeaderId]) -> Self::Serialized {
+        match src {
+            TwoPMapOp::Insert { key, value } => TwoPMapOp::Insert {
+                key: K::rebase_batch_time(key, previous_headers),
+                value: V::rebase_batch_time(value, previous_headers),
+            },
+            TwoPMapOp::Apply { key, operation } => TwoPMapOp::Apply {
+                key: K::rebase_batch_time(key, previous_headers),
+                operation: Op::rebase_batch_time(operation, previous_headers),
+            },
+            TwoPMapOp::Delete { key } => TwoPMapOp::Delete {
+                key: K::rebase_batch_time(key, previous_headers),
+            },
+        }
+    }
 }
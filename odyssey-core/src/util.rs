@@ -1,3 +1,4 @@
+pub mod encoding;
 pub mod merkle_tree;
 
 use bytes::{Bytes, BytesMut};
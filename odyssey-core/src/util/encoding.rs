@@ -0,0 +1,141 @@
+//! Canonical encoding of everything that is hashed (ECG headers and bodies, metadata headers, and
+//! initial states), so that ids stay stable across releases.
+//!
+//! An encoding starts with a byte holding `ENCODING_VERSION`, followed by the value where:
+//! - Integers are fixed width and big endian. Booleans are a single byte.
+//! - Struct fields are encoded in declaration order without their names.
+//! - Enum variants are a `u32` index followed by their fields.
+//! - Sequences, maps, strings and byte strings are prefixed with their length as a `u64`. Maps
+//!   must iterate in a deterministic order (`BTreeMap`, `OrdMap`, etc).
+//! - Options are a `0` or `1` byte followed by the value if present.
+//!
+//! Any change to this format must bump `ENCODING_VERSION`. Changing the fields of a hashed type
+//! changes its ids, so that needs a new protocol version instead. The golden vectors in the tests
+//! (and next to the hashed types) catch accidental changes.
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+use crate::util::Hash;
+
+/// Current version of the canonical encoding.
+pub const ENCODING_VERSION: u8 = 0;
+
+/// Errors that can occur when decoding canonically encoded values.
+#[derive(Debug)]
+pub enum EncodingError {
+    /// The input is empty.
+    MissingVersion,
+    /// The encoding version isn't one we know about.
+    UnsupportedVersion(u8),
+    /// The input isn't a valid encoding of the value.
+    Malformed(bincode::Error),
+}
+
+impl Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodingError::MissingVersion => write!(f, "Missing encoding version"),
+            EncodingError::UnsupportedVersion(v) => write!(f, "Unsupported encoding version: {v}"),
+            EncodingError::Malformed(err) => write!(f, "Malformed encoding: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for EncodingError {}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_big_endian()
+        .with_no_limit()
+        .reject_trailing_bytes()
+}
+
+/// Canonically encode a value.
+pub fn to_vec<T: Serialize + ?Sized>(x: &T) -> Vec<u8> {
+    let mut bytes = vec![ENCODING_VERSION];
+    options()
+        .serialize_into(&mut bytes, x)
+        .expect("Unreachable: Canonical encoding only fails for sequences without a known length.");
+    bytes
+}
+
+/// Decode a canonically encoded value.
+pub fn from_slice<'d, T: Deserialize<'d>>(bytes: &'d [u8]) -> Result<T, EncodingError> {
+    let (version, bytes) = bytes.split_first().ok_or(EncodingError::MissingVersion)?;
+    if *version != ENCODING_VERSION {
+        return Err(EncodingError::UnsupportedVersion(*version));
+    }
+    options()
+        .deserialize(bytes)
+        .map_err(EncodingError::Malformed)
+}
+
+/// Hash the canonical encoding of a value.
+pub fn hash<H: Hash, T: Serialize + ?Sized>(x: &T) -> H {
+    let mut h = H::new();
+    H::update(&mut h, to_vec(x));
+    H::finalize(h)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::util::Sha256Hash;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    enum Example {
+        Unit,
+        Fields { a: u8, b: Option<u32> },
+    }
+
+    #[test]
+    fn test_golden_vectors() {
+        assert_eq!(hex::encode(to_vec(&0x0102_0304u32)), "0001020304");
+        assert_eq!(hex::encode(to_vec(&-2i16)), "00fffe");
+        assert_eq!(hex::encode(to_vec(&true)), "0001");
+        assert_eq!(hex::encode(to_vec(&vec![1u8, 2])), "0000000000000000020102");
+        assert_eq!(hex::encode(to_vec("hi")), "0000000000000000026869");
+        assert_eq!(
+            hex::encode(to_vec(&BTreeMap::from([(2u8, 'b'), (1u8, 'a')]))),
+            "00000000000000000201610262"
+        );
+        assert_eq!(hex::encode(to_vec(&Example::Unit)), "0000000000");
+        assert_eq!(
+            hex::encode(to_vec(&Example::Fields { a: 7, b: Some(9) })),
+            "0000000001070100000009"
+        );
+        assert_eq!(
+            hex::encode(hash::<Sha256Hash, _>(&Example::Unit)),
+            "8855508aade16ec573d21e6a485dfd0a7624085c1a14b5ecdd6485de0c6839a4"
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let x = Example::Fields { a: 1, b: None };
+        assert_eq!(from_slice::<Example>(&to_vec(&x)).unwrap(), x);
+
+        let mut bytes = to_vec(&x);
+        bytes[0] = ENCODING_VERSION + 1;
+        assert!(matches!(
+            from_slice::<Example>(&bytes),
+            Err(EncodingError::UnsupportedVersion(_))
+        ));
+
+        let mut bytes = to_vec(&x);
+        bytes.push(0);
+        assert!(matches!(
+            from_slice::<Example>(&bytes),
+            Err(EncodingError::Malformed(_))
+        ));
+        assert!(matches!(
+            from_slice::<Example>(&[]),
+            Err(EncodingError::MissingVersion)
+        ));
+    }
+}
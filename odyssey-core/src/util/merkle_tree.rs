@@ -1,10 +1,11 @@
+use serde::{Deserialize, Serialize};
 use std::{cmp, fmt::Debug};
 
 use crate::util::Hash;
 
 /// Binary merkle tree.
 /// Second pre-image attacks aren't possible since the tree's size and shape are fixed. An attacker cannot swap a leaf with a branch node.
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MerkleTree<N> {
     // Flattened BFS representation of merkle tree. Root is at index 0.
     nodes: Vec<N>,
@@ -25,6 +25,11 @@ pub trait CRDT {
     type Op; // <Time>; // Required due to lack of higher kinded types.
     type Time; // TODO: Delete this??
 
+    /// Whether concurrent operations commute, so that they can be applied in any causally
+    /// consistent order. If not, stores rebuild the state in a deterministic order whenever an
+    /// operation arrives that is concurrent with operations that were already applied.
+    const CONCURRENT_OPERATIONS_COMMUTE: bool;
+
     // TODO: enabled...
 
     // Mut or return Self?
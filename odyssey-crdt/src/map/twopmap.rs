@@ -1,5 +1,5 @@
 use im::{OrdMap, OrdSet};
-use serde::de::{MapAccess, Visitor};
+use serde::de::{MapAccess, SeqAccess, Visitor};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
 use std::fmt::{self, Debug};
@@ -18,7 +18,7 @@ pub struct TwoPMap<K, V> {
     tombstones: OrdSet<K>,
 }
 
-// TODO: Standardized serialization.
+// Fields are serialized in order so that positional (canonical) encodings are stable.
 impl<K: Serialize + Ord + Clone, V: Serialize + Clone> Serialize for TwoPMap<K, V> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -56,6 +56,20 @@ impl<'d, K: Clone + Ord + Deserialize<'d>, V: Clone + Deserialize<'d>> Deseriali
                 formatter.write_str("struct TwoPMap")
             }
 
+            fn visit_seq<S>(self, mut s: S) -> Result<TwoPMap<K, V>, S::Error>
+            where
+                S: SeqAccess<'d>,
+            {
+                let map = s
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                let tombstones = s
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(1, &self))?;
+
+                Ok(TwoPMap { map, tombstones })
+            }
+
             fn visit_map<M>(self, mut m: M) -> Result<TwoPMap<K, V>, M::Error>
             where
                 M: MapAccess<'d>,
@@ -97,7 +111,6 @@ impl<K: Ord + Debug, V: Debug> Debug for TwoPMap<K, V> {
     }
 }
 
-// TODO: Define CBOR properly
 #[derive(Debug, Serialize, Deserialize)]
 pub enum TwoPMapOp<K, V, Op> {
     Insert { key: K, value: V },
@@ -119,6 +132,11 @@ impl<K: Ord + Clone, V: CRDT<Time = K> + Clone> CRDT for TwoPMap<K, V> {
     type Op = TwoPMapOp<K, V, V::Op>;
     type Time = V::Time; // JP: Newtype wrap `struct TwoPMapId<V>(V::Time)`?
 
+    // Keys are unique, and operations on a key come after its insert, so only concurrent
+    // applies and deletes of a key can meet. Deletes win either way, so it comes down to whether
+    // the values' operations commute.
+    const CONCURRENT_OPERATIONS_COMMUTE: bool = V::CONCURRENT_OPERATIONS_COMMUTE;
+
     fn apply<CS: CausalState<Time = Self::Time>>(self, st: &CS, op: Self::Op) -> Self {
         // Check if deleted.
         let is_deleted = {
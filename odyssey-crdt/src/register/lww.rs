@@ -7,7 +7,6 @@ use crate::{
     CRDT,
 };
 
-// TODO: Define CBOR properly
 #[derive(Clone, Debug, PartialEq, Typeable, Serialize, Deserialize)]
 /// Last writer wins (LWW) register.
 pub struct LWW<T, A> {
@@ -33,6 +32,9 @@ impl<T: Ord, A> CRDT for LWW<T, A> {
     type Op = LWW<T, A>;
     type Time = T;
 
+    // The operation with the greatest time wins, whatever order operations are applied in.
+    const CONCURRENT_OPERATIONS_COMMUTE: bool = true;
+
     fn apply<CS: CausalState<Time = Self::Time>>(self, st: &CS, op: Self::Op) -> Self {
         match compare_with_tiebreak(st, &self.time, &op.time) {
             Ordering::Less => op,
@@ -38,6 +38,9 @@ impl<T: Eq + Ord + Clone + Debug, A: Clone + Debug> CRDT for CausalTree<T, A> {
     type Op = CausalTreeOp<T, A>;
     type Time = T;
 
+    // Siblings are kept sorted by `compare_atom`, whatever order they're inserted in.
+    const CONCURRENT_OPERATIONS_COMMUTE: bool = true;
+
     fn apply<CS: CausalState<Time = Self::Time>>(self, st: &CS, op: Self::Op) -> Self {
         let (ct, op_ret) = insert_in_weave(st, self, op);
         if op_ret.is_some() {